@@ -65,13 +65,16 @@ impl std::fmt::Display for BencodeValue<'_> {
 }
 
 impl<'input> BencodeValue<'input> {
-    pub fn from_str(input: &'input str) -> Result<(&str, Self)> {
+    pub fn from_str(input: &'input str) -> Result<(&'input str, Self)> {
         let (rest, value) = BencodeValue::from_bytes(input.as_bytes())?;
         Ok((std::str::from_utf8(rest)?, value))
     }
 
-    pub fn from_bytes(input: &'input [u8]) -> Result<(&[u8], Self)> {
-        match input[0] {
+    pub fn from_bytes(input: &'input [u8]) -> Result<(&'input [u8], Self)> {
+        let Some(first) = input.first() else {
+            anyhow::bail!("empty bencode value");
+        };
+        match first {
             b'0'..=b'9' => {
                 // Byte string
                 let delimiter_index = input.iter().position(|b| *b == b':');
@@ -148,6 +151,30 @@ impl<'input> BencodeValue<'input> {
         }
     }
 
+    /// Find `key` in the dictionary at the start of `input` and return the raw, still
+    /// encoded bytes of its value, exactly as they appear in `input`.
+    pub fn dictionary_entry_bytes(input: &'input [u8], key: &[u8]) -> Result<Option<&'input [u8]>> {
+        if input.first() != Some(&b'd') {
+            anyhow::bail!("not a dictionary");
+        }
+        let mut rest = &input[1..];
+        loop {
+            match rest.first() {
+                None => anyhow::bail!("premature end of dictionary"),
+                Some(b'e') => return Ok(None),
+                _ => {
+                    let (after_key, entry_key) = BencodeValue::from_bytes(rest)?;
+                    let (remainder, _) = BencodeValue::from_bytes(after_key)?;
+                    let value_bytes = &after_key[..after_key.len() - remainder.len()];
+                    if entry_key.as_byte_string().map(|bs| bs.0) == Some(key) {
+                        return Ok(Some(value_bytes));
+                    }
+                    rest = remainder;
+                }
+            }
+        }
+    }
+
     pub fn to_bytes(&self) -> Vec<u8> {
         let mut output = Vec::new();
         match self {
@@ -178,14 +205,16 @@ impl<'input> BencodeValue<'input> {
         output
     }
 
-    pub fn as_byte_string(&self) -> Option<&BencodeByteString> {
+    pub fn as_byte_string(&self) -> Option<&BencodeByteString<'input>> {
         match self {
             BencodeValue::ByteString(bs) => Some(bs),
             _ => None,
         }
     }
 
-    pub fn as_dictionary(&self) -> Option<&BTreeMap<BencodeByteString, BencodeValue>> {
+    pub fn as_dictionary(
+        &self,
+    ) -> Option<&BTreeMap<BencodeByteString<'input>, BencodeValue<'input>>> {
         match self {
             BencodeValue::Dictionary(map) => Some(map),
             _ => None,
@@ -199,8 +228,7 @@ impl<'input> BencodeValue<'input> {
         }
     }
 
-    #[allow(dead_code)]
-    pub fn as_list(&self) -> Option<&[BencodeValue]> {
+    pub fn as_list(&self) -> Option<&[BencodeValue<'input>]> {
         match self {
             BencodeValue::List(values) => Some(values),
             _ => None,
@@ -320,6 +348,29 @@ mod tests {
         }
     }
 
+    #[test]
+    fn dictionary_entry_bytes() {
+        {
+            // Value is returned verbatim
+            let input = b"d3:bar4:spam4:infod1:ai1eee";
+            let value = BencodeValue::dictionary_entry_bytes(input, b"info").unwrap();
+            assert_eq!(value, Some(&b"d1:ai1ee"[..]));
+        }
+
+        {
+            // Missing key
+            let input = b"d3:bar4:spame";
+            let value = BencodeValue::dictionary_entry_bytes(input, b"info").unwrap();
+            assert_eq!(value, None);
+        }
+
+        {
+            // Not a dictionary
+            let result = BencodeValue::dictionary_entry_bytes(b"i42e", b"info");
+            assert!(result.is_err());
+        }
+    }
+
     #[test]
     fn parse_dictionary() {
         {
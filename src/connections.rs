@@ -0,0 +1,326 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::sync::mpsc;
+
+use crate::{peer::PeerConnection, pex::Pex, torrent::Torrent, tracker::Peer};
+
+/// How long to wait for a peer to accept the connection and answer the handshake.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The address we would use to reach `remote`, or `None` if there is no route to it. This
+/// "connects" a UDP socket, which sends nothing.
+fn local_ip_towards(remote: SocketAddr) -> Option<IpAddr> {
+    let bind_addr: SocketAddr = match remote {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(bind_addr).ok()?;
+    socket.connect(remote).ok()?;
+    Some(socket.local_addr().ok()?.ip())
+}
+
+/// Our IPv4 address on the route to the internet, if we have one.
+pub fn local_ipv4() -> Option<Ipv4Addr> {
+    match local_ip_towards((Ipv4Addr::new(192, 0, 2, 1), 6881).into())? {
+        IpAddr::V4(ip) => Some(ip),
+        IpAddr::V6(_) => None,
+    }
+}
+
+/// Our IPv6 address on the route to the internet, if we have one.
+pub fn local_ipv6() -> Option<Ipv6Addr> {
+    match local_ip_towards((Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1), 6881).into())? {
+        IpAddr::V6(ip) => Some(ip),
+        IpAddr::V4(_) => None,
+    }
+}
+
+/// Whether other hosts on the internet could reach us at `ip`, as far as we can tell.
+pub fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                // Carrier-grade NAT
+                || (ip.octets()[0] == 100 && ip.octets()[1] & 0xC0 == 64))
+        }
+        IpAddr::V6(ip) => {
+            let first = ip.segments()[0];
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                // Unique local and link-local
+                || first & 0xFE00 == 0xFC00
+                || first & 0xFFC0 == 0xFE80)
+        }
+    }
+}
+
+/// Hands out connections to peers, dialling IPv4 and IPv6 peers alike.
+///
+/// Peers arrive on a channel from the trackers. Each is tried once, alternating between the
+/// two address families so that a swarm that is mostly one family doesn't starve the other.
+/// Peers of a family we have no route to are skipped.
+///
+/// Unless the torrent is private, connected peers also tell us about their peers (BEP 11),
+/// which are tried like the trackers' peers. So are peers from [`Self::discovered`].
+pub struct ConnectionManager {
+    torrent: Arc<Torrent>,
+    local_peer_id: [u8; 20],
+    new_peers: mpsc::UnboundedReceiver<Peer>,
+    pex: Option<Arc<Pex>>,
+    discovered_tx: mpsc::UnboundedSender<Peer>,
+    discovered: mpsc::UnboundedReceiver<Peer>,
+    tried: HashSet<SocketAddr>,
+    pending_ipv4: VecDeque<Peer>,
+    pending_ipv6: VecDeque<Peer>,
+    prefer_ipv6: bool,
+    has_ipv4: bool,
+    has_ipv6: bool,
+}
+
+impl ConnectionManager {
+    pub fn new(
+        torrent: Arc<Torrent>,
+        local_peer_id: [u8; 20],
+        new_peers: mpsc::UnboundedReceiver<Peer>,
+    ) -> Self {
+        // We keep a sender, so this channel never closes; we are done once the trackers' one
+        // does
+        let (discovered_tx, discovered) = mpsc::unbounded_channel();
+        let pex = (!torrent.info.private).then(|| Arc::new(Pex::new(discovered_tx.clone())));
+        ConnectionManager {
+            torrent,
+            local_peer_id,
+            new_peers,
+            pex,
+            discovered_tx,
+            discovered,
+            tried: HashSet::new(),
+            pending_ipv4: VecDeque::new(),
+            pending_ipv6: VecDeque::new(),
+            prefer_ipv6: true,
+            has_ipv4: local_ipv4().is_some(),
+            has_ipv6: local_ipv6().is_some(),
+        }
+    }
+
+    /// Where to send peers found other than through the trackers, such as on the local
+    /// network. Unlike the trackers' channel, this one doesn't keep [`Self::connect`] waiting
+    /// for more peers while it is open.
+    pub fn discovered(&self) -> mpsc::UnboundedSender<Peer> {
+        self.discovered_tx.clone()
+    }
+
+    fn add(&mut self, peer: Peer) {
+        let reachable = match peer.addr {
+            // Loopback peers are always reachable, which matters for tests
+            addr if addr.ip().is_loopback() => true,
+            SocketAddr::V4(_) => self.has_ipv4,
+            SocketAddr::V6(_) => self.has_ipv6,
+        };
+        if !reachable || !self.tried.insert(peer.addr) {
+            return;
+        }
+        match peer.addr {
+            SocketAddr::V4(_) => self.pending_ipv4.push_back(peer),
+            SocketAddr::V6(_) => self.pending_ipv6.push_back(peer),
+        }
+    }
+
+    /// The next peer to dial, alternating between families while both have peers waiting.
+    fn next_pending(&mut self) -> Option<Peer> {
+        let (first, second) = if self.prefer_ipv6 {
+            (&mut self.pending_ipv6, &mut self.pending_ipv4)
+        } else {
+            (&mut self.pending_ipv4, &mut self.pending_ipv6)
+        };
+        let peer = first.pop_front().or_else(|| second.pop_front())?;
+        self.prefer_ipv6 = peer.addr.is_ipv4();
+        Some(peer)
+    }
+
+    /// Connect to the next peer we haven't tried that accepts the connection and has the peer
+    /// ID the tracker told us about, if any. Waits for more peers when we run out, and returns
+    /// `None` once no more will come.
+    pub async fn connect(&mut self) -> Option<PeerConnection> {
+        loop {
+            while let Ok(peer) = self.new_peers.try_recv() {
+                self.add(peer);
+            }
+            while let Ok(peer) = self.discovered.try_recv() {
+                self.add(peer);
+            }
+            let peer = match self.next_pending() {
+                Some(peer) => peer,
+                None => {
+                    tokio::select! {
+                        peer = self.new_peers.recv() => self.add(peer?),
+                        Some(peer) = self.discovered.recv() => self.add(peer),
+                    }
+                    continue;
+                }
+            };
+
+            let connect = PeerConnection::connect(
+                self.torrent.clone(),
+                self.local_peer_id,
+                peer.addr,
+                self.pex.clone(),
+            );
+            match tokio::time::timeout(CONNECT_TIMEOUT, connect).await {
+                Ok(Ok(connection))
+                    if peer.peer_id.is_some() && connection.peer_id != peer.peer_id =>
+                {
+                    eprintln!(
+                        "peer {} sent a different peer ID than the tracker",
+                        peer.addr
+                    );
+                }
+                Ok(Ok(connection)) => return Some(connection),
+                Ok(Err(e)) => eprintln!("failed to connect to peer {}: {}", peer.addr, e),
+                Err(_) => eprintln!("timed out connecting to peer {}", peer.addr),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_public, ConnectionManager};
+    use crate::{torrent::Torrent, tracker::Peer};
+    use std::{net::SocketAddr, sync::Arc};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        sync::mpsc,
+    };
+
+    #[test]
+    fn public_addresses() {
+        for (ip, public) in [
+            ("203.0.113.7", true),
+            ("10.1.2.3", false),
+            ("192.168.0.1", false),
+            ("100.64.0.1", false),
+            ("127.0.0.1", false),
+            ("2001:db8::1", true),
+            ("fd00::1", false),
+            ("fe80::1", false),
+            ("::1", false),
+        ] {
+            assert_eq!(is_public(ip.parse().unwrap()), public, "{}", ip);
+        }
+    }
+
+    #[tokio::test]
+    async fn alternates_families() {
+        let input = std::fs::read("sample.torrent").unwrap();
+        let torrent = Arc::new(Torrent::from_bytes(&input).unwrap());
+        let (peers_tx, peers_rx) = mpsc::unbounded_channel();
+        let mut manager = ConnectionManager::new(torrent, [0; 20], peers_rx);
+        manager.has_ipv4 = true;
+        manager.has_ipv6 = true;
+
+        for addr in [
+            "1.0.0.1:1",
+            "1.0.0.2:1",
+            "1.0.0.1:1",
+            "[2001:db8::1]:1",
+            "1.0.0.3:1",
+        ] {
+            peers_tx
+                .send(Peer::from(addr.parse::<SocketAddr>().unwrap()))
+                .unwrap();
+        }
+        while let Ok(peer) = manager.new_peers.try_recv() {
+            manager.add(peer);
+        }
+        let order = std::iter::from_fn(|| manager.next_pending())
+            .map(|peer| peer.addr.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            order,
+            ["[2001:db8::1]:1", "1.0.0.1:1", "1.0.0.2:1", "1.0.0.3:1"]
+        );
+
+        {
+            // Families without a route are skipped
+            manager.has_ipv6 = false;
+            manager.add(Peer::from("[2001:db8::2]:1".parse::<SocketAddr>().unwrap()));
+            assert!(manager.next_pending().is_none());
+        }
+    }
+
+    /// A peer that offers the extension protocol and tells us about `added` with `ut_pex`
+    /// if we offer it too, then hangs up. Returns whether we offered it.
+    async fn pex_peer(listener: TcpListener, added: SocketAddr) -> bool {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut handshake = [0; 68];
+        stream.read_exact(&mut handshake).await.unwrap();
+        let extensions = handshake[25] & 0x10 != 0;
+        handshake[25] |= 0x10;
+        stream.write_all(&handshake).await.unwrap();
+        if extensions {
+            let mut len = [0; 4];
+            stream.read_exact(&mut len).await.unwrap();
+            let mut message = vec![0; u32::from_be_bytes(len) as usize];
+            stream.read_exact(&mut message).await.unwrap();
+            assert_eq!(message, b"\x14\x00d1:md6:ut_pexi1eee");
+        }
+
+        let mut messages = Vec::new();
+        for (tag, payload) in [
+            (20, b"\x00d1:md6:ut_pexi2eee".to_vec()),
+            (5, vec![0xFF]),
+            (20, {
+                let SocketAddr::V4(added) = added else {
+                    unreachable!()
+                };
+                let mut payload = b"\x01d5:added6:".to_vec();
+                payload.extend(added.ip().octets());
+                payload.extend(added.port().to_be_bytes());
+                payload.push(b'e');
+                payload
+            }),
+        ] {
+            messages.extend((payload.len() as u32 + 1).to_be_bytes());
+            messages.push(tag);
+            messages.extend(payload);
+        }
+        // Whatever we get, we hang up after sending these
+        let _ = stream.write_all(&messages).await;
+        extensions
+    }
+
+    #[tokio::test]
+    async fn exchanges_peers() {
+        let input = std::fs::read("sample.torrent").unwrap();
+        let added = "127.0.0.2:6881".parse::<SocketAddr>().unwrap();
+        for private in [false, true] {
+            let mut torrent = Torrent::from_bytes(&input).unwrap();
+            torrent.info.private = private;
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let peer_addr = listener.local_addr().unwrap();
+            let peer = tokio::spawn(pex_peer(listener, added));
+
+            let (peers_tx, peers_rx) = mpsc::unbounded_channel();
+            let mut manager = ConnectionManager::new(Arc::new(torrent), [0; 20], peers_rx);
+            peers_tx.send(Peer::from(peer_addr)).unwrap();
+            let mut connection = manager.connect().await.unwrap();
+            // The peer hangs up before sending any piece
+            assert!(connection.fetch_piece(0).await.is_err());
+            drop(connection);
+
+            // Private torrents get their peers from their trackers only
+            assert_eq!(peer.await.unwrap(), !private);
+            let learned = manager.discovered.try_recv().ok().map(|peer| peer.addr);
+            assert_eq!(learned, (!private).then_some(added));
+        }
+    }
+}
@@ -0,0 +1,1149 @@
+use anyhow::{Context, Result};
+use futures::{stream::FuturesUnordered, FutureExt, StreamExt};
+use rand::seq::IteratorRandom;
+use sha1::{Digest, Sha1};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{
+    net::UdpSocket,
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+};
+
+use crate::{
+    bencode::{BencodeByteString, BencodeValue},
+    identity::Identity,
+    magnet::PublicKeyMagnet,
+    torrent::Torrent,
+    tracker::Peer,
+};
+
+mod bloom;
+pub mod items;
+mod krpc;
+mod routing;
+mod security;
+mod state;
+
+use bloom::Bloom;
+use items::{Item, MutableItem};
+use krpc::{Body, KrpcError, Message, NodeId, NodeInfo, Query, Response};
+use routing::{RoutingTable, K};
+use state::State;
+
+/// Well-known nodes that exist to let new nodes join the network.
+pub const DEFAULT_ROUTERS: [&str; 3] = [
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+];
+/// How many queries a lookup keeps in flight.
+const ALPHA: usize = 3;
+const QUERY_TIMEOUT: Duration = Duration::from_secs(3);
+/// Tokens are made from a secret that changes this often, and the previous secret is still
+/// accepted, so a token stays valid for up to twice as long.
+const TOKEN_ROTATION: Duration = Duration::from_secs(5 * 60);
+/// Peers that haven't announced for this long are forgotten.
+const PEER_TTL: Duration = Duration::from_secs(30 * 60);
+/// Items that haven't been put again for this long are forgotten.
+const ITEM_TTL: Duration = Duration::from_secs(2 * 60 * 60);
+/// The most peers we keep for one info hash, the most info hashes we keep peers for, and
+/// the most items we store. Tokens are easy to get, so without limits a single host could
+/// fill our memory; the oldest entries make way for new ones.
+const MAX_PEERS_PER_TORRENT: usize = 200;
+const MAX_TORRENTS: usize = 2000;
+const MAX_ITEMS: usize = 1000;
+/// The most peers to return for a `get_peers`, so that the response fits in a datagram.
+const MAX_VALUES: usize = 50;
+/// The most info hashes to return for a `sample_infohashes`.
+const MAX_SAMPLES: usize = 20;
+/// How often nodes may ask us for a new sample, in seconds.
+const SAMPLE_INTERVAL: i64 = 5 * 60;
+/// How often to look for peers again and re-announce while downloading.
+const REANNOUNCE_INTERVAL: Duration = Duration::from_secs(15 * 60);
+const MAX_PACKET_LEN: usize = 65536;
+
+/// Command line options for the DHT.
+#[derive(Debug, clap::Args)]
+pub struct DhtArgs {
+    /// Don't look for peers in the DHT when the trackers fail
+    #[arg(long, global = true)]
+    no_dht: bool,
+    /// A DHT node to join the network through, as host:port. May be given more than once
+    #[arg(long = "dht-router", global = true, default_values = DEFAULT_ROUTERS)]
+    routers: Vec<String>,
+    /// Where to keep the DHT node ID and the nodes we know between runs. Defaults to
+    /// bittorrent-starter-rust/dht.dat in $XDG_STATE_HOME or ~/.local/state
+    #[arg(long = "dht-state", global = true)]
+    state: Option<PathBuf>,
+}
+
+impl DhtArgs {
+    /// Whether the DHT may be used to find peers for `torrent`. Private torrents only get
+    /// peers from their trackers.
+    pub fn enabled_for(&self, torrent: &Torrent) -> bool {
+        !self.no_dht && !torrent.info.private
+    }
+
+    fn state_path(&self) -> Option<PathBuf> {
+        if let Some(path) = &self.state {
+            return Some(path.clone());
+        }
+        let dir = match std::env::var_os("XDG_STATE_HOME") {
+            Some(dir) => PathBuf::from(dir),
+            None => PathBuf::from(std::env::var_os("HOME")?).join(".local/state"),
+        };
+        Some(dir.join("bittorrent-starter-rust").join("dht.dat"))
+    }
+
+    /// Start a DHT node on the identity's port, or any port if that is taken. It joins the
+    /// network through the nodes saved by the last run that still answer, and `nodes`, such
+    /// as a torrent's. The routers are only needed when none of the saved nodes answer.
+    pub async fn start(&self, nodes: &[(String, u16)], identity: &Identity) -> Result<Dht> {
+        let port = identity.port;
+        let state = match self.state_path().map(|path| State::load(&path)) {
+            Some(Ok(state)) => state,
+            Some(Err(e)) => {
+                eprintln!("warning: ignoring saved DHT state: {:#}", e);
+                None
+            }
+            None => None,
+        };
+        let id = state.as_ref().map_or_else(rand::random, |state| state.id);
+        let dht = match Dht::bind((Ipv4Addr::UNSPECIFIED, port).into(), id).await {
+            Ok(dht) => dht,
+            Err(_) => Dht::bind((Ipv4Addr::UNSPECIFIED, 0).into(), id).await?,
+        };
+        let warm = match &state {
+            Some(state) => dht.ping(&state.nodes).await,
+            None => 0,
+        };
+
+        let mut bootstrap = Vec::new();
+        let routers = self.routers.iter().filter_map(|router| {
+            let (host, port) = router.rsplit_once(':')?;
+            Some((host, port.parse().ok()?))
+        });
+        let hosts = nodes
+            .iter()
+            .map(|(host, port)| (host.as_str(), *port))
+            .chain(routers.filter(|_| warm == 0));
+        for (host, port) in hosts {
+            match tokio::net::lookup_host((host, port)).await {
+                Ok(addrs) => bootstrap.extend(addrs.filter(SocketAddr::is_ipv4)),
+                Err(e) => eprintln!("warning: could not resolve DHT node {}: {}", host, e),
+            }
+        }
+        let ip = match identity.ip {
+            Some(IpAddr::V4(ip)) => Some(ip),
+            _ => None,
+        };
+        dht.bootstrap(&bootstrap, ip).await?;
+        Ok(dht)
+    }
+
+    /// Save the node ID and the nodes `dht` knows, for the next run to start from.
+    pub fn save(&self, dht: &Dht) {
+        let Some(path) = self.state_path() else {
+            return;
+        };
+        if let Err(e) = dht.state().save(&path) {
+            eprintln!(
+                "warning: failed to save DHT state to {}: {:#}",
+                path.display(),
+                e
+            );
+        }
+    }
+}
+
+/// A node in the mainline DHT (BEP 5), over IPv4. It answers other nodes' queries in the
+/// background for as long as it exists.
+pub struct Dht {
+    node: Arc<Node>,
+    task: JoinHandle<()>,
+}
+
+impl Drop for Dht {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl Dht {
+    pub async fn bind(addr: SocketAddr, id: NodeId) -> Result<Self> {
+        let socket = UdpSocket::bind(addr)
+            .await
+            .with_context(|| format!("failed to bind DHT socket to {}", addr))?;
+        let node = Arc::new(Node {
+            id: Mutex::new(id),
+            socket,
+            table: Mutex::new(RoutingTable::new(id)),
+            pending: Mutex::new(HashMap::new()),
+            next_transaction: AtomicU16::new(rand::random()),
+            tokens: Mutex::new(Tokens::new()),
+            peers: Mutex::new(HashMap::new()),
+            items: Mutex::new(HashMap::new()),
+            ip_votes: Mutex::new(HashMap::new()),
+            query_timeout: QUERY_TIMEOUT,
+        });
+        let task = tokio::spawn(node.clone().run());
+        Ok(Dht { node, task })
+    }
+
+    fn state(&self) -> State {
+        let id = self.node.id();
+        State {
+            id,
+            nodes: self.node.table.lock().unwrap().closest(&id, usize::MAX),
+        }
+    }
+
+    /// Ping `nodes`, adding those that answer to the routing table. Returns how many did.
+    async fn ping(&self, nodes: &[NodeInfo]) -> usize {
+        let pings = nodes
+            .iter()
+            .map(|node| self.node.query(node.addr, Query::Ping));
+        futures::future::join_all(pings)
+            .await
+            .iter()
+            .filter(|result| result.is_ok())
+            .count()
+    }
+
+    /// Join the network through the nodes at `addrs`, and find the nodes closest to us.
+    /// On the way, our ID is changed to one that matches our external IP address if it
+    /// doesn't already: `ip` if given, else the one the nodes we asked saw us at.
+    pub async fn bootstrap(&self, addrs: &[SocketAddr], ip: Option<Ipv4Addr>) -> Result<()> {
+        let id = self.node.id();
+        let queries = addrs.iter().filter_map(|addr| match addr {
+            SocketAddr::V4(addr) => Some(self.node.query(*addr, Query::FindNode { target: id })),
+            SocketAddr::V6(_) => None,
+        });
+        let responses = futures::future::join_all(queries).await;
+        if let Some(ip) = ip.or_else(|| self.node.external_ip()) {
+            if !security::is_valid(&id, ip) {
+                self.node.set_id(security::node_id(ip));
+            }
+        }
+
+        let id = self.node.id();
+        let mut nodes = self.node.table.lock().unwrap().closest(&id, K);
+        for response in responses.into_iter().flatten() {
+            nodes.extend(response.nodes);
+        }
+        self.node
+            .lookup(id, nodes, Query::FindNode { target: id })
+            .await;
+        anyhow::ensure!(
+            self.node.table.lock().unwrap().len() > 0,
+            "no DHT nodes answered"
+        );
+        Ok(())
+    }
+
+    async fn lookup_peers(
+        &self,
+        info_hash: [u8; 20],
+        scrape: bool,
+    ) -> (Vec<(NodeInfo, Response)>, HashSet<SocketAddrV4>) {
+        let start = self.node.table.lock().unwrap().closest(&info_hash, K);
+        let query = Query::GetPeers {
+            info_hash,
+            scrape,
+            noseed: false,
+        };
+        self.node.lookup(info_hash, start, query).await
+    }
+
+    /// Find peers for a torrent.
+    pub async fn get_peers(&self, info_hash: [u8; 20]) -> Vec<SocketAddr> {
+        let (_, peers) = self.lookup_peers(info_hash, false).await;
+        peers.into_iter().map(SocketAddr::V4).collect()
+    }
+
+    /// Find peers for a torrent, and tell the nodes closest to it that we have it on `port`.
+    pub async fn announce(&self, info_hash: [u8; 20], port: u16) -> Vec<SocketAddr> {
+        let (closest, peers) = self.lookup_peers(info_hash, false).await;
+        let announces = closest.into_iter().filter_map(|(node, response)| {
+            let query = Query::AnnouncePeer {
+                info_hash,
+                port,
+                implied_port: false,
+                token: response.token?,
+                seed: false,
+            };
+            Some(self.node.query(node.addr, query))
+        });
+        for result in futures::future::join_all(announces).await {
+            if let Err(e) = result {
+                eprintln!("warning: DHT announce failed: {:#}", e);
+            }
+        }
+        peers.into_iter().map(SocketAddr::V4).collect()
+    }
+
+    /// Estimate how many seeds and other peers a torrent has from the bloom filters of the
+    /// nodes closest to it (BEP 33).
+    pub async fn scrape(&self, info_hash: [u8; 20]) -> Scrape {
+        let (closest, _) = self.lookup_peers(info_hash, true).await;
+        let mut seeds = Bloom::default();
+        let mut peers = Bloom::default();
+        let mut nodes = 0;
+        for (_, response) in closest {
+            if let (Some(node_seeds), Some(node_peers)) =
+                (&response.bloom_seeds, &response.bloom_peers)
+            {
+                seeds.union(node_seeds);
+                peers.union(node_peers);
+                nodes += 1;
+            }
+        }
+        Scrape {
+            seeds: seeds.estimate().round() as u64,
+            peers: peers.estimate().round() as u64,
+            nodes,
+        }
+    }
+
+    /// Ask the nodes closest to `target` which info hashes they have peers for (BEP 51).
+    pub async fn sample(&self, target: NodeId) -> Sample {
+        let start = self.node.table.lock().unwrap().closest(&target, K);
+        let (closest, _) = self
+            .node
+            .lookup(target, start, Query::SampleInfohashes { target })
+            .await;
+        let mut sample = Sample::default();
+        for (_, response) in closest {
+            if let Some(num) = response.num {
+                sample.info_hashes.extend(response.samples);
+                sample.total += num.max(0) as u64;
+                sample.nodes += 1;
+            }
+        }
+        sample
+    }
+
+    /// Find the closest nodes to `target` that answer `get`s, with their responses.
+    async fn lookup_item(&self, target: NodeId) -> Vec<(NodeInfo, Response)> {
+        let start = self.node.table.lock().unwrap().closest(&target, K);
+        let query = Query::Get { target, seq: None };
+        let (closest, _) = self.node.lookup(target, start, query).await;
+        closest
+    }
+
+    /// Find the immutable item with the hash `target` (BEP 44).
+    pub async fn get_immutable(&self, target: NodeId) -> Option<Vec<u8>> {
+        self.lookup_item(target)
+            .await
+            .into_iter()
+            .filter_map(|(_, response)| response.value)
+            .find(|value| Item::Immutable(value.clone()).target() == target)
+    }
+
+    /// Find the newest version of the mutable item of `key` and `salt` (BEP 44).
+    pub async fn get_mutable(&self, key: [u8; 32], salt: &[u8]) -> Option<MutableItem> {
+        let target = items::mutable_target(&key, salt);
+        self.lookup_item(target)
+            .await
+            .into_iter()
+            .filter_map(|(_, response)| {
+                Some(MutableItem {
+                    key: response.key?,
+                    salt: salt.to_vec(),
+                    seq: response.seq?,
+                    value: response.value?,
+                    signature: response.signature?,
+                })
+            })
+            .filter(|item| item.key == key && item.verify())
+            .max_by_key(|item| item.seq)
+    }
+
+    /// Store `item` with the nodes closest to its target. Returns how many took it.
+    pub async fn put(&self, item: Item) -> Result<usize> {
+        let closest = self.lookup_item(item.target()).await;
+        let puts = closest.into_iter().filter_map(|(node, response)| {
+            let query = Query::Put {
+                token: response.token?,
+                item: item.clone(),
+                cas: None,
+            };
+            Some(self.node.query(node.addr, query))
+        });
+        let mut stored = 0;
+        let mut last_error = None;
+        for result in futures::future::join_all(puts).await {
+            match result {
+                Ok(_) => stored += 1,
+                Err(e) => last_error = Some(e),
+            }
+        }
+        match last_error {
+            Some(e) if stored == 0 => Err(e.context("no DHT node stored the item")),
+            None if stored == 0 => anyhow::bail!("no DHT nodes to store the item with"),
+            _ => Ok(stored),
+        }
+    }
+
+    /// Point the updatable torrent of `key` and `salt` at `info_hash` (BEP 46). Returns the
+    /// sequence number of this version.
+    pub async fn publish(
+        &self,
+        key: &ed25519_dalek::SigningKey,
+        salt: &[u8],
+        info_hash: [u8; 20],
+    ) -> Result<i64> {
+        let current = self.get_mutable(key.verifying_key().to_bytes(), salt).await;
+        let seq = current.map_or(1, |item| item.seq + 1);
+        let mut value = BTreeMap::new();
+        value.insert(
+            BencodeByteString(b"ih"),
+            BencodeValue::ByteString(BencodeByteString(&info_hash)),
+        );
+        let value = BencodeValue::Dictionary(value).to_bytes();
+        self.put(Item::Mutable(MutableItem::sign(key, salt, seq, value)?))
+            .await?;
+        Ok(seq)
+    }
+
+    /// Find the info hash an updatable torrent currently points at (BEP 46).
+    pub async fn resolve(&self, magnet: &PublicKeyMagnet) -> Result<[u8; 20]> {
+        let item = self
+            .get_mutable(magnet.key, &magnet.salt)
+            .await
+            .context("no DHT node knows of this torrent")?;
+        let (_, value) = BencodeValue::from_bytes(&item.value)?;
+        value
+            .as_dictionary()
+            .and_then(|value| value.get(&BencodeByteString(b"ih")))
+            .and_then(BencodeValue::as_byte_string)
+            .and_then(|ih| ih.0.try_into().ok())
+            .context("the DHT item of this torrent has no info hash")
+    }
+
+    /// Keep announcing in the background, sending the peers found each time to `peers`.
+    pub fn spawn(
+        self,
+        info_hash: [u8; 20],
+        port: u16,
+        peers: mpsc::UnboundedSender<Peer>,
+    ) -> Reannouncer {
+        let (stop, mut stopped) = oneshot::channel();
+        let task = tokio::spawn(async move {
+            loop {
+                let found = tokio::select! {
+                    _ = &mut stopped => break,
+                    found = self.announce(info_hash, port) => found,
+                };
+                for addr in found {
+                    // The download may already be over
+                    let _ = peers.send(addr.into());
+                }
+                tokio::select! {
+                    _ = &mut stopped => break,
+                    _ = tokio::time::sleep(REANNOUNCE_INTERVAL) => {}
+                }
+            }
+            self
+        });
+        Reannouncer { stop, task }
+    }
+}
+
+/// How many seeds and other peers a torrent has, roughly.
+#[derive(Debug)]
+pub struct Scrape {
+    pub seeds: u64,
+    pub peers: u64,
+    /// How many nodes had bloom filters for the torrent.
+    pub nodes: usize,
+}
+
+/// Info hashes that nodes have peers for.
+#[derive(Debug, Default)]
+pub struct Sample {
+    pub info_hashes: HashSet<[u8; 20]>,
+    /// How many info hashes the nodes said they have in all.
+    pub total: u64,
+    /// How many nodes answered.
+    pub nodes: usize,
+}
+
+/// A [`Dht`] announcing in the background.
+pub struct Reannouncer {
+    stop: oneshot::Sender<()>,
+    task: JoinHandle<Dht>,
+}
+
+impl Reannouncer {
+    /// Stop announcing and hand the DHT back.
+    pub async fn stop(self) -> Result<Dht> {
+        let _ = self.stop.send(());
+        Ok(self.task.await?)
+    }
+}
+
+/// Makes and checks the tokens that `announce_peer` queries must carry, which prove that
+/// the sender got them from us at its address not long ago.
+struct Tokens {
+    current: [u8; 20],
+    previous: [u8; 20],
+    rotated: Instant,
+}
+
+impl Tokens {
+    fn new() -> Self {
+        Tokens {
+            current: rand::random(),
+            previous: rand::random(),
+            rotated: Instant::now(),
+        }
+    }
+
+    fn rotate(&mut self) {
+        if self.rotated.elapsed() >= TOKEN_ROTATION {
+            self.previous = self.current;
+            self.current = rand::random();
+            self.rotated = Instant::now();
+        }
+    }
+
+    fn make(secret: &[u8; 20], ip: Ipv4Addr) -> Vec<u8> {
+        let hash = Sha1::new()
+            .chain_update(secret)
+            .chain_update(ip.octets())
+            .finalize();
+        hash[..8].to_vec()
+    }
+
+    fn issue(&mut self, ip: Ipv4Addr) -> Vec<u8> {
+        self.rotate();
+        Self::make(&self.current, ip)
+    }
+
+    fn check(&mut self, ip: Ipv4Addr, token: &[u8]) -> bool {
+        self.rotate();
+        token == Self::make(&self.current, ip) || token == Self::make(&self.previous, ip)
+    }
+}
+
+fn error(code: i64, message: &str) -> Body {
+    Body::Error(KrpcError {
+        code,
+        message: message.to_owned(),
+    })
+}
+
+/// When a peer announced itself to us, and whether as a seed.
+struct Announced {
+    at: Instant,
+    seed: bool,
+}
+
+/// A query we sent and are waiting for the answer to.
+struct Pending {
+    addr: SocketAddrV4,
+    response: oneshot::Sender<Result<Response, KrpcError>>,
+}
+
+struct Node {
+    id: Mutex<NodeId>,
+    socket: UdpSocket,
+    table: Mutex<RoutingTable>,
+    /// Keyed by transaction ID.
+    pending: Mutex<HashMap<Vec<u8>, Pending>>,
+    next_transaction: AtomicU16,
+    tokens: Mutex<Tokens>,
+    /// Peers that announced themselves to us, by info hash.
+    peers: Mutex<HashMap<[u8; 20], HashMap<SocketAddrV4, Announced>>>,
+    /// Items put with us, and when, by target.
+    items: Mutex<HashMap<NodeId, (Item, Instant)>>,
+    /// The addresses that nodes answering our queries said they saw us at, and how many said
+    /// each.
+    ip_votes: Mutex<HashMap<Ipv4Addr, usize>>,
+    query_timeout: Duration,
+}
+
+impl Node {
+    fn id(&self) -> NodeId {
+        *self.id.lock().unwrap()
+    }
+
+    /// Take a new ID, keeping the nodes we know.
+    fn set_id(&self, id: NodeId) {
+        let mut table = self.table.lock().unwrap();
+        let nodes = table.closest(&id, usize::MAX);
+        *table = RoutingTable::new(id);
+        for node in nodes {
+            table.insert(node, false);
+        }
+        *self.id.lock().unwrap() = id;
+    }
+
+    /// Our IP address as most of the nodes we asked see it.
+    fn external_ip(&self) -> Option<Ipv4Addr> {
+        let votes = self.ip_votes.lock().unwrap();
+        votes
+            .iter()
+            .max_by_key(|(_, votes)| **votes)
+            .map(|(ip, _)| *ip)
+    }
+
+    /// Receive messages until the DHT is dropped, answering queries and handing responses to
+    /// whoever is waiting for them.
+    async fn run(self: Arc<Self>) {
+        let mut buf = vec![0; MAX_PACKET_LEN];
+        loop {
+            let (n, from) = match self.socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                // Errors from earlier sends, such as ICMP port unreachable, show up here
+                Err(_) => continue,
+            };
+            let SocketAddr::V4(from) = from else {
+                continue;
+            };
+            let Ok(message) = Message::decode(&buf[..n]) else {
+                continue;
+            };
+            let result = match message.body {
+                Body::Query { id, query } => {
+                    let body = self.answer(from, id, query);
+                    let response = Message {
+                        transaction_id: message.transaction_id,
+                        body,
+                        ip: Some(from),
+                    };
+                    if let Err(e) = self.socket.send_to(&response.encode(), from).await {
+                        eprintln!("warning: failed to answer DHT node {}: {}", from, e);
+                    }
+                    continue;
+                }
+                Body::Response(response) => Ok(response),
+                Body::Error(error) => Err(error),
+            };
+            let pending = {
+                let mut pending = self.pending.lock().unwrap();
+                match pending.get(&message.transaction_id) {
+                    Some(query) if query.addr == from => pending.remove(&message.transaction_id),
+                    _ => None,
+                }
+            };
+            if let Some(pending) = pending {
+                if let Some(ip) = message.ip {
+                    *self.ip_votes.lock().unwrap().entry(*ip.ip()).or_default() += 1;
+                }
+                let _ = pending.response.send(result);
+            }
+        }
+    }
+
+    fn answer(&self, from: SocketAddrV4, id: NodeId, query: Query) -> Body {
+        self.table
+            .lock()
+            .unwrap()
+            .insert(NodeInfo { id, addr: from }, false);
+        let mut response = Response {
+            id: self.id(),
+            ..Response::default()
+        };
+        match query {
+            Query::Ping => {}
+            Query::FindNode { target } => {
+                response.nodes = self.table.lock().unwrap().closest(&target, K);
+            }
+            Query::GetPeers {
+                info_hash,
+                scrape,
+                noseed,
+            } => {
+                response.token = Some(self.tokens.lock().unwrap().issue(*from.ip()));
+                response.nodes = self.table.lock().unwrap().closest(&info_hash, K);
+                if let Some(peers) = self.peers.lock().unwrap().get_mut(&info_hash) {
+                    peers.retain(|_, announced| announced.at.elapsed() < PEER_TTL);
+                    response.values = peers
+                        .iter()
+                        .filter(|(_, announced)| !(noseed && announced.seed))
+                        .map(|(addr, _)| *addr)
+                        .take(MAX_VALUES)
+                        .collect();
+                    if scrape {
+                        let mut seeds = Bloom::default();
+                        let mut others = Bloom::default();
+                        for (addr, announced) in peers.iter() {
+                            let bloom = if announced.seed {
+                                &mut seeds
+                            } else {
+                                &mut others
+                            };
+                            bloom.insert((*addr.ip()).into());
+                        }
+                        response.bloom_seeds = Some(seeds);
+                        response.bloom_peers = Some(others);
+                    }
+                }
+            }
+            Query::AnnouncePeer {
+                info_hash,
+                port,
+                implied_port,
+                token,
+                seed,
+            } => {
+                if !self.tokens.lock().unwrap().check(*from.ip(), &token) {
+                    return error(krpc::PROTOCOL_ERROR, "bad token");
+                }
+                let port = if implied_port { from.port() } else { port };
+                self.add_peer(info_hash, SocketAddrV4::new(*from.ip(), port), seed);
+            }
+            Query::SampleInfohashes { target } => {
+                response.nodes = self.table.lock().unwrap().closest(&target, K);
+                let mut peers = self.peers.lock().unwrap();
+                for swarm in peers.values_mut() {
+                    swarm.retain(|_, announced| announced.at.elapsed() < PEER_TTL);
+                }
+                peers.retain(|_, swarm| !swarm.is_empty());
+                response.samples = peers
+                    .keys()
+                    .copied()
+                    .choose_multiple(&mut rand::thread_rng(), MAX_SAMPLES);
+                response.num = Some(peers.len() as i64);
+                response.interval = Some(SAMPLE_INTERVAL);
+            }
+            Query::Get { target, seq } => {
+                response.token = Some(self.tokens.lock().unwrap().issue(*from.ip()));
+                response.nodes = self.table.lock().unwrap().closest(&target, K);
+                let mut items = self.items.lock().unwrap();
+                items.retain(|_, (_, put)| put.elapsed() < ITEM_TTL);
+                match items.get(&target) {
+                    Some((Item::Immutable(value), _)) => response.value = Some(value.clone()),
+                    Some((Item::Mutable(item), _)) => {
+                        response.seq = Some(item.seq);
+                        if seq.is_none_or(|seq| item.seq > seq) {
+                            response.value = Some(item.value.clone());
+                            response.key = Some(item.key);
+                            response.signature = Some(item.signature);
+                        }
+                    }
+                    None => {}
+                }
+            }
+            Query::Put { token, item, cas } => {
+                if !self.tokens.lock().unwrap().check(*from.ip(), &token) {
+                    return error(krpc::PROTOCOL_ERROR, "bad token");
+                }
+                if let Err(error) = self.store(item, cas) {
+                    return Body::Error(error);
+                }
+            }
+            Query::Unknown(_) => return error(krpc::METHOD_UNKNOWN, "Method Unknown"),
+        }
+        Body::Response(response)
+    }
+
+    /// Remember a peer that announced itself to us, making room for it if needed.
+    fn add_peer(&self, info_hash: [u8; 20], addr: SocketAddrV4, seed: bool) {
+        let mut peers = self.peers.lock().unwrap();
+        if !peers.contains_key(&info_hash) && peers.len() >= MAX_TORRENTS {
+            for swarm in peers.values_mut() {
+                swarm.retain(|_, announced| announced.at.elapsed() < PEER_TTL);
+            }
+            peers.retain(|_, swarm| !swarm.is_empty());
+            if peers.len() >= MAX_TORRENTS {
+                // Drop the torrent that was announced least recently
+                let oldest = peers
+                    .iter()
+                    .min_by_key(|(_, swarm)| swarm.values().map(|announced| announced.at).max())
+                    .map(|(info_hash, _)| *info_hash);
+                if let Some(oldest) = oldest {
+                    peers.remove(&oldest);
+                }
+            }
+        }
+        let swarm = peers.entry(info_hash).or_default();
+        if !swarm.contains_key(&addr) && swarm.len() >= MAX_PEERS_PER_TORRENT {
+            let oldest = swarm
+                .iter()
+                .min_by_key(|(_, announced)| announced.at)
+                .map(|(addr, _)| *addr);
+            if let Some(oldest) = oldest {
+                swarm.remove(&oldest);
+            }
+        }
+        swarm.insert(
+            addr,
+            Announced {
+                at: Instant::now(),
+                seed,
+            },
+        );
+    }
+
+    /// Store an item put with us, if it is valid and, for a mutable item, newer than the
+    /// one we have.
+    fn store(&self, item: Item, cas: Option<i64>) -> Result<(), KrpcError> {
+        let error = |code, message: &str| KrpcError {
+            code,
+            message: message.to_owned(),
+        };
+        if item.value().len() > items::MAX_VALUE_LEN {
+            return Err(error(krpc::MESSAGE_TOO_BIG, "message (v field) too big"));
+        }
+        let target = item.target();
+        let mut items = self.items.lock().unwrap();
+        if let Item::Mutable(new) = &item {
+            if new.salt.len() > items::MAX_SALT_LEN {
+                return Err(error(krpc::SALT_TOO_BIG, "salt (salt field) too big"));
+            }
+            if !new.verify() {
+                return Err(error(krpc::INVALID_SIGNATURE, "invalid signature"));
+            }
+            if let Some((Item::Mutable(old), _)) = items.get(&target) {
+                if cas.is_some_and(|cas| cas != old.seq) {
+                    return Err(error(krpc::CAS_MISMATCH, "CAS mismatch"));
+                }
+                if new.seq < old.seq {
+                    return Err(error(
+                        krpc::SEQUENCE_TOO_LOW,
+                        "sequence number less than current",
+                    ));
+                }
+            }
+        }
+        if !items.contains_key(&target) && items.len() >= MAX_ITEMS {
+            items.retain(|_, (_, put)| put.elapsed() < ITEM_TTL);
+            if items.len() >= MAX_ITEMS {
+                let oldest = items
+                    .iter()
+                    .min_by_key(|(_, (_, put))| *put)
+                    .map(|(target, _)| *target);
+                if let Some(oldest) = oldest {
+                    items.remove(&oldest);
+                }
+            }
+        }
+        items.insert(target, (item, Instant::now()));
+        Ok(())
+    }
+
+    /// Send `query` to the node at `addr` and wait for its response. Nodes that answer are
+    /// added to the routing table.
+    async fn query(&self, addr: SocketAddrV4, query: Query) -> Result<Response> {
+        let transaction_id = self
+            .next_transaction
+            .fetch_add(1, Ordering::Relaxed)
+            .to_be_bytes()
+            .to_vec();
+        let (tx, rx) = oneshot::channel();
+        self.pending
+            .lock()
+            .unwrap()
+            .insert(transaction_id.clone(), Pending { addr, response: tx });
+        let message = Message {
+            transaction_id: transaction_id.clone(),
+            body: Body::Query {
+                id: self.id(),
+                query,
+            },
+            ip: None,
+        };
+        let result = async {
+            self.socket.send_to(&message.encode(), addr).await?;
+            let response = tokio::time::timeout(self.query_timeout, rx)
+                .await
+                .with_context(|| format!("DHT node {} did not answer", addr))??;
+            Ok(response?)
+        }
+        .await;
+        self.pending.lock().unwrap().remove(&transaction_id);
+
+        let mut table = self.table.lock().unwrap();
+        match &result {
+            Ok(response) => {
+                table.insert(
+                    NodeInfo {
+                        id: response.id,
+                        addr,
+                    },
+                    true,
+                );
+            }
+            Err(_) => table.failed(addr),
+        }
+        result
+    }
+
+    /// Walk towards `target` from the nodes in `start`, asking each for nodes closer still,
+    /// until the `K` closest nodes have all answered `query`. Returns those nodes with their
+    /// responses, and any peers the nodes asked know about.
+    async fn lookup(
+        &self,
+        target: NodeId,
+        start: Vec<NodeInfo>,
+        query: Query,
+    ) -> (Vec<(NodeInfo, Response)>, HashSet<SocketAddrV4>) {
+        let mut candidates = BTreeMap::new();
+        for node in start {
+            candidates.insert(routing::distance(&node.id, &target), node);
+        }
+        let id = self.id();
+        let mut queried = HashSet::new();
+        let mut answered = BTreeMap::new();
+        let mut peers = HashSet::new();
+        let mut in_flight = FuturesUnordered::new();
+        loop {
+            while in_flight.len() < ALPHA {
+                let next = candidates
+                    .iter()
+                    .find(|(_, node)| node.id != id && !queried.contains(&node.addr))
+                    .map(|(distance, node)| (*distance, *node));
+                let Some((distance, node)) = next else {
+                    break;
+                };
+                // Nodes further away than the K closest that answered can't help
+                if answered.len() >= K && answered.keys().nth(K - 1) < Some(&distance) {
+                    break;
+                }
+                queried.insert(node.addr);
+                in_flight.push(
+                    self.query(node.addr, query.clone())
+                        .map(move |result| (distance, node, result)),
+                );
+            }
+
+            let Some((distance, node, result)) = in_flight.next().await else {
+                break;
+            };
+            let Ok(response) = result else {
+                continue;
+            };
+            for node in &response.nodes {
+                candidates.insert(routing::distance(&node.id, &target), *node);
+            }
+            peers.extend(&response.values);
+            answered.insert(distance, (node, response));
+        }
+        (answered.into_values().take(K).collect(), peers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        items::{Item, MutableItem},
+        krpc, security, Dht, PublicKeyMagnet, Query, Tokens, MAX_ITEMS, MAX_PEERS_PER_TORRENT,
+        MAX_TORRENTS,
+    };
+    use ed25519_dalek::SigningKey;
+    use std::{
+        net::{Ipv4Addr, SocketAddrV4},
+        time::Duration,
+    };
+
+    #[test]
+    fn tokens() {
+        let mut tokens = Tokens::new();
+        let ip = Ipv4Addr::new(10, 0, 0, 1);
+        let token = tokens.issue(ip);
+        assert!(tokens.check(ip, &token));
+        assert!(!tokens.check(Ipv4Addr::new(10, 0, 0, 2), &token));
+
+        // Tokens survive one rotation but not two
+        tokens.rotated -= super::TOKEN_ROTATION;
+        assert!(tokens.check(ip, &token));
+        tokens.rotated -= super::TOKEN_ROTATION;
+        assert!(!tokens.check(ip, &token));
+    }
+
+    #[tokio::test]
+    async fn loopback_network() {
+        // A handful of nodes that all know the first one
+        let mut nodes = Vec::new();
+        for _ in 0..6 {
+            let dht = Dht::bind("127.0.0.1:0".parse().unwrap(), rand::random())
+                .await
+                .unwrap();
+            nodes.push(dht);
+        }
+        let first = nodes[0].node.socket.local_addr().unwrap();
+        assert!(nodes[0].bootstrap(&[], None).await.is_err());
+        for dht in &nodes[1..] {
+            dht.bootstrap(&[first], None).await.unwrap();
+        }
+        // Later nodes have found earlier ones through the first
+        assert!(nodes[5].node.table.lock().unwrap().len() >= 2);
+        assert_eq!(nodes[5].node.external_ip(), Some(Ipv4Addr::LOCALHOST));
+
+        let info_hash = [7; 20];
+        assert!(nodes[1].announce(info_hash, 5000).await.is_empty());
+        let peers = tokio::time::timeout(Duration::from_secs(10), nodes[5].get_peers(info_hash))
+            .await
+            .unwrap();
+        assert_eq!(peers, ["127.0.0.1:5000".parse().unwrap()]);
+
+        {
+            // Announces need a token from the node
+            let response = nodes[2]
+                .node
+                .query(
+                    SocketAddrV4::new(Ipv4Addr::LOCALHOST, first.port()),
+                    Query::AnnouncePeer {
+                        info_hash,
+                        port: 1,
+                        implied_port: false,
+                        token: b"made up".to_vec(),
+                        seed: false,
+                    },
+                )
+                .await;
+            assert_eq!(
+                response.unwrap_err().to_string(),
+                "DHT error 203: bad token"
+            );
+        }
+
+        {
+            // Scrapes count seeds and other peers by IP address
+            let first = SocketAddrV4::new(Ipv4Addr::LOCALHOST, first.port());
+            let get_peers = Query::GetPeers {
+                info_hash,
+                scrape: false,
+                noseed: false,
+            };
+            let response = nodes[3].node.query(first, get_peers).await.unwrap();
+            let announce = Query::AnnouncePeer {
+                info_hash,
+                port: 6000,
+                implied_port: false,
+                token: response.token.unwrap(),
+                seed: true,
+            };
+            nodes[3].node.query(first, announce).await.unwrap();
+            let scrape = nodes[5].scrape(info_hash).await;
+            assert_eq!((scrape.seeds, scrape.peers), (1, 1));
+            assert!(scrape.nodes >= 1);
+
+            let noseed = Query::GetPeers {
+                info_hash,
+                scrape: false,
+                noseed: true,
+            };
+            let response = nodes[3].node.query(first, noseed).await.unwrap();
+            assert!(!response.values.contains(&"127.0.0.1:6000".parse().unwrap()));
+
+            let sample = nodes[5].sample(rand::random()).await;
+            assert!(sample.info_hashes.contains(&info_hash));
+            assert!(sample.total >= 1);
+        }
+
+        {
+            // Items put by one node can be got by another
+            let value = b"12:Hello World!".to_vec();
+            let item = Item::Immutable(value.clone());
+            let target = item.target();
+            assert!(nodes[1].put(item).await.unwrap() >= 1);
+            assert_eq!(nodes[4].get_immutable(target).await, Some(value));
+            assert_eq!(nodes[4].get_immutable([9; 20]).await, None);
+
+            // Only the newest version of a mutable item is kept
+            let key = SigningKey::from_bytes(&[3; 32]);
+            let public_key = key.verifying_key().to_bytes();
+            let info_hash = [0xAB; 20];
+            assert_eq!(
+                nodes[2].publish(&key, b"nightly", info_hash).await.unwrap(),
+                1
+            );
+            assert_eq!(
+                nodes[3]
+                    .publish(&key, b"nightly", [0xCD; 20])
+                    .await
+                    .unwrap(),
+                2
+            );
+            let magnet = PublicKeyMagnet {
+                key: public_key,
+                salt: b"nightly".to_vec(),
+            };
+            assert_eq!(nodes[4].resolve(&magnet).await.unwrap(), [0xCD; 20]);
+            let stale = MutableItem::sign(&key, b"nightly", 1, b"i1e".to_vec()).unwrap();
+            let error = nodes[4].node.store(Item::Mutable(stale.clone()), None);
+            assert_eq!(error.unwrap_err().code, krpc::SEQUENCE_TOO_LOW);
+            let error = nodes[4].node.store(Item::Mutable(stale.clone()), Some(1));
+            assert_eq!(error.unwrap_err().code, krpc::CAS_MISMATCH);
+            nodes[1].put(Item::Mutable(stale)).await.unwrap();
+            assert_eq!(nodes[4].resolve(&magnet).await.unwrap(), [0xCD; 20]);
+
+            let mut forged = MutableItem::sign(&key, b"", 1, b"i1e".to_vec()).unwrap();
+            forged.value = b"i2e".to_vec();
+            let error = nodes[1].put(Item::Mutable(forged)).await.unwrap_err();
+            assert!(format!("{:#}", error).contains("DHT error 206"));
+            assert!(nodes[4].get_mutable(public_key, b"").await.is_none());
+        }
+
+        {
+            // A node started from saved state keeps its ID and rejoins through saved nodes
+            let state = nodes[5].state();
+            assert!(state.nodes.len() >= 2);
+            let restarted = Dht::bind("127.0.0.1:0".parse().unwrap(), state.id)
+                .await
+                .unwrap();
+            assert_eq!(restarted.ping(&state.nodes).await, state.nodes.len());
+            restarted.bootstrap(&[], None).await.unwrap();
+            assert_eq!(restarted.state().id, state.id);
+        }
+
+        {
+            // A node told its public address takes an ID that matches it
+            let dht = Dht::bind("127.0.0.1:0".parse().unwrap(), [0; 20])
+                .await
+                .unwrap();
+            let ip = Ipv4Addr::new(203, 0, 113, 7);
+            dht.bootstrap(&[first], Some(ip)).await.unwrap();
+            assert!(security::is_valid(&dht.node.id(), ip));
+            assert!(dht.node.table.lock().unwrap().len() >= 2);
+        }
+    }
+
+    #[tokio::test]
+    async fn storage_limits() {
+        let dht = Dht::bind("127.0.0.1:0".parse().unwrap(), rand::random())
+            .await
+            .unwrap();
+        let node = &dht.node;
+
+        // Peers of one torrent, with the oldest making way
+        for port in 0..=MAX_PEERS_PER_TORRENT as u16 {
+            node.add_peer([1; 20], SocketAddrV4::new(Ipv4Addr::LOCALHOST, port), false);
+        }
+        let swarm_len = node.peers.lock().unwrap()[&[1; 20]].len();
+        assert_eq!(swarm_len, MAX_PEERS_PER_TORRENT);
+        assert!(!node.peers.lock().unwrap()[&[1; 20]]
+            .contains_key(&SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)));
+
+        // Torrents, with the one announced least recently making way
+        for i in 0..MAX_TORRENTS {
+            let mut info_hash = [0; 20];
+            info_hash[..8].copy_from_slice(&(i as u64 + 2).to_be_bytes());
+            node.add_peer(info_hash, SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1), false);
+        }
+        assert_eq!(node.peers.lock().unwrap().len(), MAX_TORRENTS);
+        assert!(!node.peers.lock().unwrap().contains_key(&[1; 20]));
+
+        // Items
+        for i in 0..=MAX_ITEMS {
+            let item = Item::Immutable(format!("i{}e", i).into_bytes());
+            node.store(item, None).unwrap();
+        }
+        let items = node.items.lock().unwrap();
+        assert_eq!(items.len(), MAX_ITEMS);
+        assert!(!items.contains_key(&Item::Immutable(b"i0e".to_vec()).target()));
+    }
+}
@@ -0,0 +1,83 @@
+use sha1::{Digest, Sha1};
+use std::net::IpAddr;
+
+const BITS: usize = 2048;
+
+/// The bloom filter of peer addresses in BEP 33 scrapes. Filters from several nodes are
+/// combined with [`Bloom::union`], and the number of addresses in them estimated from how
+/// many bits are still unset.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bloom(Box<[u8; BITS / 8]>);
+
+impl Default for Bloom {
+    fn default() -> Self {
+        Bloom(Box::new([0; BITS / 8]))
+    }
+}
+
+impl Bloom {
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        Some(Bloom(Box::new(bytes.try_into().ok()?)))
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0[..]
+    }
+
+    pub fn insert(&mut self, ip: IpAddr) {
+        let hash = match ip {
+            IpAddr::V4(ip) => Sha1::digest(ip.octets()),
+            IpAddr::V6(ip) => Sha1::digest(ip.octets()),
+        };
+        for index in [
+            u16::from_le_bytes([hash[0], hash[1]]),
+            u16::from_le_bytes([hash[2], hash[3]]),
+        ] {
+            let index = usize::from(index) % BITS;
+            self.0[index / 8] |= 1 << (index % 8);
+        }
+    }
+
+    pub fn union(&mut self, other: &Bloom) {
+        for (byte, other) in self.0.iter_mut().zip(other.0.iter()) {
+            *byte |= other;
+        }
+    }
+
+    /// How many addresses were inserted, roughly.
+    pub fn estimate(&self) -> f64 {
+        let unset = self.0.iter().map(|byte| byte.count_zeros()).sum::<u32>();
+        // A full filter says nothing more than that there are many
+        let unset = f64::from(unset.max(1));
+        let m = BITS as f64;
+        (unset / m).ln() / (2.0 * (1.0 - 1.0 / m).ln())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Bloom;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    #[test]
+    fn bloom() {
+        let mut bloom = Bloom::default();
+        assert_eq!(bloom.estimate(), 0.0);
+
+        // From BEP 33
+        for i in 0..=255 {
+            bloom.insert(Ipv4Addr::new(192, 0, 2, i).into());
+        }
+        for i in 0..1000 {
+            bloom.insert(Ipv6Addr::new(0x2001, 0xDB8, 0, 0, 0, 0, 0, i).into());
+        }
+        assert!((bloom.estimate() - 1224.9308).abs() < 0.001);
+
+        let mut other = Bloom::default();
+        other.insert(Ipv4Addr::new(192, 0, 2, 0).into());
+        other.union(&bloom);
+        assert_eq!(other, bloom);
+        assert_eq!(Bloom::from_bytes(bloom.as_bytes()), Some(bloom));
+        assert_eq!(Bloom::from_bytes(&[0; 3]), None);
+    }
+}
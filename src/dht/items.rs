@@ -0,0 +1,202 @@
+//! BEP 44: arbitrary data stored in the DHT. Immutable items are found by the hash of their
+//! value; mutable items by the hash of their owner's public key and a salt, and only the
+//! owner can sign new versions of them.
+use anyhow::{Context, Result};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use sha1::{Digest, Sha1};
+use std::path::Path;
+
+use super::krpc::NodeId;
+use crate::bencode::BencodeValue;
+
+/// The longest value nodes store.
+pub const MAX_VALUE_LEN: usize = 1000;
+/// The longest salt nodes accept.
+pub const MAX_SALT_LEN: usize = 64;
+
+/// An item as stored, with its value still bencoded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Item {
+    Immutable(Vec<u8>),
+    Mutable(MutableItem),
+}
+
+impl Item {
+    pub fn target(&self) -> NodeId {
+        match self {
+            Item::Immutable(value) => Sha1::digest(value).into(),
+            Item::Mutable(item) => item.target(),
+        }
+    }
+
+    pub fn value(&self) -> &[u8] {
+        match self {
+            Item::Immutable(value) => value,
+            Item::Mutable(item) => &item.value,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MutableItem {
+    pub key: [u8; 32],
+    pub salt: Vec<u8>,
+    /// Newer versions have higher sequence numbers.
+    pub seq: i64,
+    pub value: Vec<u8>,
+    pub signature: [u8; 64],
+}
+
+impl MutableItem {
+    pub fn sign(key: &SigningKey, salt: &[u8], seq: i64, value: Vec<u8>) -> Result<Self> {
+        check_value(&value)?;
+        let signature = key.sign(&signed_data(salt, seq, &value));
+        Ok(MutableItem {
+            key: key.verifying_key().to_bytes(),
+            salt: salt.to_vec(),
+            seq,
+            value,
+            signature: signature.to_bytes(),
+        })
+    }
+
+    pub fn target(&self) -> NodeId {
+        mutable_target(&self.key, &self.salt)
+    }
+
+    pub fn verify(&self) -> bool {
+        let Ok(key) = VerifyingKey::from_bytes(&self.key) else {
+            return false;
+        };
+        let signature = Signature::from_bytes(&self.signature);
+        key.verify(&signed_data(&self.salt, self.seq, &self.value), &signature)
+            .is_ok()
+    }
+}
+
+/// Check that nodes will store `value`: bencoded the one way it can be, so that other nodes
+/// hash and verify the very bytes we did, and no longer than they accept.
+pub fn check_value(value: &[u8]) -> Result<()> {
+    anyhow::ensure!(
+        value.len() <= MAX_VALUE_LEN,
+        "value is longer than {} bytes",
+        MAX_VALUE_LEN
+    );
+    let (rest, decoded) = BencodeValue::from_bytes(value).context("value is not bencoded")?;
+    anyhow::ensure!(rest.is_empty(), "value is not bencoded");
+    anyhow::ensure!(
+        decoded.to_bytes() == value,
+        "value is not canonically bencoded, e.g. its dictionary keys are out of order"
+    );
+    Ok(())
+}
+
+pub fn mutable_target(key: &[u8; 32], salt: &[u8]) -> NodeId {
+    Sha1::new()
+        .chain_update(key)
+        .chain_update(salt)
+        .finalize()
+        .into()
+}
+
+/// What the signature of a mutable item covers: its salt, sequence number and value, the
+/// way they would appear in a bencoded dictionary.
+fn signed_data(salt: &[u8], seq: i64, value: &[u8]) -> Vec<u8> {
+    let mut data = Vec::new();
+    if !salt.is_empty() {
+        data.extend(format!("4:salt{}:", salt.len()).as_bytes());
+        data.extend(salt);
+    }
+    data.extend(format!("3:seqi{}e1:v", seq).as_bytes());
+    data.extend(value);
+    data
+}
+
+/// Read the hex private key at `path`, making one first if there is none.
+pub fn signing_key(path: &Path) -> Result<SigningKey> {
+    match std::fs::read_to_string(path) {
+        Ok(hex) => {
+            let seed = hex::decode(hex.trim())
+                .ok()
+                .and_then(|seed| <[u8; 32]>::try_from(seed).ok())
+                .with_context(|| format!("{} is not a private key", path.display()))?;
+            Ok(SigningKey::from_bytes(&seed))
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let key = SigningKey::generate(&mut rand::rngs::OsRng);
+            let mut options = std::fs::OpenOptions::new();
+            options.write(true).create_new(true);
+            #[cfg(unix)]
+            std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+            let mut file = options
+                .open(path)
+                .with_context(|| format!("failed to create {}", path.display()))?;
+            std::io::Write::write_all(&mut file, hex::encode(key.to_bytes()).as_bytes())?;
+            Ok(key)
+        }
+        Err(e) => Err(e).with_context(|| format!("failed to read {}", path.display())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_value, signing_key, Item, MutableItem, MAX_VALUE_LEN};
+
+    #[test]
+    fn items() {
+        // From BEP 44
+        let value = b"12:Hello World!".to_vec();
+        assert_eq!(
+            hex::encode(Item::Immutable(value.clone()).target()),
+            "e5f96f6f38320f0f33959cb4d3d656452117aadb"
+        );
+        let key = hex::decode("77ff84905a91936367c01360803104f92432fcd904a43511876df5cdf3e7e548")
+            .unwrap()
+            .try_into()
+            .unwrap();
+        for (salt, signature, target) in [
+            (
+                &b""[..],
+                "305ac8aeb6c9c151fa120f120ea2cfb923564e11552d06a5d856091e5e853cff1260d3f39e4999684aa92eb73ffd136e6f4f3ecbfda0ce53a1608ecd7ae21f01",
+                "4a533d47ec9c7d95b1ad75f576cffc641853b750",
+            ),
+            (
+                b"foobar",
+                "6834284b6b24c3204eb2fea824d82f88883a3d95e8b4a21b8c0ded553d17d17ddf9a8a7104b1258f30bed3787e6cb896fca78c58f8e03b5f18f14951a87d9a08",
+                "411eba73b6f087ca51a3795d9c8c938d365e32c1",
+            ),
+        ] {
+            let mut item = MutableItem {
+                key,
+                salt: salt.to_vec(),
+                seq: 1,
+                value: value.clone(),
+                signature: hex::decode(signature).unwrap().try_into().unwrap(),
+            };
+            assert!(item.verify());
+            assert_eq!(hex::encode(item.target()), target);
+            item.seq = 2;
+            assert!(!item.verify());
+        }
+
+        {
+            // Keys are made once and then reused
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("key");
+            let key = signing_key(&path).unwrap();
+            assert_eq!(signing_key(&path).unwrap(), key);
+            let item = MutableItem::sign(&key, b"salt", 5, value).unwrap();
+            assert!(item.verify());
+        }
+
+        {
+            // Only values nodes can store as they are
+            assert!(check_value(b"d1:ai1e1:bi2ee").is_ok());
+            assert!(check_value(b"d1:bi2e1:ai1ee").is_err());
+            assert!(check_value(b"i01e").is_err());
+            assert!(check_value(b"i1ei2e").is_err());
+            let long = format!("{}:{}", MAX_VALUE_LEN, "a".repeat(MAX_VALUE_LEN));
+            assert!(check_value(long.as_bytes()).is_err());
+        }
+    }
+}
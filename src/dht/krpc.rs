@@ -0,0 +1,677 @@
+use anyhow::{Context, Result};
+use std::{
+    collections::BTreeMap,
+    net::{Ipv4Addr, SocketAddrV4},
+};
+use thiserror::Error;
+
+use super::{
+    bloom::Bloom,
+    items::{Item, MutableItem},
+};
+use crate::bencode::{BencodeByteString, BencodeValue};
+
+pub type NodeId = [u8; 20];
+
+/// A node as it appears in `nodes` lists: its ID and compact address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NodeInfo {
+    pub id: NodeId,
+    pub addr: SocketAddrV4,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Query {
+    Ping,
+    FindNode {
+        target: NodeId,
+    },
+    GetPeers {
+        info_hash: [u8; 20],
+        /// Ask for bloom filters of the seeds and peers instead of just some peers (BEP 33).
+        scrape: bool,
+        /// Leave seeds out of the peers returned.
+        noseed: bool,
+    },
+    AnnouncePeer {
+        info_hash: [u8; 20],
+        port: u16,
+        /// Use the port the query came from instead of `port`, for peers behind NAT.
+        implied_port: bool,
+        token: Vec<u8>,
+        /// Whether we have the whole torrent (BEP 33).
+        seed: bool,
+    },
+    /// Ask for a sample of the info hashes the node has peers for (BEP 51).
+    SampleInfohashes {
+        target: NodeId,
+    },
+    /// Ask for the item stored under `target` (BEP 44). For mutable items, only if it is
+    /// newer than `seq`.
+    Get {
+        target: NodeId,
+        seq: Option<i64>,
+    },
+    /// Store an item. A mutable item only replaces the one stored if that has sequence
+    /// number `cas`, when given.
+    Put {
+        token: Vec<u8>,
+        item: Item,
+        cas: Option<i64>,
+    },
+    /// A method we don't implement.
+    Unknown(String),
+}
+
+impl Query {
+    fn method(&self) -> &str {
+        match self {
+            Query::Ping => "ping",
+            Query::FindNode { .. } => "find_node",
+            Query::GetPeers { .. } => "get_peers",
+            Query::AnnouncePeer { .. } => "announce_peer",
+            Query::SampleInfohashes { .. } => "sample_infohashes",
+            Query::Get { .. } => "get",
+            Query::Put { .. } => "put",
+            Query::Unknown(method) => method,
+        }
+    }
+}
+
+/// The arguments of a response. Which are set depends on the query it answers.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Response {
+    pub id: NodeId,
+    pub nodes: Vec<NodeInfo>,
+    /// Peers for a `get_peers`.
+    pub values: Vec<SocketAddrV4>,
+    /// What an `announce_peer` to this node must carry.
+    pub token: Option<Vec<u8>>,
+    /// The seeds and the other peers the node knows of, for a scraping `get_peers`.
+    pub bloom_seeds: Option<Bloom>,
+    pub bloom_peers: Option<Bloom>,
+    /// Info hashes the node has peers for, for a `sample_infohashes`.
+    pub samples: Vec<[u8; 20]>,
+    /// How many info hashes the node has peers for in all.
+    pub num: Option<i64>,
+    /// How many seconds to wait before asking the node for another sample.
+    pub interval: Option<i64>,
+    /// The bencoded value of the item asked for with a `get`.
+    pub value: Option<Vec<u8>>,
+    /// The public key, signature and sequence number of a mutable item.
+    pub key: Option<[u8; 32]>,
+    pub signature: Option<[u8; 64]>,
+    pub seq: Option<i64>,
+}
+
+/// An error message from a node.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("DHT error {code}: {message}")]
+pub struct KrpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+pub const PROTOCOL_ERROR: i64 = 203;
+pub const METHOD_UNKNOWN: i64 = 204;
+pub const MESSAGE_TOO_BIG: i64 = 205;
+pub const INVALID_SIGNATURE: i64 = 206;
+pub const SALT_TOO_BIG: i64 = 207;
+pub const CAS_MISMATCH: i64 = 301;
+pub const SEQUENCE_TOO_LOW: i64 = 302;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Body {
+    Query { id: NodeId, query: Query },
+    Response(Response),
+    Error(KrpcError),
+}
+
+/// A KRPC message. Responses and errors carry the transaction ID of their query, and the
+/// address the query came from (BEP 42).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub transaction_id: Vec<u8>,
+    pub body: Body,
+    pub ip: Option<SocketAddrV4>,
+}
+
+impl Message {
+    pub fn encode(&self) -> Vec<u8> {
+        match &self.body {
+            Body::Query { id, query } => {
+                let mut args = BTreeMap::new();
+                args.insert(BencodeByteString(b"id"), byte_string(id));
+                match query {
+                    Query::Ping | Query::Unknown(_) => {}
+                    Query::FindNode { target } => {
+                        args.insert(BencodeByteString(b"target"), byte_string(target));
+                    }
+                    Query::GetPeers {
+                        info_hash,
+                        scrape,
+                        noseed,
+                    } => {
+                        args.insert(BencodeByteString(b"info_hash"), byte_string(info_hash));
+                        insert_flag(&mut args, b"scrape", *scrape);
+                        insert_flag(&mut args, b"noseed", *noseed);
+                    }
+                    Query::AnnouncePeer {
+                        info_hash,
+                        port,
+                        implied_port,
+                        token,
+                        seed,
+                    } => {
+                        args.insert(BencodeByteString(b"info_hash"), byte_string(info_hash));
+                        args.insert(
+                            BencodeByteString(b"port"),
+                            BencodeValue::Integer((*port).into()),
+                        );
+                        insert_flag(&mut args, b"implied_port", *implied_port);
+                        args.insert(BencodeByteString(b"token"), byte_string(token));
+                        insert_flag(&mut args, b"seed", *seed);
+                    }
+                    Query::SampleInfohashes { target } => {
+                        args.insert(BencodeByteString(b"target"), byte_string(target));
+                    }
+                    Query::Get { target, seq } => {
+                        args.insert(BencodeByteString(b"target"), byte_string(target));
+                        if let Some(seq) = seq {
+                            args.insert(BencodeByteString(b"seq"), BencodeValue::Integer(*seq));
+                        }
+                    }
+                    Query::Put { token, item, cas } => {
+                        args.insert(BencodeByteString(b"token"), byte_string(token));
+                        if let Item::Mutable(item) = item {
+                            args.insert(BencodeByteString(b"k"), byte_string(&item.key));
+                            args.insert(BencodeByteString(b"sig"), byte_string(&item.signature));
+                            args.insert(BencodeByteString(b"seq"), BencodeValue::Integer(item.seq));
+                            if !item.salt.is_empty() {
+                                args.insert(BencodeByteString(b"salt"), byte_string(&item.salt));
+                            }
+                        }
+                        if let Some(cas) = cas {
+                            args.insert(BencodeByteString(b"cas"), BencodeValue::Integer(*cas));
+                        }
+                    }
+                }
+                let value = match query {
+                    Query::Put { item, .. } => Some(item.value()),
+                    _ => None,
+                };
+                self.envelope(
+                    b"q",
+                    Some(query.method().as_bytes()),
+                    b"a",
+                    encode_args(args, value),
+                )
+            }
+            Body::Response(response) => {
+                let nodes = encode_nodes(&response.nodes);
+                let values = response
+                    .values
+                    .iter()
+                    .map(|addr| encode_addr(*addr))
+                    .collect::<Vec<_>>();
+                let samples = response.samples.concat();
+                let mut args = BTreeMap::new();
+                args.insert(BencodeByteString(b"id"), byte_string(&response.id));
+                if !nodes.is_empty() {
+                    args.insert(BencodeByteString(b"nodes"), byte_string(&nodes));
+                }
+                if !values.is_empty() {
+                    args.insert(
+                        BencodeByteString(b"values"),
+                        BencodeValue::List(values.iter().map(|value| byte_string(value)).collect()),
+                    );
+                }
+                if let Some(token) = &response.token {
+                    args.insert(BencodeByteString(b"token"), byte_string(token));
+                }
+                if let Some(bloom) = &response.bloom_seeds {
+                    args.insert(BencodeByteString(b"BFsd"), byte_string(bloom.as_bytes()));
+                }
+                if let Some(bloom) = &response.bloom_peers {
+                    args.insert(BencodeByteString(b"BFpe"), byte_string(bloom.as_bytes()));
+                }
+                if let Some(num) = response.num {
+                    args.insert(BencodeByteString(b"samples"), byte_string(&samples));
+                    args.insert(BencodeByteString(b"num"), BencodeValue::Integer(num));
+                }
+                if let Some(interval) = response.interval {
+                    args.insert(
+                        BencodeByteString(b"interval"),
+                        BencodeValue::Integer(interval),
+                    );
+                }
+                if let Some(key) = &response.key {
+                    args.insert(BencodeByteString(b"k"), byte_string(key));
+                }
+                if let Some(signature) = &response.signature {
+                    args.insert(BencodeByteString(b"sig"), byte_string(signature));
+                }
+                if let Some(seq) = response.seq {
+                    args.insert(BencodeByteString(b"seq"), BencodeValue::Integer(seq));
+                }
+                self.envelope(
+                    b"r",
+                    None,
+                    b"r",
+                    encode_args(args, response.value.as_deref()),
+                )
+            }
+            Body::Error(error) => self.envelope(
+                b"e",
+                None,
+                b"e",
+                BencodeValue::List(vec![
+                    BencodeValue::Integer(error.code),
+                    byte_string(error.message.as_bytes()),
+                ])
+                .to_bytes(),
+            ),
+        }
+    }
+
+    /// Wrap the bencoded body `value` under `key`, along with the message type, the method of
+    /// a query and the transaction ID.
+    fn envelope(&self, kind: &[u8], method: Option<&[u8]>, key: &[u8], value: Vec<u8>) -> Vec<u8> {
+        let mut dict = BTreeMap::new();
+        if let Some(ip) = self.ip {
+            dict.insert(&b"ip"[..], byte_string(&encode_addr(ip)).to_bytes());
+        }
+        if let Some(method) = method {
+            dict.insert(b"q", byte_string(method).to_bytes());
+        }
+        dict.insert(b"t", byte_string(&self.transaction_id).to_bytes());
+        dict.insert(b"y", byte_string(kind).to_bytes());
+        dict.insert(key, value);
+        encode_dictionary(dict)
+    }
+
+    pub fn decode(input: &[u8]) -> Result<Self> {
+        let (_, value) = BencodeValue::from_bytes(input)?;
+        let dict = value
+            .as_dictionary()
+            .context("KRPC message is not a dictionary")?;
+        let transaction_id = bytes(dict, b"t").context("missing transaction ID")?;
+        let body = match bytes(dict, b"y").context("missing message type")? {
+            b"q" => {
+                let method = bytes(dict, b"q").context("missing method")?;
+                let args = dict
+                    .get(&BencodeByteString(b"a"))
+                    .and_then(BencodeValue::as_dictionary)
+                    .context("missing query arguments")?;
+                let query = match method {
+                    b"ping" => Query::Ping,
+                    b"find_node" => Query::FindNode {
+                        target: id(args, b"target")?,
+                    },
+                    b"get_peers" => Query::GetPeers {
+                        info_hash: id(args, b"info_hash")?,
+                        scrape: flag(args, b"scrape"),
+                        noseed: flag(args, b"noseed"),
+                    },
+                    b"announce_peer" => Query::AnnouncePeer {
+                        info_hash: id(args, b"info_hash")?,
+                        port: args
+                            .get(&BencodeByteString(b"port"))
+                            .and_then(BencodeValue::as_integer)
+                            .and_then(|port| u16::try_from(*port).ok())
+                            .context("missing or invalid port")?,
+                        implied_port: flag(args, b"implied_port"),
+                        token: bytes(args, b"token").context("missing token")?.to_vec(),
+                        seed: flag(args, b"seed"),
+                    },
+                    b"sample_infohashes" => Query::SampleInfohashes {
+                        target: id(args, b"target")?,
+                    },
+                    b"get" => Query::Get {
+                        target: id(args, b"target")?,
+                        seq: integer(args, b"seq"),
+                    },
+                    b"put" => {
+                        let value = item_value(input, b"a").context("missing value")?;
+                        let item = match bytes(args, b"k") {
+                            None => Item::Immutable(value),
+                            Some(key) => Item::Mutable(MutableItem {
+                                key: key.try_into().context("invalid public key")?,
+                                salt: bytes(args, b"salt").unwrap_or_default().to_vec(),
+                                seq: integer(args, b"seq").context("missing seq")?,
+                                value,
+                                signature: bytes(args, b"sig")
+                                    .and_then(|sig| sig.try_into().ok())
+                                    .context("missing or invalid signature")?,
+                            }),
+                        };
+                        Query::Put {
+                            token: bytes(args, b"token").context("missing token")?.to_vec(),
+                            item,
+                            cas: integer(args, b"cas"),
+                        }
+                    }
+                    method => Query::Unknown(String::from_utf8_lossy(method).into_owned()),
+                };
+                Body::Query {
+                    id: id(args, b"id")?,
+                    query,
+                }
+            }
+            b"r" => {
+                let args = dict
+                    .get(&BencodeByteString(b"r"))
+                    .and_then(BencodeValue::as_dictionary)
+                    .context("missing response arguments")?;
+                let values = args
+                    .get(&BencodeByteString(b"values"))
+                    .and_then(BencodeValue::as_list)
+                    .unwrap_or_default()
+                    .iter()
+                    .filter_map(|value| decode_addr(value.as_byte_string()?.0))
+                    .collect();
+                Body::Response(Response {
+                    id: id(args, b"id")?,
+                    nodes: bytes(args, b"nodes").map(decode_nodes).unwrap_or_default(),
+                    values,
+                    token: bytes(args, b"token").map(<[u8]>::to_vec),
+                    bloom_seeds: bytes(args, b"BFsd").and_then(Bloom::from_bytes),
+                    bloom_peers: bytes(args, b"BFpe").and_then(Bloom::from_bytes),
+                    samples: bytes(args, b"samples")
+                        .unwrap_or_default()
+                        .chunks_exact(20)
+                        .map(|hash| hash.try_into().unwrap())
+                        .collect(),
+                    num: integer(args, b"num"),
+                    interval: integer(args, b"interval"),
+                    value: item_value(input, b"r"),
+                    key: bytes(args, b"k").and_then(|key| key.try_into().ok()),
+                    signature: bytes(args, b"sig").and_then(|sig| sig.try_into().ok()),
+                    seq: integer(args, b"seq"),
+                })
+            }
+            b"e" => {
+                let error = dict
+                    .get(&BencodeByteString(b"e"))
+                    .and_then(BencodeValue::as_list)
+                    .context("missing error")?;
+                let [code, message] = error else {
+                    anyhow::bail!("invalid error");
+                };
+                Body::Error(KrpcError {
+                    code: *code.as_integer().context("invalid error code")?,
+                    message: String::from_utf8_lossy(
+                        message.as_byte_string().context("invalid error message")?.0,
+                    )
+                    .into_owned(),
+                })
+            }
+            kind => anyhow::bail!("unknown message type {:?}", String::from_utf8_lossy(kind)),
+        };
+        Ok(Message {
+            transaction_id: transaction_id.to_vec(),
+            body,
+            ip: bytes(dict, b"ip").and_then(decode_addr),
+        })
+    }
+}
+
+fn byte_string(bytes: &[u8]) -> BencodeValue<'_> {
+    BencodeValue::ByteString(BencodeByteString(bytes))
+}
+
+fn bytes<'a>(
+    dict: &BTreeMap<BencodeByteString<'a>, BencodeValue<'a>>,
+    key: &[u8],
+) -> Option<&'a [u8]> {
+    dict.get(&BencodeByteString(key))
+        .and_then(BencodeValue::as_byte_string)
+        .map(|bs| bs.0)
+}
+
+/// The bencoded `v` of a BEP 44 item in the arguments under `key` of the message `input`,
+/// exactly as it was sent: its hash or signature covers those bytes, which re-encoding
+/// would change if they aren't canonical.
+fn item_value(input: &[u8], key: &[u8]) -> Option<Vec<u8>> {
+    let args = BencodeValue::dictionary_entry_bytes(input, key).ok()??;
+    let value = BencodeValue::dictionary_entry_bytes(args, b"v").ok()??;
+    Some(value.to_vec())
+}
+
+/// Encode `args`, with the bencoded item `value` copied in as it is.
+fn encode_args(args: BTreeMap<BencodeByteString, BencodeValue>, value: Option<&[u8]>) -> Vec<u8> {
+    let mut dict = args
+        .iter()
+        .map(|(key, value)| (key.0, value.to_bytes()))
+        .collect::<BTreeMap<_, _>>();
+    if let Some(value) = value {
+        dict.insert(b"v", value.to_vec());
+    }
+    encode_dictionary(dict)
+}
+
+/// A dictionary of keys and their already bencoded values.
+fn encode_dictionary(dict: BTreeMap<&[u8], Vec<u8>>) -> Vec<u8> {
+    let mut output = vec![b'd'];
+    for (key, value) in dict {
+        output.extend(BencodeByteString(key).to_bytes());
+        output.extend(value);
+    }
+    output.push(b'e');
+    output
+}
+
+fn integer(dict: &BTreeMap<BencodeByteString, BencodeValue>, key: &[u8]) -> Option<i64> {
+    dict.get(&BencodeByteString(key))
+        .and_then(BencodeValue::as_integer)
+        .copied()
+}
+
+/// Boolean arguments are integers that are 1 when set, and usually left out when not.
+fn flag(dict: &BTreeMap<BencodeByteString, BencodeValue>, key: &[u8]) -> bool {
+    integer(dict, key).is_some_and(|n| n != 0)
+}
+
+fn insert_flag<'a>(
+    dict: &mut BTreeMap<BencodeByteString<'a>, BencodeValue<'a>>,
+    key: &'a [u8],
+    value: bool,
+) {
+    if value {
+        dict.insert(BencodeByteString(key), BencodeValue::Integer(1));
+    }
+}
+
+fn id(dict: &BTreeMap<BencodeByteString, BencodeValue>, key: &[u8]) -> Result<[u8; 20]> {
+    bytes(dict, key)
+        .and_then(|bytes| bytes.try_into().ok())
+        .with_context(|| format!("missing or invalid {}", String::from_utf8_lossy(key)))
+}
+
+pub fn encode_addr(addr: SocketAddrV4) -> [u8; 6] {
+    let mut compact = [0; 6];
+    compact[..4].copy_from_slice(&addr.ip().octets());
+    compact[4..].copy_from_slice(&addr.port().to_be_bytes());
+    compact
+}
+
+pub fn decode_addr(compact: &[u8]) -> Option<SocketAddrV4> {
+    let compact: [u8; 6] = compact.try_into().ok()?;
+    Some(SocketAddrV4::new(
+        Ipv4Addr::new(compact[0], compact[1], compact[2], compact[3]),
+        u16::from_be_bytes([compact[4], compact[5]]),
+    ))
+}
+
+/// Compact node info: 20-byte ID and 6-byte address for each node.
+pub fn encode_nodes(nodes: &[NodeInfo]) -> Vec<u8> {
+    let mut compact = Vec::with_capacity(26 * nodes.len());
+    for node in nodes {
+        compact.extend(node.id);
+        compact.extend(encode_addr(node.addr));
+    }
+    compact
+}
+
+pub fn decode_nodes(compact: &[u8]) -> Vec<NodeInfo> {
+    compact
+        .chunks_exact(26)
+        .filter_map(|node| {
+            Some(NodeInfo {
+                id: node[..20].try_into().unwrap(),
+                addr: decode_addr(&node[20..])?,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Bloom, Body, Item, KrpcError, Message, MutableItem, NodeInfo, Query, Response};
+
+    #[test]
+    fn messages() {
+        // From BEP 5
+        let input = b"d1:ad2:id20:abcdefghij01234567899:info_hash20:mnopqrstuvwxyz123456e1:q9:get_peers1:t2:aa1:y1:qe";
+        let message = Message::decode(input).unwrap();
+        assert_eq!(
+            message,
+            Message {
+                transaction_id: b"aa".to_vec(),
+                body: Body::Query {
+                    id: *b"abcdefghij0123456789",
+                    query: Query::GetPeers {
+                        info_hash: *b"mnopqrstuvwxyz123456",
+                        scrape: false,
+                        noseed: false,
+                    },
+                },
+                ip: None,
+            }
+        );
+        assert_eq!(message.encode(), input);
+
+        let input = b"d1:eli201e23:A Generic Error Ocurrede1:t2:aa1:y1:ee";
+        let message = Message::decode(input).unwrap();
+        assert_eq!(
+            message.body,
+            Body::Error(KrpcError {
+                code: 201,
+                message: "A Generic Error Ocurred".to_owned()
+            })
+        );
+        assert_eq!(message.encode(), input);
+
+        for body in [
+            Body::Query {
+                id: [1; 20],
+                query: Query::AnnouncePeer {
+                    info_hash: [2; 20],
+                    port: 6881,
+                    implied_port: true,
+                    token: b"token".to_vec(),
+                    seed: true,
+                },
+            },
+            Body::Query {
+                id: [1; 20],
+                query: Query::GetPeers {
+                    info_hash: [2; 20],
+                    scrape: true,
+                    noseed: true,
+                },
+            },
+            Body::Query {
+                id: [1; 20],
+                query: Query::SampleInfohashes { target: [2; 20] },
+            },
+            Body::Query {
+                id: [1; 20],
+                query: Query::Get {
+                    target: [2; 20],
+                    seq: Some(4),
+                },
+            },
+            Body::Query {
+                id: [1; 20],
+                query: Query::Put {
+                    token: b"token".to_vec(),
+                    item: Item::Immutable(b"li1ei2ee".to_vec()),
+                    cas: None,
+                },
+            },
+            Body::Query {
+                id: [1; 20],
+                query: Query::Put {
+                    token: b"token".to_vec(),
+                    item: Item::Mutable(MutableItem {
+                        key: [7; 32],
+                        salt: b"salt".to_vec(),
+                        seq: 3,
+                        value: b"d2:ih20:aaaaaaaaaaaaaaaaaaaae".to_vec(),
+                        signature: [8; 64],
+                    }),
+                    cas: Some(2),
+                },
+            },
+            Body::Query {
+                id: [1; 20],
+                query: Query::Unknown("vote".to_owned()),
+            },
+            Body::Response(Response {
+                id: [3; 20],
+                nodes: vec![NodeInfo {
+                    id: [4; 20],
+                    addr: "10.0.0.1:1".parse().unwrap(),
+                }],
+                values: vec!["10.0.0.2:2".parse().unwrap()],
+                token: Some(b"token".to_vec()),
+                bloom_seeds: Some(Bloom::default()),
+                bloom_peers: Some(Bloom::from_bytes(&[0xFF; 256]).unwrap()),
+                ..Response::default()
+            }),
+            Body::Response(Response {
+                id: [3; 20],
+                samples: vec![[5; 20], [6; 20]],
+                num: Some(2),
+                interval: Some(60),
+                ..Response::default()
+            }),
+            Body::Response(Response {
+                id: [3; 20],
+                token: Some(b"token".to_vec()),
+                value: Some(b"12:Hello World!".to_vec()),
+                key: Some([7; 32]),
+                signature: Some([8; 64]),
+                seq: Some(1),
+                ..Response::default()
+            }),
+        ] {
+            let message = Message {
+                transaction_id: b"t".to_vec(),
+                body,
+                ip: Some("10.0.0.3:3".parse().unwrap()),
+            };
+            assert_eq!(Message::decode(&message.encode()).unwrap(), message);
+        }
+
+        {
+            // Item values go through as they were sent, even if re-encoding would change them
+            let message = Message {
+                transaction_id: b"t".to_vec(),
+                body: Body::Query {
+                    id: [1; 20],
+                    query: Query::Put {
+                        token: b"token".to_vec(),
+                        item: Item::Immutable(b"d1:bi1e1:ai2ee".to_vec()),
+                        cas: None,
+                    },
+                },
+                ip: None,
+            };
+            assert_eq!(Message::decode(&message.encode()).unwrap(), message);
+        }
+
+        assert!(Message::decode(b"d1:t2:aa1:y1:qe").is_err());
+    }
+}
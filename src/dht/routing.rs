@@ -0,0 +1,239 @@
+use std::{
+    net::SocketAddrV4,
+    time::{Duration, Instant},
+};
+
+use super::{
+    krpc::{NodeId, NodeInfo},
+    security,
+};
+
+/// How many nodes a bucket holds, and how many nodes a lookup ends on.
+pub const K: usize = 8;
+/// Nodes we haven't heard from for this long may be replaced by new ones.
+const QUESTIONABLE_AFTER: Duration = Duration::from_secs(15 * 60);
+/// Nodes that failed to answer this many queries in a row are replaced first.
+const MAX_FAILURES: u32 = 2;
+
+pub fn distance(a: &NodeId, b: &NodeId) -> NodeId {
+    std::array::from_fn(|i| a[i] ^ b[i])
+}
+
+struct Entry {
+    node: NodeInfo,
+    last_seen: Instant,
+    failures: u32,
+    /// Whether the node's ID matches its IP address.
+    secure: bool,
+}
+
+impl Entry {
+    fn is_bad(&self) -> bool {
+        self.failures >= MAX_FAILURES
+    }
+}
+
+/// A Kademlia routing table: bucket `i` holds up to `K` nodes whose distance from us has
+/// `i` leading zero bits, so we know many nodes close to us and a few far away.
+pub struct RoutingTable {
+    id: NodeId,
+    buckets: Vec<Vec<Entry>>,
+}
+
+impl RoutingTable {
+    pub fn new(id: NodeId) -> Self {
+        RoutingTable {
+            id,
+            buckets: (0..160).map(|_| Vec::new()).collect(),
+        }
+    }
+
+    fn bucket(&self, id: &NodeId) -> Option<usize> {
+        let distance = distance(&self.id, id);
+        let leading_zeros = distance
+            .iter()
+            .position(|byte| *byte != 0)
+            .map(|i| i * 8 + distance[i].leading_zeros() as usize)?;
+        Some(leading_zeros)
+    }
+
+    /// Note that `node` is alive. It is added if its bucket has room, or has a node that is
+    /// bad or hasn't been heard from for a while. Failing that, a node whose ID matches its
+    /// IP address replaces one whose ID doesn't. Returns whether the node is in the table.
+    ///
+    /// Anyone can send queries with a known node's ID, so a node only moves to a new address
+    /// if it `answered` a query we sent there, or if its old address went bad. A node whose
+    /// ID matches its address never moves to one its ID doesn't match.
+    pub fn insert(&mut self, node: NodeInfo, answered: bool) -> bool {
+        let Some(i) = self.bucket(&node.id) else {
+            return false;
+        };
+        let secure = security::is_valid(&node.id, *node.addr.ip());
+        let bucket = &mut self.buckets[i];
+        if let Some(entry) = bucket.iter_mut().find(|entry| entry.node.id == node.id) {
+            let moves = entry.node.addr != node.addr;
+            if moves && ((!answered && !entry.is_bad()) || (entry.secure && !secure)) {
+                return false;
+            }
+            entry.node.addr = node.addr;
+            entry.last_seen = Instant::now();
+            entry.failures = 0;
+            entry.secure = secure;
+            return true;
+        }
+        if bucket.len() >= K {
+            let oldest = |replaceable: &dyn Fn(&Entry) -> bool| {
+                bucket
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, entry)| replaceable(entry))
+                    .min_by_key(|(_, entry)| entry.last_seen)
+                    .map(|(i, _)| i)
+            };
+            let replace = bucket
+                .iter()
+                .position(Entry::is_bad)
+                .or_else(|| oldest(&|entry| entry.last_seen.elapsed() >= QUESTIONABLE_AFTER))
+                .or_else(|| oldest(&|entry| secure && !entry.secure));
+            match replace {
+                Some(i) => {
+                    bucket.remove(i);
+                }
+                None => return false,
+            }
+        }
+        bucket.push(Entry {
+            node,
+            last_seen: Instant::now(),
+            failures: 0,
+            secure,
+        });
+        true
+    }
+
+    /// Note that the node at `addr` didn't answer a query.
+    pub fn failed(&mut self, addr: SocketAddrV4) {
+        for entry in self.buckets.iter_mut().flatten() {
+            if entry.node.addr == addr {
+                entry.failures += 1;
+            }
+        }
+    }
+
+    /// The `n` nodes closest to `target` that aren't bad.
+    pub fn closest(&self, target: &NodeId, n: usize) -> Vec<NodeInfo> {
+        let mut nodes = self
+            .buckets
+            .iter()
+            .flatten()
+            .filter(|entry| !entry.is_bad())
+            .map(|entry| entry.node)
+            .collect::<Vec<_>>();
+        nodes.sort_by_key(|node| distance(&node.id, target));
+        nodes.truncate(n);
+        nodes
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(Vec::len).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{distance, RoutingTable, K};
+    use crate::dht::krpc::NodeInfo;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    fn node(id: [u8; 20], port: u16) -> NodeInfo {
+        NodeInfo {
+            id,
+            addr: SocketAddrV4::new(Ipv4Addr::LOCALHOST, port),
+        }
+    }
+
+    #[test]
+    fn routing_table() {
+        let mut table = RoutingTable::new([0; 20]);
+        assert!(
+            !table.insert(node([0; 20], 1), false),
+            "we are not in our own table"
+        );
+
+        // The far half of the ID space only gets one bucket
+        for i in 0..K as u8 + 2 {
+            let mut id = [0xFF; 20];
+            id[19] = i;
+            assert_eq!(table.insert(node(id, i.into()), false), i < K as u8);
+        }
+        let mut close = [0; 20];
+        close[19] = 1;
+        assert!(table.insert(node(close, 100), false));
+        assert_eq!(table.len(), K + 1);
+        assert_eq!(table.closest(&[0; 20], 2)[0].id, close);
+
+        {
+            // Nodes that stop answering make room
+            let mut id = [0xFF; 20];
+            id[19] = 0;
+            table.failed(node(id, 0).addr);
+            table.failed(node(id, 0).addr);
+            assert!(!table.closest(&[0xFF; 20], 20).iter().any(|n| n.id == id));
+            id[19] = 50;
+            assert!(table.insert(node(id, 50), false));
+            assert_eq!(table.len(), K + 1);
+        }
+
+        {
+            // Queries from another address don't move a node, unless it went bad or
+            // answered us from there
+            assert!(!table.insert(node(close, 101), false));
+            assert_eq!(table.closest(&close, 1)[0].addr.port(), 100);
+            assert!(table.insert(node(close, 101), true));
+            assert_eq!(table.closest(&close, 1)[0].addr.port(), 101);
+            table.failed(node(close, 101).addr);
+            table.failed(node(close, 101).addr);
+            assert!(table.insert(node(close, 102), false));
+            assert_eq!(table.closest(&close, 1)[0].addr.port(), 102);
+        }
+
+        {
+            // Nodes whose IDs match their address push out those whose IDs don't
+            let mut table = RoutingTable::new([0; 20]);
+            let ip = Ipv4Addr::new(65, 23, 51, 170);
+            for i in 0..K as u8 {
+                let mut id = [0xFF; 20];
+                id[19] = i;
+                assert!(table.insert(
+                    NodeInfo {
+                        id,
+                        addr: SocketAddrV4::new(ip, i.into()),
+                    },
+                    false
+                ));
+            }
+            // From BEP 42
+            let mut id = [0; 20];
+            id[..3].copy_from_slice(&[0xA5, 0xD4, 0x32]);
+            id[19] = 22;
+            assert!(table.insert(
+                NodeInfo {
+                    id,
+                    addr: SocketAddrV4::new(ip, 100),
+                },
+                false
+            ));
+            assert_eq!(table.len(), K);
+            assert!(table.closest(&id, 1)[0].id == id);
+
+            // Nor can an address the ID doesn't match take its place
+            let impostor = SocketAddrV4::new(Ipv4Addr::new(1, 2, 3, 4), 100);
+            assert!(!table.insert(NodeInfo { id, addr: impostor }, true));
+            assert_eq!(*table.closest(&id, 1)[0].addr.ip(), ip);
+        }
+
+        let mut a = [0; 20];
+        a[0] = 0b1010;
+        assert_eq!(distance(&a, &[0xFF; 20])[0], 0b1111_0101);
+    }
+}
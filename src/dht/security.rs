@@ -0,0 +1,90 @@
+//! BEP 42: node IDs tied to the node's IP address, so that a single machine can't pick IDs
+//! to surround a target and take over the nodes responsible for it.
+use std::net::Ipv4Addr;
+
+use super::krpc::NodeId;
+
+const V4_MASK: [u8; 4] = [0x03, 0x0F, 0x3F, 0xFF];
+
+/// CRC-32C (Castagnoli), bit by bit; it only ever hashes four bytes.
+fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0x82F6_3B78
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// The 21 bits that IDs for `ip` must start with, given the random number `r` that such an
+/// ID ends with.
+fn prefix(ip: Ipv4Addr, r: u8) -> [u8; 3] {
+    let mut masked: [u8; 4] = std::array::from_fn(|i| ip.octets()[i] & V4_MASK[i]);
+    masked[0] |= (r & 0x07) << 5;
+    let crc = crc32c(&masked).to_be_bytes();
+    [crc[0], crc[1], crc[2] & 0xF8]
+}
+
+/// Addresses on local networks can't be checked, and any ID goes for them.
+fn is_local(ip: Ipv4Addr) -> bool {
+    ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_unspecified()
+}
+
+/// A random ID that is valid for a node at `ip`.
+pub fn node_id(ip: Ipv4Addr) -> NodeId {
+    let mut id: NodeId = rand::random();
+    let prefix = prefix(ip, id[19]);
+    id[0] = prefix[0];
+    id[1] = prefix[1];
+    id[2] = prefix[2] | (id[2] & 0x07);
+    id
+}
+
+/// Whether a node at `ip` may use `id`.
+pub fn is_valid(id: &NodeId, ip: Ipv4Addr) -> bool {
+    if is_local(ip) {
+        return true;
+    }
+    let prefix = prefix(ip, id[19]);
+    id[..2] == prefix[..2] && id[2] & 0xF8 == prefix[2]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{crc32c, is_valid, node_id, prefix};
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn node_ids() {
+        assert_eq!(crc32c(b"123456789"), 0xE306_9283);
+
+        // From BEP 42
+        for (ip, r, start) in [
+            ([124, 31, 75, 21], 1, [0x5F, 0xBF, 0xBF]),
+            ([21, 75, 31, 124], 86, [0x5A, 0x3C, 0xE9]),
+            ([65, 23, 51, 170], 22, [0xA5, 0xD4, 0x32]),
+            ([84, 124, 73, 14], 65, [0x1B, 0x03, 0x21]),
+            ([43, 213, 53, 83], 90, [0xE5, 0x6F, 0x6C]),
+        ] {
+            let ip = Ipv4Addr::from(ip);
+            assert_eq!(prefix(ip, r), [start[0], start[1], start[2] & 0xF8]);
+            let mut id = [0; 20];
+            id[..3].copy_from_slice(&start);
+            id[19] = r;
+            assert!(is_valid(&id, ip));
+            id[0] ^= 1;
+            assert!(!is_valid(&id, ip));
+        }
+
+        let ip = Ipv4Addr::new(203, 0, 113, 7);
+        assert!(is_valid(&node_id(ip), ip));
+        assert!(!is_valid(&node_id(ip), Ipv4Addr::new(198, 51, 100, 7)));
+        assert!(is_valid(&[0; 20], Ipv4Addr::new(192, 168, 1, 1)));
+    }
+}
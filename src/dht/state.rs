@@ -0,0 +1,81 @@
+use anyhow::{Context, Result};
+use std::{collections::BTreeMap, path::Path};
+
+use super::krpc::{decode_nodes, encode_nodes, NodeId, NodeInfo};
+use crate::bencode::{BencodeByteString, BencodeValue};
+
+/// What a DHT node remembers between runs: its ID, so that other nodes' routing tables stay
+/// right about it, and the nodes it knew, to join the network through next time.
+#[derive(Debug, PartialEq, Eq)]
+pub struct State {
+    pub id: NodeId,
+    pub nodes: Vec<NodeInfo>,
+}
+
+impl State {
+    /// Read the state saved at `path`, or `None` if nothing was saved yet.
+    pub fn load(path: &Path) -> Result<Option<Self>> {
+        let input = match std::fs::read(path) {
+            Ok(input) => input,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let (_, value) = BencodeValue::from_bytes(&input)?;
+        let dict = value.as_dictionary().context("invalid DHT state")?;
+        let bytes = |key: &[u8]| {
+            dict.get(&BencodeByteString(key))
+                .and_then(BencodeValue::as_byte_string)
+                .map(|bs| bs.0)
+        };
+        Ok(Some(State {
+            id: bytes(b"id")
+                .and_then(|id| id.try_into().ok())
+                .context("invalid node ID in DHT state")?,
+            nodes: bytes(b"nodes").map(decode_nodes).unwrap_or_default(),
+        }))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let nodes = encode_nodes(&self.nodes);
+        let mut dict = BTreeMap::new();
+        dict.insert(
+            BencodeByteString(b"id"),
+            BencodeValue::ByteString(BencodeByteString(&self.id)),
+        );
+        dict.insert(
+            BencodeByteString(b"nodes"),
+            BencodeValue::ByteString(BencodeByteString(&nodes)),
+        );
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(path, BencodeValue::Dictionary(dict).to_bytes())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::State;
+    use crate::dht::krpc::NodeInfo;
+
+    #[test]
+    fn save_and_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state").join("dht.dat");
+        assert_eq!(State::load(&path).unwrap(), None);
+
+        let state = State {
+            id: [1; 20],
+            nodes: vec![NodeInfo {
+                id: [2; 20],
+                addr: "10.0.0.1:6881".parse().unwrap(),
+            }],
+        };
+        state.save(&path).unwrap();
+        assert_eq!(State::load(&path).unwrap(), Some(state));
+
+        std::fs::write(&path, b"d5:nodes0:e").unwrap();
+        assert!(State::load(&path).is_err());
+    }
+}
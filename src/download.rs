@@ -0,0 +1,334 @@
+use anyhow::Result;
+use std::{
+    io::Read,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+use tempfile::TempDir;
+
+use crate::{
+    connections::ConnectionManager, httpseed::HttpSeed, peer::PeerConnection, sanitize::PathPolicy,
+    torrent::Torrent, webseed::WebSeed,
+};
+
+/// Somewhere pieces can be downloaded from.
+pub enum PieceSource {
+    Peer(PeerConnection),
+    WebSeed(WebSeed),
+    HttpSeed(HttpSeed),
+}
+
+impl std::fmt::Display for PieceSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PieceSource::Peer(connection) => write!(f, "peer {}", connection.peer_addr),
+            PieceSource::WebSeed(web_seed) => write!(f, "web seed {}", web_seed.url()),
+            PieceSource::HttpSeed(http_seed) => write!(f, "HTTP seed {}", http_seed.url()),
+        }
+    }
+}
+
+impl PieceSource {
+    /// Download a piece. The data is not checked against the piece hash.
+    async fn fetch_piece(&mut self, torrent: &Torrent, piece_index: usize) -> Result<Vec<u8>> {
+        match self {
+            PieceSource::Peer(connection) => connection.fetch_piece(piece_index).await,
+            PieceSource::WebSeed(web_seed) => {
+                web_seed.fetch_piece(&torrent.info, piece_index).await
+            }
+            PieceSource::HttpSeed(http_seed) => http_seed.fetch_piece(torrent, piece_index).await,
+        }
+    }
+}
+
+/// Running totals of a download, as reported to trackers.
+#[derive(Debug)]
+pub struct Stats {
+    uploaded: AtomicU64,
+    downloaded: AtomicU64,
+    left: AtomicU64,
+}
+
+impl Stats {
+    pub fn new(torrent: &Torrent) -> Self {
+        Stats {
+            uploaded: AtomicU64::new(0),
+            downloaded: AtomicU64::new(0),
+            left: AtomicU64::new(torrent.info.length as u64),
+        }
+    }
+
+    /// Bytes sent to peers.
+    pub fn uploaded(&self) -> u64 {
+        self.uploaded.load(Ordering::Relaxed)
+    }
+
+    /// Bytes received from any source, including pieces that failed the hash check.
+    pub fn downloaded(&self) -> u64 {
+        self.downloaded.load(Ordering::Relaxed)
+    }
+
+    /// Bytes still needed to complete the torrent.
+    pub fn left(&self) -> u64 {
+        self.left.load(Ordering::Relaxed)
+    }
+}
+
+/// Download every piece from `sources` and write the torrent's files to `output_path`, which
+/// is the file itself for single-file torrents and the root directory for multi-file torrents.
+///
+/// Each piece is tried against the sources in order. A source that fails or sends data that
+/// doesn't match the piece hash is dropped. When no sources are left we connect to the next
+/// peer from `connections`, waiting for the trackers to hand one out if needed. Progress is
+/// recorded in `stats` as pieces arrive.
+pub async fn download(
+    torrent: &Arc<Torrent>,
+    mut sources: Vec<PieceSource>,
+    mut connections: ConnectionManager,
+    output_path: &Path,
+    policy: PathPolicy,
+    stats: &Stats,
+) -> Result<()> {
+    let output_files = torrent.info.output_files(output_path, policy)?;
+
+    let temp_dir = TempDir::new()?;
+    for i in 0..torrent.info.piece_count() {
+        loop {
+            if sources.is_empty() {
+                let Some(peer) = connections.connect().await else {
+                    anyhow::bail!("no sources left to download piece {} from", i);
+                };
+                sources.push(PieceSource::Peer(peer));
+            }
+            let source = &mut sources[0];
+            let result = source.fetch_piece(torrent, i).await;
+            if let Ok(piece) = &result {
+                stats
+                    .downloaded
+                    .fetch_add(piece.len() as u64, Ordering::Relaxed);
+            }
+            match result {
+                Ok(piece) if torrent.info.verify_piece(i, &piece) => {
+                    std::fs::write(piece_path(temp_dir.path(), i), &piece)?;
+                    stats.left.fetch_sub(piece.len() as u64, Ordering::Relaxed);
+                    break;
+                }
+                Ok(_) => eprintln!("{} sent piece {} with an incorrect hash", source, i),
+                Err(e) => eprintln!("failed to download piece {} from {}: {}", i, source, e),
+            }
+            sources.remove(0);
+        }
+    }
+
+    let mut pieces = PieceReader {
+        dir: temp_dir.path(),
+        next_index: 0,
+        piece_count: torrent.info.piece_count(),
+        current: None,
+    };
+    for (path, length) in output_files {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut file = std::fs::File::create(path)?;
+        let written = std::io::copy(&mut (&mut pieces).take(length as u64), &mut file)?;
+        if written != length as u64 {
+            anyhow::bail!("pieces are shorter than the files they contain");
+        }
+    }
+
+    Ok(())
+}
+
+fn piece_path(dir: &Path, index: usize) -> PathBuf {
+    dir.join(format!("piece-{}", index))
+}
+
+/// Reads the downloaded piece files in `dir` back to back, as one stream of torrent data.
+struct PieceReader<'a> {
+    dir: &'a Path,
+    next_index: usize,
+    piece_count: usize,
+    current: Option<std::fs::File>,
+}
+
+impl Read for PieceReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            if let Some(file) = &mut self.current {
+                let n = file.read(buf)?;
+                if n > 0 || buf.is_empty() {
+                    return Ok(n);
+                }
+            }
+            if self.next_index == self.piece_count {
+                return Ok(0);
+            }
+            self.current = Some(std::fs::File::open(piece_path(self.dir, self.next_index))?);
+            self.next_index += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{download, PieceSource, Stats};
+    use crate::{
+        connections::ConnectionManager,
+        http::testing::{self, Response},
+        sanitize::PathPolicy,
+        torrent::Torrent,
+        webseed::WebSeed,
+    };
+    use sha1::{Digest, Sha1};
+    use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::Duration};
+    use tokio::sync::mpsc;
+
+    /// Serve `files` over HTTP, honouring single `Range` headers.
+    async fn serve_files(files: HashMap<&'static str, Vec<u8>>) -> SocketAddr {
+        testing::serve(move |request| {
+            let Some(data) = files.get(request.target.as_str()) else {
+                return Response::new(404, "");
+            };
+            match request
+                .header("range")
+                .and_then(|range| range.strip_prefix("bytes="))
+                .and_then(|range| range.split_once('-'))
+            {
+                Some((start, end)) => {
+                    let start = start.parse::<usize>().unwrap();
+                    let end = end.parse::<usize>().unwrap();
+                    Response::new(206, &data[start..=end])
+                }
+                None => Response::new(200, data.clone()),
+            }
+        })
+        .await
+    }
+
+    fn piece_hashes(data: &[u8], piece_length: usize) -> Vec<u8> {
+        data.chunks(piece_length)
+            .flat_map(|piece| {
+                let mut hasher = Sha1::new();
+                hasher.update(piece);
+                hasher.finalize().to_vec()
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn download_from_web_seed() {
+        let files = HashMap::from([
+            ("/seed/root/a", b"abc".to_vec()),
+            ("/seed/root/sub%20dir/b", b"defghij".to_vec()),
+        ]);
+        let addr = serve_files(files).await;
+
+        let mut input = b"d8:announce14:http://a.b/ann4:infod5:filesld6:lengthi3e4:pathl1:aeed6:lengthi7e4:pathl7:sub dir1:beee4:name4:root12:piece lengthi4e6:pieces60:".to_vec();
+        input.extend(piece_hashes(b"abcdefghij", 4));
+        input.extend(b"ee");
+        let torrent = Arc::new(Torrent::from_bytes(&input).unwrap());
+
+        let web_seed = WebSeed::new(&format!("http://{}/seed", addr)).unwrap();
+        let output_dir = tempfile::TempDir::new().unwrap();
+        let stats = Stats::new(&torrent);
+        let (_, no_peers) = mpsc::unbounded_channel();
+        download(
+            &torrent,
+            vec![PieceSource::WebSeed(web_seed)],
+            ConnectionManager::new(torrent.clone(), [0; 20], no_peers),
+            output_dir.path(),
+            PathPolicy::Reject,
+            &stats,
+        )
+        .await
+        .unwrap();
+        assert_eq!(stats.downloaded(), 10);
+        assert_eq!(stats.left(), 0);
+        assert_eq!(std::fs::read(output_dir.path().join("a")).unwrap(), b"abc");
+        assert_eq!(
+            std::fs::read(output_dir.path().join("sub dir/b")).unwrap(),
+            b"defghij"
+        );
+
+        {
+            // A web seed with the wrong data fails the hash check
+            let files = HashMap::from([("/sample.txt", b"not the sample".to_vec())]);
+            let addr = serve_files(files).await;
+            let input = std::fs::read("sample.torrent").unwrap();
+            let torrent = Arc::new(Torrent::from_bytes(&input).unwrap());
+            let web_seed = WebSeed::new(&format!("http://{}/", addr)).unwrap();
+            let stats = Stats::new(&torrent);
+            let (_, no_peers) = mpsc::unbounded_channel();
+            let result = download(
+                &torrent,
+                vec![PieceSource::WebSeed(web_seed)],
+                ConnectionManager::new(torrent.clone(), [0; 20], no_peers),
+                &output_dir.path().join("sample.txt"),
+                PathPolicy::Reject,
+                &stats,
+            )
+            .await;
+            assert!(result.is_err());
+            assert_eq!(stats.left(), torrent.info.length as u64);
+        }
+
+        {
+            // Padding files aren't on the seed, and are filled with zeros instead
+            let files = HashMap::from([
+                ("/seed/padded/a", b"a".to_vec()),
+                ("/seed/padded/b", b"bc".to_vec()),
+            ]);
+            let addr = serve_files(files).await;
+            let mut input = b"d8:announce14:http://a.b/ann4:infod5:filesld6:lengthi1e4:pathl1:aeed4:attr1:p6:lengthi3e4:pathl4:.pad1:3eed6:lengthi2e4:pathl1:beee4:name6:padded12:piece lengthi4e6:pieces40:".to_vec();
+            input.extend(piece_hashes(b"a\0\0\0bc", 4));
+            input.extend(b"ee");
+            let torrent = Arc::new(Torrent::from_bytes(&input).unwrap());
+            let web_seed = WebSeed::new(&format!("http://{}/seed", addr)).unwrap();
+            let output_dir = tempfile::TempDir::new().unwrap();
+            let stats = Stats::new(&torrent);
+            let (_, no_peers) = mpsc::unbounded_channel();
+            download(
+                &torrent,
+                vec![PieceSource::WebSeed(web_seed)],
+                ConnectionManager::new(torrent.clone(), [0; 20], no_peers),
+                output_dir.path(),
+                PathPolicy::Reject,
+                &stats,
+            )
+            .await
+            .unwrap();
+            assert_eq!(std::fs::read(output_dir.path().join("b")).unwrap(), b"bc");
+        }
+
+        {
+            // With the only web seed failing and no trackers, the download stops, even while
+            // peers could still be found on the local network
+            let addr = serve_files(HashMap::new()).await;
+            let input = std::fs::read("sample.torrent").unwrap();
+            let torrent = Arc::new(Torrent::from_bytes(&input).unwrap());
+            let web_seed = WebSeed::new(&format!("http://{}/", addr)).unwrap();
+            let stats = Stats::new(&torrent);
+            let (_, no_peers) = mpsc::unbounded_channel();
+            let connections = ConnectionManager::new(torrent.clone(), [0; 20], no_peers);
+            let _local_peers = connections.discovered();
+            let result = tokio::time::timeout(
+                Duration::from_secs(10),
+                download(
+                    &torrent,
+                    vec![PieceSource::WebSeed(web_seed)],
+                    connections,
+                    &output_dir.path().join("sample.txt"),
+                    PathPolicy::Reject,
+                    &stats,
+                ),
+            )
+            .await
+            .expect("download should stop once the web seed fails");
+            assert!(result.is_err());
+        }
+    }
+}
@@ -0,0 +1,387 @@
+use anyhow::{Context, Result};
+use sha1::{Digest, Sha1};
+use std::collections::BTreeMap;
+
+use crate::bencode::{BencodeByteString, BencodeValue};
+
+/// Changes to make to a torrent file. Everything except `source` and `private` lives outside
+/// the info dictionary and leaves the info hash alone.
+#[derive(Debug, Default, clap::Args)]
+#[group(skip)]
+pub struct Edit {
+    /// Replace all trackers. Tiers are separated by `;` and trackers within a tier by `,`
+    #[arg(long, value_name = "TIERS")]
+    pub set_trackers: Option<String>,
+    /// Remove the tier at this position, counting from 0
+    #[arg(long = "remove-tier", value_name = "INDEX")]
+    pub remove_tiers: Vec<usize>,
+    /// Remove a tracker from every tier it appears in
+    #[arg(long = "remove-tracker", value_name = "URL")]
+    pub remove_trackers: Vec<String>,
+    /// Add a tracker in a new tier of its own after the existing ones
+    #[arg(long = "add-tracker", value_name = "URL")]
+    pub add_trackers: Vec<String>,
+    /// Set the comment, or remove it if empty
+    #[arg(long)]
+    pub comment: Option<String>,
+    /// Replace the web seeds (`url-list`) with these URLs
+    #[arg(long = "web-seed", value_name = "URL")]
+    pub web_seeds: Vec<String>,
+    /// Remove all web seeds
+    #[arg(long)]
+    pub clear_web_seeds: bool,
+    /// Remove the creation date
+    #[arg(long)]
+    pub strip_creation_date: bool,
+    /// Set the source tag, or remove it if empty. This changes the info hash
+    #[arg(long)]
+    pub source: Option<String>,
+    /// Set or clear the private flag. This changes the info hash
+    #[arg(long)]
+    pub private: Option<bool>,
+}
+
+/// The edited torrent file.
+pub struct Edited {
+    pub bytes: Vec<u8>,
+    pub old_info_hash: String,
+    pub new_info_hash: String,
+}
+
+impl Edited {
+    pub fn info_hash_changed(&self) -> bool {
+        self.old_info_hash != self.new_info_hash
+    }
+}
+
+impl Edit {
+    fn edits_trackers(&self) -> bool {
+        self.set_trackers.is_some()
+            || !self.remove_tiers.is_empty()
+            || !self.remove_trackers.is_empty()
+            || !self.add_trackers.is_empty()
+    }
+
+    fn edits_info(&self) -> bool {
+        self.source.is_some() || self.private.is_some()
+    }
+
+    /// Apply the changes to the torrent file in `input`. The values of keys that aren't being
+    /// changed are copied over byte for byte, and so is the info dictionary unless an info
+    /// field is being changed.
+    pub fn apply(&self, input: &[u8]) -> Result<Edited> {
+        let (_, value) = BencodeValue::from_bytes(input)?;
+        let mut dict = value
+            .as_dictionary()
+            .context("invalid torrent file")?
+            .iter()
+            .map(|(k, v)| (k.0, v))
+            .collect::<BTreeMap<_, _>>();
+        let info_bytes = BencodeValue::dictionary_entry_bytes(input, b"info")?
+            .context("missing or invalid info field")?;
+        dict.remove(&b"info"[..]);
+
+        // Owned values that the edited dictionary borrows from
+        let tiers;
+        let mut replacements: BTreeMap<&[u8], Option<BencodeValue>> = BTreeMap::new();
+
+        if self.edits_trackers() {
+            tiers = self.edit_trackers(&dict)?;
+            let tier_values = tiers
+                .iter()
+                .map(|tier| {
+                    BencodeValue::List(
+                        tier.iter()
+                            .map(|url| BencodeValue::ByteString(BencodeByteString(url.as_bytes())))
+                            .collect(),
+                    )
+                })
+                .collect::<Vec<_>>();
+            let announce = tiers.first().and_then(|tier| tier.first());
+            replacements.insert(
+                b"announce",
+                announce.map(|url| BencodeValue::ByteString(BencodeByteString(url.as_bytes()))),
+            );
+            let tracker_count = tiers.iter().map(Vec::len).sum::<usize>();
+            replacements.insert(
+                b"announce-list",
+                (tracker_count > 1).then_some(BencodeValue::List(tier_values)),
+            );
+        }
+        if let Some(comment) = &self.comment {
+            replacements.insert(
+                b"comment",
+                (!comment.is_empty()).then_some(BencodeValue::ByteString(BencodeByteString(
+                    comment.as_bytes(),
+                ))),
+            );
+        }
+        if self.clear_web_seeds || !self.web_seeds.is_empty() {
+            let web_seed_values = self
+                .web_seeds
+                .iter()
+                .map(|url| BencodeValue::ByteString(BencodeByteString(url.as_bytes())))
+                .collect::<Vec<_>>();
+            replacements.insert(
+                b"url-list",
+                (!web_seed_values.is_empty()).then_some(BencodeValue::List(web_seed_values)),
+            );
+        }
+        if self.strip_creation_date {
+            replacements.insert(b"creation date", None);
+        }
+
+        let mut output_dict = dict
+            .keys()
+            .filter(|k| !replacements.contains_key(*k))
+            .map(|k| {
+                let value = BencodeValue::dictionary_entry_bytes(input, k)?
+                    .context("invalid torrent file")?;
+                Ok((*k, value.to_vec()))
+            })
+            .collect::<Result<BTreeMap<_, _>>>()?;
+        for (key, value) in replacements {
+            if let Some(value) = value {
+                output_dict.insert(key, value.to_bytes());
+            }
+        }
+        let new_info_bytes = if self.edits_info() {
+            self.edit_info(info_bytes)?
+        } else {
+            info_bytes.to_vec()
+        };
+        output_dict.insert(b"info", new_info_bytes.clone());
+
+        let mut bytes = vec![b'd'];
+        for (key, value) in output_dict {
+            bytes.extend(BencodeByteString(key).to_bytes());
+            bytes.extend(value);
+        }
+        bytes.push(b'e');
+
+        Ok(Edited {
+            bytes,
+            old_info_hash: sha1_hex(info_bytes),
+            new_info_hash: sha1_hex(&new_info_bytes),
+        })
+    }
+
+    fn edit_trackers(&self, dict: &BTreeMap<&[u8], &BencodeValue>) -> Result<Vec<Vec<String>>> {
+        let mut tiers = match &self.set_trackers {
+            Some(set_trackers) => set_trackers
+                .split(';')
+                .map(|tier| {
+                    tier.split(',')
+                        .map(str::trim)
+                        .filter(|url| !url.is_empty())
+                        .map(str::to_owned)
+                        .collect::<Vec<_>>()
+                })
+                .collect(),
+            None => current_tiers(dict),
+        };
+
+        let mut remove_tiers = self.remove_tiers.clone();
+        remove_tiers.sort_unstable();
+        remove_tiers.dedup();
+        for index in remove_tiers.into_iter().rev() {
+            if index >= tiers.len() {
+                anyhow::bail!("no tier {}, there are {}", index, tiers.len());
+            }
+            tiers.remove(index);
+        }
+
+        for tier in tiers.iter_mut() {
+            tier.retain(|url| !self.remove_trackers.contains(url));
+        }
+        for url in self.add_trackers.iter() {
+            tiers.push(vec![url.clone()]);
+        }
+        tiers.retain(|tier| !tier.is_empty());
+
+        for url in tiers.iter().flatten() {
+            reqwest::Url::parse(url).with_context(|| format!("invalid tracker URL {:?}", url))?;
+        }
+        Ok(tiers)
+    }
+
+    fn edit_info(&self, info_bytes: &[u8]) -> Result<Vec<u8>> {
+        let (_, info) = BencodeValue::from_bytes(info_bytes)?;
+        let mut info = info
+            .as_dictionary()
+            .context("missing or invalid info field")?
+            .iter()
+            .map(|(k, v)| (k.0, v.to_bytes()))
+            .collect::<BTreeMap<_, _>>();
+
+        if let Some(source) = &self.source {
+            if source.is_empty() {
+                info.remove(&b"source"[..]);
+            } else {
+                info.insert(b"source", BencodeByteString(source.as_bytes()).to_bytes());
+            }
+        }
+        match self.private {
+            Some(true) => {
+                info.insert(b"private", BencodeValue::Integer(1).to_bytes());
+            }
+            Some(false) => {
+                info.remove(&b"private"[..]);
+            }
+            None => {}
+        }
+
+        let mut bytes = vec![b'd'];
+        for (key, value) in info {
+            bytes.extend(BencodeByteString(key).to_bytes());
+            bytes.extend(value);
+        }
+        bytes.push(b'e');
+        Ok(bytes)
+    }
+}
+
+/// The tracker tiers in a torrent: `announce-list` if present, otherwise just `announce`.
+fn current_tiers(dict: &BTreeMap<&[u8], &BencodeValue>) -> Vec<Vec<String>> {
+    let announce_list = dict
+        .get(&b"announce-list"[..])
+        .and_then(|v| v.as_list())
+        .map(|tiers| {
+            tiers
+                .iter()
+                .filter_map(BencodeValue::as_list)
+                .map(|tier| {
+                    tier.iter()
+                        .filter_map(BencodeValue::as_byte_string)
+                        .map(|bs| String::from_utf8_lossy(bs.0).into_owned())
+                        .collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    if announce_list.iter().any(|tier| !tier.is_empty()) {
+        return announce_list;
+    }
+
+    dict.get(&b"announce"[..])
+        .and_then(|v| v.as_byte_string())
+        .map(|bs| vec![vec![String::from_utf8_lossy(bs.0).into_owned()]])
+        .unwrap_or_default()
+}
+
+fn sha1_hex(bytes: &[u8]) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(bytes);
+    hex::encode(hasher.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Edit;
+    use crate::torrent::Torrent;
+
+    #[test]
+    fn outer_edits_keep_info_hash() {
+        let input = std::fs::read("sample.torrent").unwrap();
+        let edit = Edit {
+            add_trackers: vec!["udp://tracker.example:6969/announce".to_owned()],
+            comment: Some("hello".to_owned()),
+            web_seeds: vec!["http://mirror.example/sample.txt".to_owned()],
+            ..Default::default()
+        };
+        let edited = edit.apply(&input).unwrap();
+        assert!(!edited.info_hash_changed());
+
+        let torrent = Torrent::from_bytes(&edited.bytes).unwrap();
+        assert_eq!(
+            torrent.info_hash(),
+            "d69f91e6b2ae4c542468d1073a71d4ea13879a7f"
+        );
+        assert_eq!(
+            torrent.announce_list,
+            vec![
+                vec!["http://bittorrent-test-tracker.codecrafters.io/announce".to_owned()],
+                vec!["udp://tracker.example:6969/announce".to_owned()],
+            ]
+        );
+    }
+
+    #[test]
+    fn untouched_keys_kept_as_they_are() {
+        // A non-canonical dictionary outside the info dictionary
+        let input = b"d4:infod1:xi1ee5:otherd1:bi1e1:ai2eee";
+        let edit = Edit {
+            comment: Some("hi".to_owned()),
+            ..Default::default()
+        };
+        let edited = edit.apply(input).unwrap();
+        assert_eq!(
+            edited.bytes,
+            b"d7:comment2:hi4:infod1:xi1ee5:otherd1:bi1e1:ai2eee"
+        );
+    }
+
+    #[test]
+    fn tracker_edits() {
+        let input = b"d8:announce8:http://a13:announce-listll8:http://a8:http://bel8:http://cee4:infod1:xi1eee";
+
+        {
+            // Removing trackers and tiers
+            let edit = Edit {
+                remove_tiers: vec![1],
+                remove_trackers: vec!["http://a".to_owned()],
+                ..Default::default()
+            };
+            let edited = edit.apply(input).unwrap();
+            assert_eq!(edited.bytes, b"d8:announce8:http://b4:infod1:xi1eee");
+        }
+
+        {
+            // Reordering tiers
+            let edit = Edit {
+                set_trackers: Some("http://c; http://b,http://a".to_owned()),
+                ..Default::default()
+            };
+            let edited = edit.apply(input).unwrap();
+            assert_eq!(
+                edited.bytes,
+                &b"d8:announce8:http://c13:announce-listll8:http://cel8:http://b8:http://aee4:infod1:xi1eee"[..]
+            );
+        }
+
+        {
+            // Removing a tier that doesn't exist
+            let edit = Edit {
+                remove_tiers: vec![2],
+                ..Default::default()
+            };
+            assert!(edit.apply(input).is_err());
+        }
+    }
+
+    #[test]
+    fn info_edits_change_info_hash() {
+        // Non-canonical info dictionary, which must survive untouched edits
+        let input = b"d8:announce8:http://a13:creation datei1e4:infod1:yi1e1:xi1eee";
+
+        let edit = Edit {
+            strip_creation_date: true,
+            ..Default::default()
+        };
+        let edited = edit.apply(input).unwrap();
+        assert!(!edited.info_hash_changed());
+        assert_eq!(edited.bytes, b"d8:announce8:http://a4:infod1:yi1e1:xi1eee");
+
+        let edit = Edit {
+            private: Some(true),
+            source: Some("TEST".to_owned()),
+            ..Default::default()
+        };
+        let edited = edit.apply(input).unwrap();
+        assert!(edited.info_hash_changed());
+        assert_eq!(
+            edited.bytes,
+            &b"d8:announce8:http://a13:creation datei1e4:infod7:privatei1e6:source4:TEST1:xi1e1:yi1eee"[..]
+        );
+    }
+}
@@ -0,0 +1,149 @@
+/// Percent-encode arbitrary bytes for use in a URL, leaving only RFC 3986 unreserved
+/// characters as they are.
+pub fn percent_encode(input: &[u8]) -> String {
+    let mut output = String::with_capacity(input.len() * 3);
+    for byte in input {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                output.push(*byte as char)
+            }
+            _ => output.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    output
+}
+
+/// Undo percent-encoding, also turning `+` into a space as query strings do. Returns `None`
+/// for malformed escapes.
+pub fn percent_decode(input: &str) -> Option<Vec<u8>> {
+    let mut output = Vec::with_capacity(input.len());
+    let mut bytes = input.bytes();
+    while let Some(byte) = bytes.next() {
+        match byte {
+            b'%' => {
+                let digit = |byte: Option<u8>| (byte? as char).to_digit(16);
+                let high = digit(bytes.next())?;
+                let low = digit(bytes.next())?;
+                output.push((high * 16 + low) as u8);
+            }
+            b'+' => output.push(b' '),
+            _ => output.push(byte),
+        }
+    }
+    Some(output)
+}
+
+/// A minimal HTTP/1.1 server on loopback for tests, one request per connection.
+#[cfg(test)]
+pub mod testing {
+    use std::{net::SocketAddr, sync::Arc};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    pub struct Request {
+        /// Path and query, e.g. `/announce?info_hash=...`.
+        pub target: String,
+        /// Headers with lowercase names.
+        pub headers: Vec<(String, String)>,
+    }
+
+    impl Request {
+        pub fn header(&self, name: &str) -> Option<&str> {
+            self.headers
+                .iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, v)| v.as_str())
+        }
+    }
+
+    pub struct Response {
+        pub status: u16,
+        pub headers: Vec<(String, String)>,
+        pub body: Vec<u8>,
+    }
+
+    impl Response {
+        pub fn new(status: u16, body: impl Into<Vec<u8>>) -> Self {
+            Response {
+                status,
+                headers: Vec::new(),
+                body: body.into(),
+            }
+        }
+    }
+
+    /// Start serving on an ephemeral port, answering every request with `handler`.
+    pub async fn serve<F>(handler: F) -> SocketAddr
+    where
+        F: Fn(&Request) -> Response + Send + Sync + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handler = Arc::new(handler);
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let handler = handler.clone();
+                tokio::spawn(async move {
+                    let mut request = Vec::new();
+                    let mut buf = [0; 1024];
+                    while !request.ends_with(b"\r\n\r\n") {
+                        let n = stream.read(&mut buf).await.unwrap();
+                        if n == 0 {
+                            return;
+                        }
+                        request.extend(&buf[..n]);
+                    }
+                    let request = String::from_utf8_lossy(&request);
+                    let mut lines = request.lines();
+                    let target = lines
+                        .next()
+                        .and_then(|line| line.split(' ').nth(1))
+                        .unwrap_or_default()
+                        .to_owned();
+                    let headers = lines
+                        .filter_map(|line| line.split_once(':'))
+                        .map(|(n, v)| (n.trim().to_ascii_lowercase(), v.trim().to_owned()))
+                        .collect();
+
+                    let response = handler(&Request { target, headers });
+                    let mut output = format!(
+                        "HTTP/1.1 {} Status\r\nContent-Length: {}\r\nConnection: close\r\n",
+                        response.status,
+                        response.body.len()
+                    );
+                    for (name, value) in response.headers {
+                        output.push_str(&format!("{}: {}\r\n", name, value));
+                    }
+                    output.push_str("\r\n");
+                    let mut output = output.into_bytes();
+                    output.extend(response.body);
+                    stream.write_all(&output).await.unwrap();
+                });
+            }
+        });
+        addr
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{percent_decode, percent_encode};
+
+    #[test]
+    fn encode() {
+        assert_eq!(percent_encode(b"sample.txt"), "sample.txt");
+        assert_eq!(percent_encode(b"a b/c"), "a%20b%2Fc");
+        assert_eq!(percent_encode(b"\x12\x34\xAB"), "%124%AB");
+    }
+
+    #[test]
+    fn decode() {
+        assert_eq!(percent_decode("a%20b%2Fc+d").unwrap(), b"a b/c d");
+        assert_eq!(percent_decode("%124%ab").unwrap(), b"\x12\x34\xAB");
+        assert!(percent_decode("%1").is_none());
+        assert!(percent_decode("%zz").is_none());
+    }
+}
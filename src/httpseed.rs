@@ -0,0 +1,162 @@
+use anyhow::{Context, Result};
+use reqwest::StatusCode;
+use std::{ops::RangeInclusive, time::Duration};
+
+use crate::{http::percent_encode, torrent::Torrent};
+
+/// How many times to retry a piece when the seed asks us to come back later.
+const MAX_RETRIES: usize = 3;
+/// Longest retry-after delay we are willing to wait for. Longer delays fail the request so
+/// that other sources get a chance.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+/// A BEP 17 HTTP seed: a script that returns the data of a piece, given the info hash and
+/// the piece index.
+pub struct HttpSeed {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl HttpSeed {
+    pub fn new(url: &str) -> Result<Self> {
+        let parsed = reqwest::Url::parse(url).context("invalid HTTP seed URL")?;
+        if !matches!(parsed.scheme(), "http" | "https") {
+            anyhow::bail!("unsupported HTTP seed scheme {:?}", parsed.scheme());
+        }
+        Ok(HttpSeed {
+            client: reqwest::Client::new(),
+            url: url.to_owned(),
+        })
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// The URL for the given byte ranges of a piece, which the seed sends back to back.
+    fn piece_url(
+        &self,
+        torrent: &Torrent,
+        piece_index: usize,
+        ranges: &[RangeInclusive<usize>],
+    ) -> String {
+        let separator = if self.url.contains('?') { '&' } else { '?' };
+        let ranges = ranges
+            .iter()
+            .map(|range| format!("{}-{}", range.start(), range.end()))
+            .collect::<Vec<_>>()
+            .join(",");
+        format!(
+            "{}{}info_hash={}&piece={}&ranges={}",
+            self.url,
+            separator,
+            percent_encode(&torrent.info_hash_bytes()),
+            piece_index,
+            ranges
+        )
+    }
+
+    /// Download a piece from the seed, as one range covering all of it. The data is not
+    /// checked against the piece hash.
+    ///
+    /// A busy seed answers with 503 and the number of seconds to wait in the body, in which
+    /// case we wait and try again.
+    pub async fn fetch_piece(&self, torrent: &Torrent, piece_index: usize) -> Result<Vec<u8>> {
+        let expected = torrent.info.piece_len(piece_index);
+        anyhow::ensure!(expected > 0, "piece {} is empty", piece_index);
+        let url = self.piece_url(torrent, piece_index, &[0..=expected - 1]);
+        for _ in 0..=MAX_RETRIES {
+            let response = self.client.get(&url).send().await?;
+            let status = response.status();
+            let body = response.bytes().await?;
+            match status {
+                StatusCode::OK => {
+                    if body.len() != expected {
+                        anyhow::bail!(
+                            "HTTP seed sent {} bytes for piece {} instead of {}",
+                            body.len(),
+                            piece_index,
+                            expected
+                        );
+                    }
+                    return Ok(body.to_vec());
+                }
+                StatusCode::SERVICE_UNAVAILABLE => {
+                    let retry_after = std::str::from_utf8(&body)
+                        .ok()
+                        .and_then(|s| s.trim().parse::<u64>().ok())
+                        .map(Duration::from_secs)
+                        .context("HTTP seed is unavailable")?;
+                    if retry_after > MAX_RETRY_AFTER {
+                        anyhow::bail!(
+                            "HTTP seed asked us to retry after {} seconds",
+                            retry_after.as_secs()
+                        );
+                    }
+                    tokio::time::sleep(retry_after).await;
+                }
+                _ => anyhow::bail!("HTTP seed request failed with {}", status),
+            }
+        }
+        anyhow::bail!("HTTP seed still busy after {} retries", MAX_RETRIES)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HttpSeed;
+    use crate::{
+        http::testing::{self, Response},
+        torrent::Torrent,
+    };
+    use sha1::{Digest, Sha1};
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    #[tokio::test]
+    async fn fetch_piece() {
+        let piece = b"abcd";
+        let mut input =
+            b"d8:announce14:http://a.b/ann4:infod6:lengthi4e4:name1:a12:piece lengthi4e6:pieces20:"
+                .to_vec();
+        input.extend(Sha1::digest(piece));
+        input.extend(b"ee");
+        let torrent = Torrent::from_bytes(&input).unwrap();
+        let expected_query = format!(
+            "/seed.php?key=1&info_hash={}&piece=0&ranges=0-3",
+            crate::http::percent_encode(&torrent.info_hash_bytes())
+        );
+
+        // Busy on the first request, then serve the piece
+        let requests = Arc::new(AtomicUsize::new(0));
+        let addr = {
+            let requests = requests.clone();
+            testing::serve(move |request| {
+                assert_eq!(request.target, expected_query);
+                match requests.fetch_add(1, Ordering::SeqCst) {
+                    0 => Response::new(503, "0"),
+                    _ => Response::new(200, &piece[..]),
+                }
+            })
+            .await
+        };
+
+        let seed = HttpSeed::new(&format!("http://{}/seed.php?key=1", addr)).unwrap();
+        let data = seed.fetch_piece(&torrent, 0).await.unwrap();
+        assert_eq!(data, piece);
+        assert!(torrent.info.verify_piece(0, &data));
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+        assert!(seed
+            .piece_url(&torrent, 0, &[0..=1, 3..=3])
+            .ends_with("&piece=0&ranges=0-1,3-3"));
+
+        {
+            // A long retry-after fails instead of waiting
+            let addr = testing::serve(|_| Response::new(503, "3600")).await;
+            let seed = HttpSeed::new(&format!("http://{}/seed.php", addr)).unwrap();
+            assert!(seed.fetch_piece(&torrent, 0).await.is_err());
+        }
+    }
+}
@@ -0,0 +1,85 @@
+use rand::{distributions::Alphanumeric, Rng};
+use std::net::IpAddr;
+
+/// Azureus-style client prefix (BEP 20): client code `XX`, version 0.1.0.0.
+const PEER_ID_PREFIX: &[u8; 8] = b"-XX0100-";
+pub const DEFAULT_PORT: u16 = 6881;
+pub const DEFAULT_USER_AGENT: &str = concat!("bittorrent-starter-rust/", env!("CARGO_PKG_VERSION"));
+
+/// How we present ourselves to trackers and peers. A new peer ID and key are made for every
+/// session and stay the same for all of its announces and connections.
+#[derive(Debug, Clone)]
+pub struct Identity {
+    pub peer_id: [u8; 20],
+    /// Lets trackers recognise us across IP address changes. Never shown to peers.
+    pub key: u32,
+    /// The port we tell trackers we listen on.
+    pub port: u16,
+    /// How many peers to ask trackers for, or `None` for the tracker's default.
+    pub numwant: Option<u32>,
+    /// The address to announce, when trackers can't tell it from the connection.
+    pub ip: Option<IpAddr>,
+    pub user_agent: String,
+}
+
+impl Default for Identity {
+    fn default() -> Self {
+        let mut rng = rand::thread_rng();
+        let mut peer_id = [0; 20];
+        peer_id[..8].copy_from_slice(PEER_ID_PREFIX);
+        for byte in peer_id[8..].iter_mut() {
+            *byte = rng.sample(Alphanumeric);
+        }
+        Identity {
+            peer_id,
+            key: rng.gen(),
+            port: DEFAULT_PORT,
+            numwant: None,
+            ip: None,
+            user_agent: DEFAULT_USER_AGENT.to_owned(),
+        }
+    }
+}
+
+/// Command line options for the parts of our identity that can be configured.
+#[derive(Debug, clap::Args)]
+pub struct IdentityArgs {
+    /// The port to announce to trackers
+    #[arg(long, global = true, default_value_t = DEFAULT_PORT)]
+    port: u16,
+    /// How many peers to ask trackers for
+    #[arg(long, global = true)]
+    numwant: Option<u32>,
+    /// The IP address to announce to trackers
+    #[arg(long, global = true)]
+    ip: Option<IpAddr>,
+    /// The User-Agent header to send to HTTP trackers
+    #[arg(long, global = true, default_value = DEFAULT_USER_AGENT)]
+    user_agent: String,
+}
+
+impl From<IdentityArgs> for Identity {
+    fn from(args: IdentityArgs) -> Self {
+        Identity {
+            port: args.port,
+            numwant: args.numwant,
+            ip: args.ip,
+            user_agent: args.user_agent,
+            ..Identity::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Identity;
+
+    #[test]
+    fn peer_ids() {
+        let a = Identity::default();
+        let b = Identity::default();
+        assert!(a.peer_id.starts_with(b"-XX0100-"));
+        assert!(a.peer_id[8..].iter().all(u8::is_ascii_alphanumeric));
+        assert_ne!(a.peer_id, b.peer_id);
+    }
+}
@@ -0,0 +1,362 @@
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+
+use crate::{bencode::BencodeValue, peer::div_round_up, sanitize, torrent::Torrent};
+
+/// Piece lengths above this make partial downloads and verification needlessly coarse.
+const MAX_RECOMMENDED_PIECE_LENGTH: usize = 16 * 1024 * 1024;
+/// Piece lengths below a single block mean an oversized `pieces` field for no benefit.
+const MIN_RECOMMENDED_PIECE_LENGTH: usize = 16 * 1024;
+/// Tracker URL schemes that clients, including this one, know how to announce to.
+const TRACKER_SCHEMES: &[&str] = &["http", "https", "udp"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+/// A single problem found in a torrent.
+#[derive(Debug, Serialize)]
+pub struct Finding {
+    pub severity: Severity,
+    /// Short identifier of the check that produced this finding, e.g. `piece-count`.
+    pub check: &'static str,
+    pub message: String,
+}
+
+impl std::fmt::Display for Finding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let severity = match self.severity {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        write!(f, "{}[{}]: {}", severity, self.check, self.message)
+    }
+}
+
+/// Run every check over `torrent`, which was parsed from `input`.
+pub fn lint(input: &[u8], torrent: &Torrent) -> Vec<Finding> {
+    let mut findings = Vec::new();
+    check_canonical(input, &mut findings);
+    check_piece_count(torrent, &mut findings);
+    check_piece_length(torrent, &mut findings);
+    check_tracker_schemes(torrent, &mut findings);
+    check_files(torrent, &mut findings);
+    check_padding(torrent, &mut findings);
+    check_paths(torrent, &mut findings);
+    findings
+}
+
+fn push(findings: &mut Vec<Finding>, severity: Severity, check: &'static str, message: String) {
+    findings.push(Finding {
+        severity,
+        check,
+        message,
+    });
+}
+
+/// The file should be exactly what re-encoding its contents gives: sorted, unique
+/// dictionary keys, integers without leading zeros, and nothing after the end.
+fn check_canonical(input: &[u8], findings: &mut Vec<Finding>) {
+    match BencodeValue::from_bytes(input) {
+        Ok((rest, value)) => {
+            if !rest.is_empty() {
+                push(
+                    findings,
+                    Severity::Error,
+                    "canonical-bencode",
+                    format!("{} bytes of trailing data after the torrent", rest.len()),
+                );
+            }
+            if value.to_bytes() != input[..input.len() - rest.len()] {
+                push(
+                    findings,
+                    Severity::Error,
+                    "canonical-bencode",
+                    "bencoding is not canonical (unsorted or duplicate keys, or non-minimal \
+                     integers)"
+                        .to_owned(),
+                );
+            }
+        }
+        Err(e) => push(
+            findings,
+            Severity::Error,
+            "canonical-bencode",
+            format!("invalid bencode: {}", e),
+        ),
+    }
+}
+
+fn check_piece_count(torrent: &Torrent, findings: &mut Vec<Finding>) {
+    let info = &torrent.info;
+    let expected = div_round_up(info.length, info.piece_length);
+    if info.piece_count() != expected {
+        push(
+            findings,
+            Severity::Error,
+            "piece-count",
+            format!(
+                "{} piece hashes but a total length of {} with {} byte pieces needs {}",
+                info.piece_count(),
+                info.length,
+                info.piece_length,
+                expected
+            ),
+        );
+    }
+}
+
+fn check_piece_length(torrent: &Torrent, findings: &mut Vec<Finding>) {
+    let piece_length = torrent.info.piece_length;
+    if !piece_length.is_power_of_two() {
+        push(
+            findings,
+            Severity::Warning,
+            "piece-length",
+            format!("piece length {} is not a power of two", piece_length),
+        );
+    }
+    if piece_length > MAX_RECOMMENDED_PIECE_LENGTH {
+        push(
+            findings,
+            Severity::Warning,
+            "piece-length",
+            format!(
+                "piece length {} is larger than {}",
+                piece_length, MAX_RECOMMENDED_PIECE_LENGTH
+            ),
+        );
+    } else if piece_length < MIN_RECOMMENDED_PIECE_LENGTH {
+        push(
+            findings,
+            Severity::Warning,
+            "piece-length",
+            format!(
+                "piece length {} is smaller than {}",
+                piece_length, MIN_RECOMMENDED_PIECE_LENGTH
+            ),
+        );
+    }
+}
+
+fn check_tracker_schemes(torrent: &Torrent, findings: &mut Vec<Finding>) {
+    let mut seen = HashSet::new();
+    let trackers = torrent
+        .announce
+        .iter()
+        .map(reqwest::Url::as_str)
+        .chain(torrent.announce_list.iter().flatten().map(String::as_str));
+    for tracker in trackers {
+        if !seen.insert(tracker) {
+            continue;
+        }
+        match reqwest::Url::parse(tracker) {
+            Ok(url) if TRACKER_SCHEMES.contains(&url.scheme()) => {}
+            Ok(url) => push(
+                findings,
+                Severity::Error,
+                "tracker-scheme",
+                format!(
+                    "tracker {} uses the unsupported scheme {:?}",
+                    tracker,
+                    url.scheme()
+                ),
+            ),
+            Err(e) => push(
+                findings,
+                Severity::Error,
+                "tracker-scheme",
+                format!("tracker {:?} is not a valid URL: {}", tracker, e),
+            ),
+        }
+    }
+}
+
+fn check_files(torrent: &Torrent, findings: &mut Vec<Finding>) {
+    let Some(files) = &torrent.info.files else {
+        if torrent.info.length == 0 {
+            push(
+                findings,
+                Severity::Warning,
+                "zero-length-file",
+                "the torrent's only file is empty".to_owned(),
+            );
+        }
+        return;
+    };
+
+    let mut paths: HashMap<Vec<&[u8]>, usize> = HashMap::new();
+    let mut folded_paths: HashMap<Vec<String>, usize> = HashMap::new();
+    for (i, file) in files.iter().enumerate() {
+        let display_path = file
+            .path
+            .iter()
+            .map(|c| c.as_str())
+            .collect::<Vec<_>>()
+            .join("/");
+
+        let path = file.path.iter().map(|c| c.bytes.as_slice()).collect();
+        let folded_path = file
+            .path
+            .iter()
+            .map(|c| c.as_str().to_lowercase())
+            .collect();
+        if let Some(first) = paths.insert(path, i) {
+            push(
+                findings,
+                Severity::Error,
+                "duplicate-file",
+                format!("file {} ({}) duplicates file {}", i, display_path, first),
+            );
+        } else if let Some(first) = folded_paths.insert(folded_path, i) {
+            push(
+                findings,
+                Severity::Warning,
+                "duplicate-file",
+                format!(
+                    "file {} ({}) only differs in case from file {}",
+                    i, display_path, first
+                ),
+            );
+        }
+
+        if file.length == 0 && !file.is_padding() {
+            push(
+                findings,
+                Severity::Warning,
+                "zero-length-file",
+                format!("file {} ({}) is empty", i, display_path),
+            );
+        }
+    }
+}
+
+/// Hybrid torrents need every file but the last to end on a piece boundary, so that the
+/// v1 and v2 views of the data agree. BEP 47 padding files are how that is done.
+fn check_padding(torrent: &Torrent, findings: &mut Vec<Finding>) {
+    let info = &torrent.info;
+    let Some(files) = &info.files else {
+        return;
+    };
+    if !info.is_hybrid() {
+        return;
+    }
+
+    let mut offset = 0;
+    for (i, file) in files.iter().enumerate() {
+        offset += file.length;
+        let is_last = i == files.len() - 1;
+        if is_last || file.is_padding() || offset.is_multiple_of(info.piece_length) {
+            continue;
+        }
+        let message = match files.get(i + 1).filter(|next| next.is_padding()) {
+            Some(padding) if (offset + padding.length).is_multiple_of(info.piece_length) => {
+                continue;
+            }
+            Some(padding) => format!(
+                "file {} is followed by a padding file ending at offset {}, which is not \
+                 piece aligned",
+                i,
+                offset + padding.length
+            ),
+            None => format!(
+                "file {} ends at offset {}, which is not piece aligned, and is not \
+                 followed by a padding file",
+                i, offset
+            ),
+        };
+        push(findings, Severity::Error, "hybrid-padding", message);
+    }
+}
+
+fn check_paths(torrent: &Torrent, findings: &mut Vec<Finding>) {
+    if let Err(e) = sanitize::validate_component(torrent.info.name.as_str()) {
+        push(
+            findings,
+            Severity::Error,
+            "unsafe-path",
+            format!("name: {}", e),
+        );
+    }
+    for (i, file) in torrent.info.files.iter().flatten().enumerate() {
+        if let Err(e) = sanitize::validate_path(&file.path) {
+            push(
+                findings,
+                Severity::Error,
+                "unsafe-path",
+                format!("file {}: {}", i, e),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{lint, Severity};
+    use crate::torrent::Torrent;
+
+    fn checks(input: &[u8]) -> Vec<(Severity, &'static str)> {
+        let torrent = Torrent::from_bytes(input).unwrap();
+        lint(input, &torrent)
+            .into_iter()
+            .map(|f| (f.severity, f.check))
+            .collect()
+    }
+
+    #[test]
+    fn sample_is_clean() {
+        let input = std::fs::read("sample.torrent").unwrap();
+        assert_eq!(checks(&input), vec![]);
+    }
+
+    #[test]
+    fn problems() {
+        {
+            // Unsorted keys, odd piece length, wrong piece count and an unknown scheme
+            let input = b"d8:announce13:wss://a.b/ann4:infod4:name1:a6:lengthi40000e12:piece lengthi20000e6:pieces20:aaaaaaaaaaaaaaaaaaaaee";
+            assert_eq!(
+                checks(input),
+                vec![
+                    (Severity::Error, "canonical-bencode"),
+                    (Severity::Error, "piece-count"),
+                    (Severity::Warning, "piece-length"),
+                    (Severity::Error, "tracker-scheme"),
+                ]
+            );
+        }
+
+        {
+            // Duplicate, empty and unsafe files
+            let input = b"d8:announce14:http://a.b/ann4:infod5:filesld6:lengthi0e4:pathl1:aeed6:lengthi16384e4:pathl1:aeed6:lengthi16384e4:pathl2:..eee4:name1:x12:piece lengthi16384e6:pieces40:aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaee";
+            assert_eq!(
+                checks(input),
+                vec![
+                    (Severity::Warning, "zero-length-file"),
+                    (Severity::Error, "duplicate-file"),
+                    (Severity::Error, "unsafe-path"),
+                ]
+            );
+        }
+
+        {
+            // Hybrid without padding between files
+            let input = b"d8:announce14:http://a.b/ann4:infod5:filesld6:lengthi1e4:pathl1:aeed6:lengthi1e4:pathl1:beee12:meta versioni2e4:name1:x12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaaee";
+            assert_eq!(checks(input), vec![(Severity::Error, "hybrid-padding")]);
+        }
+
+        {
+            // Hybrid with padding that is one byte short
+            let input = b"d8:announce14:http://a.b/ann4:infod5:filesld6:lengthi1e4:pathl1:aeed4:attr1:p6:lengthi16382e4:pathl4:.pad5:16382eed6:lengthi1e4:pathl1:beee12:meta versioni2e4:name1:x12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaaee";
+            assert_eq!(checks(input), vec![(Severity::Error, "hybrid-padding")]);
+        }
+    }
+
+    #[test]
+    fn padded_hybrid_is_clean() {
+        let input = b"d8:announce14:http://a.b/ann4:infod5:filesld6:lengthi1e4:pathl1:aeed4:attr1:p6:lengthi16383e4:pathl4:.pad5:16383eed6:lengthi1e4:pathl1:beee12:meta versioni2e4:name1:x12:piece lengthi16384e6:pieces40:aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaee";
+        assert_eq!(checks(input), vec![]);
+    }
+}
@@ -0,0 +1,368 @@
+//! Local Service Discovery (BEP 14): finding peers on the same network by multicasting
+//! `BT-SEARCH` announces, without any tracker.
+use anyhow::{Context, Result};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+    sync::atomic::{AtomicU32, Ordering},
+    time::Duration,
+};
+use tokio::{
+    net::UdpSocket,
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+    time::Instant,
+};
+
+use crate::{torrent::Torrent, tracker::Peer};
+
+pub const LSD_IPV4: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(239, 192, 152, 143), 6771);
+pub const LSD_IPV6: SocketAddrV6 = SocketAddrV6::new(
+    Ipv6Addr::new(0xff15, 0, 0, 0, 0, 0, 0xefc0, 0x988f),
+    6771,
+    0,
+    0,
+);
+/// How often to announce a torrent.
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+/// The least time between two announces of the same torrent.
+const MIN_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(60);
+const MAX_MESSAGE_LEN: usize = 1400;
+/// How long to wait after a failed receive. Each further failure in a row doubles it.
+const RECEIVE_RETRY_BASE: Duration = Duration::from_millis(100);
+const MAX_RECEIVE_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// Command line options for local service discovery.
+#[derive(Debug, clap::Args)]
+pub struct LsdArgs {
+    /// Don't look for peers on the local network
+    #[arg(long, global = true)]
+    no_lsd: bool,
+}
+
+impl LsdArgs {
+    /// Whether local peers may be looked for for `torrent`. Private torrents only get peers
+    /// from their trackers.
+    pub fn enabled_for(&self, torrent: &Torrent) -> bool {
+        !self.no_lsd && !torrent.info.private
+    }
+}
+
+/// A `BT-SEARCH` message: a peer listening on `port` has these torrents.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Announce {
+    pub port: u16,
+    pub info_hashes: Vec<[u8; 20]>,
+    /// Set by the sender to recognise its own announces when they come back to it.
+    pub cookie: Option<String>,
+}
+
+impl Announce {
+    /// The message to send to the multicast group `host`.
+    pub fn encode(&self, host: SocketAddr) -> Vec<u8> {
+        let mut message = format!("BT-SEARCH * HTTP/1.1\r\nHost: {}\r\n", host);
+        message.push_str(&format!("Port: {}\r\n", self.port));
+        for info_hash in &self.info_hashes {
+            message.push_str(&format!("Infohash: {}\r\n", hex::encode(info_hash)));
+        }
+        if let Some(cookie) = &self.cookie {
+            message.push_str(&format!("cookie: {}\r\n", cookie));
+        }
+        message.push_str("\r\n\r\n");
+        message.into_bytes()
+    }
+
+    pub fn decode(input: &[u8]) -> Result<Self> {
+        let input = std::str::from_utf8(input).context("LSD message is not text")?;
+        let mut lines = input.split("\r\n");
+        anyhow::ensure!(
+            lines.next() == Some("BT-SEARCH * HTTP/1.1"),
+            "not a BT-SEARCH message"
+        );
+        let mut port = None;
+        let mut info_hashes = Vec::new();
+        let mut cookie = None;
+        for line in lines.take_while(|line| !line.is_empty()) {
+            let (name, value) = line.split_once(':').context("invalid LSD header")?;
+            let value = value.trim();
+            match name.to_ascii_lowercase().as_str() {
+                "port" => port = Some(value.parse().context("invalid LSD port")?),
+                "infohash" => info_hashes.push(
+                    hex::decode(value)
+                        .ok()
+                        .and_then(|info_hash| info_hash.try_into().ok())
+                        .context("invalid LSD info hash")?,
+                ),
+                "cookie" => cookie = Some(value.to_string()),
+                _ => {}
+            }
+        }
+        Ok(Announce {
+            port: port.context("LSD message has no port")?,
+            info_hashes,
+            cookie,
+        })
+    }
+}
+
+/// A multicast group we announce to and listen on.
+pub struct Group {
+    addr: SocketAddr,
+    socket: UdpSocket,
+    /// How many receives in a row have failed.
+    failures: AtomicU32,
+}
+
+impl Group {
+    /// Join the IPv4 group `addr` on the interface with address `interface`. The port is
+    /// shared with other clients on this host, and our announces reach them too.
+    pub fn ipv4(addr: SocketAddrV4, interface: Ipv4Addr) -> Result<Self> {
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_reuse_address(true)?;
+        socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, addr.port())).into())?;
+        socket.join_multicast_v4(addr.ip(), &interface)?;
+        socket.set_multicast_if_v4(&interface)?;
+        socket.set_multicast_loop_v4(true)?;
+        Self::new(addr.into(), socket)
+    }
+
+    /// Join the IPv6 group `addr` on the interface with index `interface`, 0 meaning the
+    /// default one.
+    pub fn ipv6(addr: SocketAddrV6, interface: u32) -> Result<Self> {
+        let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_only_v6(true)?;
+        socket.set_reuse_address(true)?;
+        socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, addr.port())).into())?;
+        socket.join_multicast_v6(addr.ip(), interface)?;
+        socket.set_multicast_if_v6(interface)?;
+        socket.set_multicast_loop_v6(true)?;
+        Self::new(addr.into(), socket)
+    }
+
+    fn new(addr: SocketAddr, socket: Socket) -> Result<Self> {
+        socket.set_nonblocking(true)?;
+        Ok(Group {
+            addr,
+            socket: UdpSocket::from_std(socket.into())?,
+            failures: AtomicU32::new(0),
+        })
+    }
+
+    /// The next announce from another host, and its address. Receive errors are retried with
+    /// exponential backoff, as they may persist (e.g. once the interface is gone).
+    async fn receive(&self) -> (Announce, SocketAddr) {
+        let mut buf = [0; MAX_MESSAGE_LEN];
+        loop {
+            let (len, from) = match self.socket.recv_from(&mut buf).await {
+                Ok(received) => {
+                    self.failures.store(0, Ordering::Relaxed);
+                    received
+                }
+                Err(e) => {
+                    let failures = self.failures.fetch_add(1, Ordering::Relaxed);
+                    if failures == 0 {
+                        eprintln!(
+                            "warning: failed to receive LSD announces on {}: {}",
+                            self.addr, e
+                        );
+                    }
+                    let delay = RECEIVE_RETRY_BASE * 2u32.pow(failures.min(16));
+                    tokio::time::sleep(delay.min(MAX_RECEIVE_RETRY_DELAY)).await;
+                    continue;
+                }
+            };
+            // Other multicast traffic may share the port
+            if let Ok(announce) = Announce::decode(&buf[..len]) {
+                return (announce, from);
+            }
+        }
+    }
+}
+
+/// Announces torrents to, and learns their peers from, the LSD multicast groups.
+pub struct Lsd {
+    groups: Vec<Group>,
+    cookie: String,
+}
+
+impl Lsd {
+    /// Join the IPv4 and IPv6 groups on the default interfaces. Only fails if we can join
+    /// neither, as many networks have no IPv6 multicast.
+    pub fn bind() -> Result<Self> {
+        let ipv4 = Group::ipv4(LSD_IPV4, Ipv4Addr::UNSPECIFIED);
+        let ipv6 = Group::ipv6(LSD_IPV6, 0);
+        let groups = match (ipv4, ipv6) {
+            (Err(e), Err(_)) => return Err(e.context("failed to join the LSD multicast groups")),
+            (ipv4, ipv6) => ipv4.into_iter().chain(ipv6).collect(),
+        };
+        Ok(Lsd::new(groups))
+    }
+
+    pub fn new(groups: Vec<Group>) -> Self {
+        Lsd {
+            groups,
+            cookie: hex::encode(rand::random::<[u8; 8]>()),
+        }
+    }
+
+    /// Tell the local network we have `info_hash` on `port`, through every group.
+    async fn announce(&self, info_hash: [u8; 20], port: u16) {
+        let announce = Announce {
+            port,
+            info_hashes: vec![info_hash],
+            cookie: Some(self.cookie.clone()),
+        };
+        for group in &self.groups {
+            if let Err(e) = group
+                .socket
+                .send_to(&announce.encode(group.addr), group.addr)
+                .await
+            {
+                eprintln!(
+                    "warning: failed to send LSD announce to {}: {}",
+                    group.addr, e
+                );
+            }
+        }
+    }
+
+    /// The next peer another host announces, with the info hashes it has.
+    async fn receive(&self) -> (Announce, SocketAddr) {
+        loop {
+            let receives = self.groups.iter().map(|group| Box::pin(group.receive()));
+            let ((announce, mut from), _, _) = futures::future::select_all(receives).await;
+            if announce.cookie.as_ref() == Some(&self.cookie) {
+                continue;
+            }
+            from.set_port(announce.port);
+            return (announce, from);
+        }
+    }
+
+    /// Announce `info_hash` in the background, sending the local peers that have it to
+    /// `peers`. Besides every few minutes, we announce in reply to peers we learn about, so
+    /// they learn about us without waiting, but no more than once a minute.
+    pub fn spawn(
+        self,
+        info_hash: [u8; 20],
+        port: u16,
+        peers: mpsc::UnboundedSender<Peer>,
+    ) -> Reannouncer {
+        let (stop, mut stopped) = oneshot::channel();
+        let task = tokio::spawn(async move {
+            let mut next_announce = Instant::now();
+            let mut last_announce = None::<Instant>;
+            loop {
+                tokio::select! {
+                    _ = &mut stopped => break,
+                    _ = tokio::time::sleep_until(next_announce) => {
+                        self.announce(info_hash, port).await;
+                        last_announce = Some(Instant::now());
+                        next_announce = Instant::now() + ANNOUNCE_INTERVAL;
+                    }
+                    (announce, from) = self.receive() => {
+                        if !announce.info_hashes.contains(&info_hash) {
+                            continue;
+                        }
+                        // The download may already be over
+                        let _ = peers.send(from.into());
+                        if let Some(last_announce) = last_announce {
+                            next_announce =
+                                next_announce.min(last_announce + MIN_ANNOUNCE_INTERVAL);
+                        }
+                    }
+                }
+            }
+        });
+        Reannouncer { stop, task }
+    }
+}
+
+/// Announces a torrent to the local network in the background until stopped.
+pub struct Reannouncer {
+    stop: oneshot::Sender<()>,
+    task: JoinHandle<()>,
+}
+
+impl Reannouncer {
+    pub async fn stop(self) -> Result<()> {
+        let _ = self.stop.send(());
+        Ok(self.task.await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Announce, Group, Lsd, LSD_IPV4, LSD_IPV6};
+    use std::{
+        net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+        time::Duration,
+    };
+    use tokio::sync::mpsc;
+
+    #[test]
+    fn messages() {
+        let announce = Announce {
+            port: 6881,
+            info_hashes: vec![[0xAB; 20], [0xCD; 20]],
+            cookie: Some("c00k1e".to_string()),
+        };
+        let encoded = announce.encode(LSD_IPV6.into());
+        assert!(encoded.starts_with(
+            b"BT-SEARCH * HTTP/1.1\r\nHost: [ff15::efc0:988f]:6771\r\nPort: 6881\r\nInfohash: abab"
+        ));
+        assert!(encoded.ends_with(b"\r\n\r\n\r\n"));
+        assert_eq!(Announce::decode(&encoded).unwrap(), announce);
+
+        {
+            // Header names are case-insensitive, and the cookie optional
+            let message = format!(
+                "BT-SEARCH * HTTP/1.1\r\nHost: {}\r\nport: 1\r\nINFOHASH: {}\r\n\r\n\r\n",
+                LSD_IPV4,
+                "AB".repeat(20)
+            );
+            let announce = Announce::decode(message.as_bytes()).unwrap();
+            assert_eq!(announce.port, 1);
+            assert_eq!(announce.info_hashes, [[0xAB; 20]]);
+            assert_eq!(announce.cookie, None);
+        }
+
+        assert!(Announce::decode(b"M-SEARCH * HTTP/1.1\r\nPort: 1\r\n\r\n").is_err());
+        assert!(Announce::decode(b"BT-SEARCH * HTTP/1.1\r\nInfohash: ab\r\n\r\n").is_err());
+    }
+
+    #[tokio::test]
+    async fn loopback_multicast() {
+        // A port of our own, so that real LSD traffic doesn't get in the way
+        let port = std::net::UdpSocket::bind("0.0.0.0:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let group = SocketAddrV4::new(*LSD_IPV4.ip(), port);
+        let lsd = || Lsd::new(vec![Group::ipv4(group, Ipv4Addr::LOCALHOST).unwrap()]);
+        let peer = |port| SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+        let info_hash = [7; 20];
+
+        let (a_tx, mut a_rx) = mpsc::unbounded_channel();
+        let (b_tx, mut b_rx) = mpsc::unbounded_channel();
+        let (other_tx, mut other_rx) = mpsc::unbounded_channel();
+        let a = lsd().spawn(info_hash, 1111, a_tx);
+        let other = lsd().spawn([8; 20], 3333, other_tx);
+        let b = lsd().spawn(info_hash, 2222, b_tx);
+
+        let timeout = Duration::from_secs(5);
+        let found = tokio::time::timeout(timeout, b_rx.recv()).await.unwrap();
+        assert_eq!(found.unwrap().addr, peer(1111));
+        let found = tokio::time::timeout(timeout, a_rx.recv()).await.unwrap();
+        assert_eq!(found.unwrap().addr, peer(2222));
+
+        a.stop().await.unwrap();
+        b.stop().await.unwrap();
+        other.stop().await.unwrap();
+        // Not our own announces, nor those of other torrents
+        assert!(a_rx.try_recv().is_err());
+        assert!(b_rx.try_recv().is_err());
+        assert!(other_rx.try_recv().is_err());
+    }
+}
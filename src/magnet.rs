@@ -0,0 +1,85 @@
+use anyhow::{Context, Result};
+use std::{fmt, str::FromStr};
+
+use crate::http::percent_decode;
+
+/// A magnet link to a torrent that its publisher can update (BEP 46). It names a public
+/// key and salt, under which the DHT holds a mutable item with the current info hash.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PublicKeyMagnet {
+    pub key: [u8; 32],
+    pub salt: Vec<u8>,
+}
+
+impl FromStr for PublicKeyMagnet {
+    type Err = anyhow::Error;
+
+    fn from_str(link: &str) -> Result<Self> {
+        let query = link.strip_prefix("magnet:?").context("not a magnet link")?;
+        let mut key = None;
+        let mut salt = Vec::new();
+        for pair in query.split('&') {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let value = percent_decode(value)
+                .and_then(|value| String::from_utf8(value).ok())
+                .with_context(|| format!("invalid {} in magnet link", name))?;
+            match name {
+                "xs" => {
+                    if let Some(hex) = value.strip_prefix("urn:btpk:") {
+                        let bytes = hex::decode(hex).ok().and_then(|key| key.try_into().ok());
+                        key = Some(bytes.context("invalid public key in magnet link")?);
+                    }
+                }
+                "s" => salt = hex::decode(&value).context("invalid salt in magnet link")?,
+                _ => {}
+            }
+        }
+        Ok(PublicKeyMagnet {
+            key: key.context("magnet link has no public key")?,
+            salt,
+        })
+    }
+}
+
+impl fmt::Display for PublicKeyMagnet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "magnet:?xs=urn:btpk:{}", hex::encode(self.key))?;
+        if !self.salt.is_empty() {
+            write!(f, "&s={}", hex::encode(&self.salt))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PublicKeyMagnet;
+
+    #[test]
+    fn public_key_magnets() {
+        let link = "magnet:?xs=urn:btpk:8543d3e6115f0f98c944077a4493dcd543e49c739fd998550a1f614ab36ed63e&s=6e";
+        let magnet = link.parse::<PublicKeyMagnet>().unwrap();
+        assert_eq!(magnet.key[..2], [0x85, 0x43]);
+        assert_eq!(magnet.salt, b"n");
+        assert_eq!(magnet.to_string(), link);
+
+        let magnet = PublicKeyMagnet {
+            key: [1; 32],
+            salt: Vec::new(),
+        };
+        assert_eq!(
+            magnet.to_string().parse::<PublicKeyMagnet>().unwrap(),
+            magnet
+        );
+
+        assert!(
+            "magnet:?xt=urn:btih:0000000000000000000000000000000000000000"
+                .parse::<PublicKeyMagnet>()
+                .is_err()
+        );
+        assert!("magnet:?xs=urn:btpk:1234"
+            .parse::<PublicKeyMagnet>()
+            .is_err());
+        assert!("http://example.com".parse::<PublicKeyMagnet>().is_err());
+    }
+}
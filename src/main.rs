@@ -1,19 +1,41 @@
 use anyhow::{Context, Result};
 use clap::{Parser, Subcommand};
-use std::{net::SocketAddrV4, path::PathBuf};
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
 mod bencode;
+mod connections;
+mod dht;
+mod download;
+mod edit;
+mod http;
+mod httpseed;
+mod identity;
+mod lint;
+mod lsd;
+mod magnet;
 mod peer;
+mod pex;
+mod sanitize;
 mod torrent;
 mod tracker;
-
-const PEER_ID: &str = "27454831420650771739";
+mod webseed;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
     #[command(subcommand)]
     command: Command,
+    #[command(flatten)]
+    identity: identity::IdentityArgs,
+    #[command(flatten)]
+    dht: dht::DhtArgs,
+    #[command(flatten)]
+    lsd: lsd::LsdArgs,
 }
 
 #[derive(Subcommand)]
@@ -26,11 +48,14 @@ enum Command {
         path: PathBuf,
     },
     Peers {
+        /// Announce to every tracker tier instead of stopping at the first that answers
+        #[arg(long)]
+        all_tiers: bool,
         path: PathBuf,
     },
     Handshake {
         path: PathBuf,
-        peer_addr: SocketAddrV4,
+        peer_addr: SocketAddr,
     },
     DownloadPiece {
         #[arg(short)]
@@ -39,15 +64,148 @@ enum Command {
         piece_index: usize,
     },
     Download {
+        /// Defaults to the torrent's name in the current directory. For multi-file torrents
+        /// this is the directory the files are written to
+        #[arg(short)]
+        output_path: Option<PathBuf>,
+        /// How to handle file names from the torrent that are unsafe to use
+        #[arg(long, value_enum, default_value_t)]
+        path_policy: sanitize::PathPolicy,
+        /// Announce to every tracker tier instead of stopping at the first that answers
+        #[arg(long)]
+        all_tiers: bool,
+        path: PathBuf,
+    },
+    /// Change trackers, web seeds and other metadata of a torrent
+    Edit {
         #[arg(short)]
         output_path: PathBuf,
         path: PathBuf,
+        #[command(flatten)]
+        edit: edit::Edit,
+    },
+    /// Ask the trackers of torrents how many seeders, leechers and downloads they have
+    Scrape {
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+    },
+    /// Run or talk to trackers
+    Tracker {
+        #[command(subcommand)]
+        command: TrackerCommand,
+    },
+    /// Survey the DHT
+    Dht {
+        #[command(subcommand)]
+        command: DhtCommand,
+    },
+    /// Check torrents for spec violations and quality problems
+    Lint {
+        /// Print the findings as JSON
+        #[arg(long)]
+        json: bool,
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+    },
+}
+
+#[derive(Subcommand)]
+#[clap(rename_all = "snake_case")]
+enum TrackerCommand {
+    /// Run a tracker, for private swarms
+    Serve {
+        /// Where to listen for HTTP announces and scrapes. May be given more than once
+        #[arg(long, default_value = "0.0.0.0:6969")]
+        http: Vec<SocketAddr>,
+        /// Where to listen for UDP announces and scrapes (BEP 15). May be given more than once
+        #[arg(long, default_value = "0.0.0.0:6969")]
+        udp: Vec<SocketAddr>,
+        /// Seconds clients should wait between announces
+        #[arg(long, default_value_t = 30 * 60)]
+        interval: u64,
+        /// Forget peers that haven't announced for this many seconds. Defaults to twice the
+        /// interval
+        #[arg(long)]
+        peer_timeout: Option<u64>,
+        /// Only track these torrents, given as torrent files or hex info hashes
+        #[arg(long)]
+        allow: Vec<String>,
+        /// Let clients announce addresses other than the one they connect from, with `ip`,
+        /// `ipv4` and `ipv6`. Anyone could then point the swarm at a third party
+        #[arg(long)]
+        trust_client_ips: bool,
+    },
+}
+
+#[derive(Subcommand)]
+#[clap(rename_all = "snake_case")]
+enum DhtCommand {
+    /// Estimate how many seeds and other peers a torrent has (BEP 33)
+    Scrape {
+        /// A hex info hash or a torrent file
+        info_hash: String,
+    },
+    /// List info hashes that DHT nodes have peers for (BEP 51)
+    Sample {
+        /// Ask the nodes closest to this hex node ID. Defaults to a random one
+        target: Option<String>,
+    },
+    /// Fetch an item stored in the DHT (BEP 44)
+    Get {
+        /// The hex hash of an immutable item, or the hex public key of a mutable one
+        target: String,
+        /// The salt of a mutable item
+        #[arg(long, default_value = "")]
+        salt: String,
+    },
+    /// Store an item in the DHT (BEP 44)
+    Put {
+        /// The bencoded value, such as 12:Hello World!
+        value: String,
+        /// Make a mutable item signed with the hex private key in this file, which is made
+        /// if it doesn't exist
+        #[arg(long)]
+        key: Option<PathBuf>,
+        /// The salt of a mutable item, to keep several under one key
+        #[arg(long, default_value = "")]
+        salt: String,
     },
+    /// Point an updatable torrent at a new version and print its magnet link (BEP 46)
+    Publish {
+        /// The hex private key to sign with, made if the file doesn't exist
+        #[arg(long)]
+        key: PathBuf,
+        /// Lets one key publish several torrents
+        #[arg(long, default_value = "")]
+        salt: String,
+        /// A hex info hash or a torrent file
+        info_hash: String,
+    },
+    /// Find the info hash an updatable torrent's magnet link currently points at (BEP 46)
+    Resolve { magnet: magnet::PublicKeyMagnet },
+}
+
+/// Parse a hex info hash, or read it from a torrent file.
+fn parse_info_hash(input: &str) -> Result<[u8; 20]> {
+    match hex::decode(input) {
+        Ok(bytes) if bytes.len() == 20 => Ok(bytes.try_into().unwrap()),
+        _ => {
+            let torrent = std::fs::read(input)
+                .with_context(|| format!("{} is not an info hash or a torrent", input))?;
+            Ok(torrent::Torrent::from_bytes(&torrent)?.info_hash_bytes())
+        }
+    }
+}
+
+/// The error for when neither the trackers nor the DHT could give us peers, with both reasons.
+fn dht_failed(tracker_error: anyhow::Error, dht_error: anyhow::Error) -> anyhow::Error {
+    anyhow::anyhow!("{:#}; the DHT failed too: {:#}", tracker_error, dht_error)
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let cli = Cli::parse();
+    let identity = identity::Identity::from(cli.identity);
 
     match cli.command {
         Command::Decode { input } => {
@@ -58,7 +216,13 @@ async fn main() -> Result<()> {
             let input = std::fs::read(path)?;
             let torrent = torrent::Torrent::from_bytes(&input)?;
 
-            println!("Tracker URL: {}", torrent.announce);
+            if let Err(e) = torrent.info.validate_paths() {
+                eprintln!("warning: unsafe path: {}", e);
+            }
+
+            if let Some(announce) = &torrent.announce {
+                println!("Tracker URL: {}", announce);
+            }
             println!("Length: {}", torrent.info.length);
             println!("Info Hash: {}", torrent.info_hash());
             println!("Piece Length: {}", torrent.info.piece_length);
@@ -67,19 +231,40 @@ async fn main() -> Result<()> {
                 println!("{}", hash);
             }
         }
-        Command::Peers { path } => {
+        Command::Peers { all_tiers, path } => {
             let input = std::fs::read(path)?;
             let torrent = torrent::Torrent::from_bytes(&input)?;
 
-            for peer in tracker::get_peers(&torrent)?.iter() {
-                println!("{:?}", peer);
+            let peers = match tracker::announce(&torrent, &identity, all_tiers).await {
+                Ok(response) => {
+                    // On stderr, so the addresses stay easy to read from stdout
+                    if let (Some(seeders), Some(leechers)) = (response.seeders, response.leechers) {
+                        eprintln!("{} seeders, {} leechers", seeders, leechers);
+                    }
+                    response.peers
+                }
+                Err(e) if cli.dht.enabled_for(&torrent) => {
+                    eprintln!("warning: {:#}, asking the DHT instead", e);
+                    let dht = match cli.dht.start(&torrent.nodes, &identity).await {
+                        Ok(dht) => dht,
+                        Err(dht_error) => return Err(dht_failed(e, dht_error)),
+                    };
+                    let peers = dht.get_peers(torrent.info_hash_bytes()).await;
+                    cli.dht.save(&dht);
+                    peers.into_iter().map(tracker::Peer::from).collect()
+                }
+                Err(e) => return Err(e),
+            };
+            for peer in peers.iter() {
+                println!("{:?}", peer.addr);
             }
         }
         Command::Handshake { path, peer_addr } => {
             let input = std::fs::read(path)?;
-            let torrent = torrent::Torrent::from_bytes(&input)?;
+            let torrent = Arc::new(torrent::Torrent::from_bytes(&input)?);
 
-            let connection = peer::PeerConnection::connect(torrent, peer_addr).await?;
+            let connection =
+                peer::PeerConnection::connect(torrent, identity.peer_id, peer_addr, None).await?;
             println!("Peer ID: {}", hex::encode(connection.peer_id.unwrap()));
         }
         Command::DownloadPiece {
@@ -88,26 +273,432 @@ async fn main() -> Result<()> {
             piece_index,
         } => {
             let input = std::fs::read(path)?;
-            let torrent = torrent::Torrent::from_bytes(&input)?;
+            let torrent = Arc::new(torrent::Torrent::from_bytes(&input)?);
 
-            let peers = tracker::get_peers(&torrent)?;
-            let peer_addr = peers.first().context("no peers found")?;
+            let peers = tracker::get_peers(&torrent, &identity, false).await?;
+            let peer = peers.first().context("no peers found")?;
 
-            let mut connection = peer::PeerConnection::connect(torrent, *peer_addr).await?;
+            let mut connection =
+                peer::PeerConnection::connect(torrent, identity.peer_id, peer.addr, None).await?;
             connection.download_piece(piece_index, &output_path).await?;
             println!("Piece {} downloaded to {:?}.", &piece_index, &output_path);
         }
-        Command::Download { output_path, path } => {
+        Command::Download {
+            output_path,
+            path_policy,
+            all_tiers,
+            path,
+        } => {
             let input = std::fs::read(&path)?;
-            let torrent = torrent::Torrent::from_bytes(&input)?;
+            let torrent = Arc::new(torrent::Torrent::from_bytes(&input)?);
+            let output_path = match output_path {
+                Some(output_path) => output_path,
+                None => torrent.info.output_path(Path::new("."), path_policy)?,
+            };
 
-            let peers = tracker::get_peers(&torrent)?;
-            let peer_addr = peers.first().context("no peers found")?;
+            let stats = Arc::new(download::Stats::new(&torrent));
+            let mut announcer = tracker::Announcer::new(&torrent, &identity, all_tiers)?;
+            let (peers_tx, peers_rx) = tokio::sync::mpsc::unbounded_channel();
+            let connections =
+                connections::ConnectionManager::new(torrent.clone(), identity.peer_id, peers_rx);
+            let mut reannouncer = None;
+            let mut dht_announcer = None;
+            let mut lsd_announcer = None;
+            if cli.lsd.enabled_for(&torrent) {
+                match lsd::Lsd::bind() {
+                    Ok(lsd) => {
+                        lsd_announcer = Some(lsd.spawn(
+                            torrent.info_hash_bytes(),
+                            identity.port,
+                            connections.discovered(),
+                        ))
+                    }
+                    Err(e) => eprintln!("warning: {:#}, not looking for local peers", e),
+                }
+            }
+            match announcer
+                .announce(Some(tracker::Event::Started), &stats)
+                .await
+            {
+                Ok(response) => {
+                    for peer in response.peers {
+                        peers_tx.send(peer)?;
+                    }
+                    reannouncer = Some(announcer.spawn(stats.clone(), peers_tx));
+                }
+                Err(e) => {
+                    let dht = if cli.dht.enabled_for(&torrent) {
+                        eprintln!("warning: {:#}, asking the DHT instead", e);
+                        cli.dht
+                            .start(&torrent.nodes, &identity)
+                            .await
+                            .map_err(|dht_error| dht_failed(e, dht_error))
+                    } else {
+                        Err(e)
+                    };
+                    match dht {
+                        Ok(dht) => {
+                            dht_announcer = Some(dht.spawn(
+                                torrent.info_hash_bytes(),
+                                identity.port,
+                                peers_tx.clone(),
+                            ));
+                        }
+                        // Web and HTTP seeds can still provide the whole torrent without peers
+                        Err(e)
+                            if !torrent.url_list.is_empty() || !torrent.http_seeds.is_empty() =>
+                        {
+                            eprintln!("warning: failed to get peers: {:#}", e);
+                        }
+                        Err(e) => return Err(e),
+                    }
+                    // The trackers may come back, so keep trying them in the background.
+                    // Without any announcer, the download should stop once the seeds fail.
+                    if announcer.has_trackers() {
+                        reannouncer = Some(announcer.spawn(stats.clone(), peers_tx));
+                    } else {
+                        drop(peers_tx);
+                    }
+                }
+            }
+            let mut sources = Vec::new();
+            for url in torrent.url_list.iter() {
+                match webseed::WebSeed::new(url) {
+                    Ok(web_seed) => sources.push(download::PieceSource::WebSeed(web_seed)),
+                    Err(e) => eprintln!("warning: skipping web seed {}: {}", url, e),
+                }
+            }
+            for url in torrent.http_seeds.iter() {
+                match httpseed::HttpSeed::new(url) {
+                    Ok(http_seed) => sources.push(download::PieceSource::HttpSeed(http_seed)),
+                    Err(e) => eprintln!("warning: skipping HTTP seed {}: {}", url, e),
+                }
+            }
 
-            let mut connection = peer::PeerConnection::connect(torrent, *peer_addr).await?;
-            connection.download(&output_path).await?;
+            let download = download::download(
+                &torrent,
+                sources,
+                connections,
+                &output_path,
+                path_policy,
+                &stats,
+            );
+            let result = tokio::select! {
+                result = download => result,
+                _ = tokio::signal::ctrl_c() => Err(anyhow::anyhow!("interrupted")),
+            };
+            if let Some(lsd_announcer) = lsd_announcer {
+                lsd_announcer.stop().await?;
+            }
+            if let Some(dht_announcer) = dht_announcer {
+                cli.dht.save(&dht_announcer.stop().await?);
+            }
+            let announcer = match reannouncer {
+                Some(reannouncer) => Some(reannouncer.stop().await?),
+                None => None,
+            };
+            // Trackers that never heard of us have no use for the final announces
+            if let Some(mut announcer) = announcer.filter(tracker::Announcer::started) {
+                if result.is_ok() {
+                    if let Err(e) = announcer
+                        .announce(Some(tracker::Event::Completed), &stats)
+                        .await
+                    {
+                        eprintln!("warning: failed to announce completion: {:#}", e);
+                    }
+                }
+                if let Err(e) = announcer
+                    .announce(Some(tracker::Event::Stopped), &stats)
+                    .await
+                {
+                    eprintln!("warning: failed to announce stopping: {:#}", e);
+                }
+            }
+            result?;
             println!("Downloaded {:?} to {:?}.", &path, &output_path)
         }
+        Command::Edit {
+            output_path,
+            path,
+            edit,
+        } => {
+            let input = std::fs::read(&path)?;
+            let edited = edit.apply(&input)?;
+            std::fs::write(&output_path, &edited.bytes)?;
+
+            if edited.info_hash_changed() {
+                eprintln!(
+                    "warning: the info hash changed from {}, peers will see this as a different torrent",
+                    edited.old_info_hash
+                );
+            }
+            println!("Info Hash: {}", edited.new_info_hash);
+        }
+        Command::Scrape { paths } => {
+            let mut torrents = Vec::new();
+            for path in paths {
+                let input = std::fs::read(&path)?;
+                torrents.push((path, torrent::Torrent::from_bytes(&input)?));
+            }
+
+            // Scrape each torrent's first tracker, asking about all its torrents at once
+            let mut by_tracker = BTreeMap::<String, Vec<usize>>::new();
+            let mut results = (0..torrents.len()).map(|_| None).collect::<Vec<_>>();
+            for (i, (_, torrent)) in torrents.iter().enumerate() {
+                match torrent
+                    .tracker_tiers()
+                    .first()
+                    .and_then(|tier| tier.first())
+                {
+                    Some(url) => by_tracker.entry(url.clone()).or_default().push(i),
+                    None => results[i] = Some(Err(anyhow::anyhow!("torrent has no trackers"))),
+                }
+            }
+            let client = tracker::TrackerClient::new(&identity)?;
+            for (url, indices) in by_tracker {
+                let info_hashes = indices
+                    .iter()
+                    .map(|&i| torrents[i].1.info_hash_bytes())
+                    .collect::<Vec<_>>();
+                let stats = match reqwest::Url::parse(&url) {
+                    Ok(url) => client.scrape(&url, &info_hashes).await,
+                    Err(e) => Err(e.into()),
+                };
+                for (i, info_hash) in indices.into_iter().zip(info_hashes) {
+                    results[i] = Some(match &stats {
+                        Ok(stats) => stats
+                            .get(&info_hash)
+                            .copied()
+                            .with_context(|| format!("{} does not track this torrent", url)),
+                        Err(e) => Err(anyhow::anyhow!("{:#}", e)),
+                    });
+                }
+            }
+
+            let mut failed = 0;
+            for ((path, _), result) in torrents.iter().zip(results) {
+                match result.context("torrent was not scraped")? {
+                    Ok(stats) => println!(
+                        "{}: {} seeders, {} leechers, {} downloaded",
+                        path.display(),
+                        stats.seeders,
+                        stats.leechers,
+                        stats.downloaded
+                    ),
+                    Err(e) => {
+                        failed += 1;
+                        println!("{}: {:#}", path.display(), e);
+                    }
+                }
+            }
+            if failed > 0 {
+                anyhow::bail!(
+                    "{} of {} torrents could not be scraped",
+                    failed,
+                    torrents.len()
+                );
+            }
+        }
+        Command::Tracker {
+            command:
+                TrackerCommand::Serve {
+                    http,
+                    udp,
+                    interval,
+                    peer_timeout,
+                    allow,
+                    trust_client_ips,
+                },
+        } => {
+            let allowlist = if allow.is_empty() {
+                None
+            } else {
+                let mut allowlist = std::collections::HashSet::new();
+                for entry in allow {
+                    allowlist.insert(parse_info_hash(&entry)?);
+                }
+                Some(allowlist)
+            };
+            let interval = std::time::Duration::from_secs(interval);
+            let config = tracker::ServerConfig {
+                interval,
+                peer_timeout: peer_timeout.map_or(interval * 2, std::time::Duration::from_secs),
+                allowlist,
+                trust_client_ips,
+            };
+            let server = tracker::TrackerServer::bind(config, &http, &udp).await?;
+            for addr in server.http_addrs()? {
+                println!("Announce URL: http://{}/announce", addr);
+            }
+            for addr in server.udp_addrs()? {
+                println!("Announce URL: udp://{}/announce", addr);
+            }
+            tokio::select! {
+                result = server.run() => result?,
+                _ = tokio::signal::ctrl_c() => {}
+            }
+        }
+        Command::Dht {
+            command: DhtCommand::Scrape { info_hash },
+        } => {
+            let info_hash = parse_info_hash(&info_hash)?;
+            let dht = cli.dht.start(&[], &identity).await?;
+            let scrape = dht.scrape(info_hash).await;
+            cli.dht.save(&dht);
+            anyhow::ensure!(scrape.nodes > 0, "no DHT node knows of this torrent");
+            println!(
+                "{}: about {} seeds and {} other peers, according to {} nodes",
+                hex::encode(info_hash),
+                scrape.seeds,
+                scrape.peers,
+                scrape.nodes
+            );
+        }
+        Command::Dht {
+            command: DhtCommand::Sample { target },
+        } => {
+            let target = match target {
+                Some(target) => hex::decode(&target)
+                    .ok()
+                    .and_then(|bytes| bytes.try_into().ok())
+                    .with_context(|| format!("{} is not a node ID", target))?,
+                None => rand::random(),
+            };
+            let dht = cli.dht.start(&[], &identity).await?;
+            let sample = dht.sample(target).await;
+            cli.dht.save(&dht);
+            for info_hash in sample.info_hashes.iter() {
+                println!("{}", hex::encode(info_hash));
+            }
+            eprintln!(
+                "{} info hashes from {} nodes, which know of {} in all",
+                sample.info_hashes.len(),
+                sample.nodes,
+                sample.total
+            );
+        }
+        Command::Dht {
+            command: DhtCommand::Get { target, salt },
+        } => {
+            let target = hex::decode(&target).unwrap_or_default();
+            anyhow::ensure!(
+                target.len() == 20 || target.len() == 32,
+                "target is neither an item hash nor a public key"
+            );
+            let dht = cli.dht.start(&[], &identity).await?;
+            let value = if let Ok(target) = <[u8; 20]>::try_from(target.as_slice()) {
+                dht.get_immutable(target).await
+            } else {
+                let key = <[u8; 32]>::try_from(target.as_slice())?;
+                dht.get_mutable(key, salt.as_bytes()).await.map(|item| {
+                    eprintln!("Sequence number: {}", item.seq);
+                    item.value
+                })
+            };
+            cli.dht.save(&dht);
+            let value = value.context("no DHT node has this item")?;
+            println!("{}", bencode::BencodeValue::from_bytes(&value)?.1);
+        }
+        Command::Dht {
+            command: DhtCommand::Put { value, key, salt },
+        } => {
+            let value = value.into_bytes();
+            dht::items::check_value(&value)?;
+            let dht = cli.dht.start(&[], &identity).await?;
+            let item = match key {
+                None => dht::items::Item::Immutable(value),
+                Some(key) => {
+                    let key = dht::items::signing_key(&key)?;
+                    let public_key = key.verifying_key().to_bytes();
+                    let seq = dht
+                        .get_mutable(public_key, salt.as_bytes())
+                        .await
+                        .map_or(1, |item| item.seq + 1);
+                    let item = dht::items::MutableItem::sign(&key, salt.as_bytes(), seq, value)?;
+                    println!("Public Key: {}", hex::encode(public_key));
+                    println!("Sequence number: {}", seq);
+                    dht::items::Item::Mutable(item)
+                }
+            };
+            let target = item.target();
+            let stored = dht.put(item).await;
+            cli.dht.save(&dht);
+            println!(
+                "Target: {} (stored by {} nodes)",
+                hex::encode(target),
+                stored?
+            );
+        }
+        Command::Dht {
+            command:
+                DhtCommand::Publish {
+                    key,
+                    salt,
+                    info_hash,
+                },
+        } => {
+            let info_hash = parse_info_hash(&info_hash)?;
+            let key = dht::items::signing_key(&key)?;
+            let dht = cli.dht.start(&[], &identity).await?;
+            let seq = dht.publish(&key, salt.as_bytes(), info_hash).await;
+            cli.dht.save(&dht);
+            println!("Sequence number: {}", seq?);
+            let magnet = magnet::PublicKeyMagnet {
+                key: key.verifying_key().to_bytes(),
+                salt: salt.into_bytes(),
+            };
+            println!("{}", magnet);
+        }
+        Command::Dht {
+            command: DhtCommand::Resolve { magnet },
+        } => {
+            let dht = cli.dht.start(&[], &identity).await?;
+            let info_hash = dht.resolve(&magnet).await;
+            cli.dht.save(&dht);
+            println!("Info Hash: {}", hex::encode(info_hash?));
+        }
+        Command::Lint { json, paths } => {
+            let mut reports = Vec::new();
+            for path in paths {
+                let input = std::fs::read(&path)?;
+                let findings = match torrent::Torrent::from_bytes(&input) {
+                    Ok(torrent) => lint::lint(&input, &torrent),
+                    Err(e) => vec![lint::Finding {
+                        severity: lint::Severity::Error,
+                        check: "parse",
+                        message: e.to_string(),
+                    }],
+                };
+                reports.push((path, findings));
+            }
+
+            if json {
+                let output = reports
+                    .iter()
+                    .map(|(path, findings)| serde_json::json!({ "path": path, "findings": findings }))
+                    .collect::<Vec<_>>();
+                println!("{}", serde_json::to_string_pretty(&output)?);
+            } else {
+                for (path, findings) in reports.iter() {
+                    if findings.is_empty() {
+                        println!("{}: ok", path.display());
+                    }
+                    for finding in findings {
+                        println!("{}: {}", path.display(), finding);
+                    }
+                }
+            }
+
+            let failed = reports
+                .iter()
+                .filter(|(_, findings)| {
+                    findings.iter().any(|f| f.severity == lint::Severity::Error)
+                })
+                .count();
+            if failed > 0 {
+                anyhow::bail!("{} of {} torrents failed lint", failed, reports.len());
+            }
+        }
     }
 
     Ok(())
@@ -1,41 +1,53 @@
 use anyhow::Result;
-use sha1::{Digest, Sha1};
-use std::{
-    io::{Read, Write},
-    net::SocketAddrV4,
-    path::PathBuf,
-};
-use tempfile::TempDir;
+use std::{collections::BTreeMap, net::SocketAddr, path::PathBuf, sync::Arc};
 use tokio::{
     io::{self, AsyncReadExt, AsyncWriteExt},
     net::TcpStream,
 };
 
-use crate::{torrent::Torrent, PEER_ID};
+use crate::{
+    bencode::{BencodeByteString, BencodeValue},
+    pex::{self, Pex, PexSession},
+    torrent::Torrent,
+};
 
 const HANDSHAKE_LEN: usize = 68;
 const BLOCK_LEN: usize = 16 * 1024;
 const MAX_CONCURRENT_REQUESTS: usize = 5;
+/// The reserved handshake bit for the extension protocol (BEP 10), as byte and mask.
+const EXTENSION_BIT: (usize, u8) = (5, 0x10);
+/// The extended message ID of the extension protocol's own handshake.
+const EXTENDED_HANDSHAKE_ID: u8 = 0;
 
 #[derive(Debug)]
 struct Handshake {
+    reserved: [u8; 8],
     info_hash: [u8; 20],
     peer_id: [u8; 20],
 }
 
 impl Handshake {
-    fn new(torrent: &Torrent) -> Result<Self> {
-        Ok(Handshake {
-            info_hash: hex::decode(torrent.info_hash())?.try_into().unwrap(),
-            peer_id: PEER_ID.as_bytes().try_into()?,
-        })
+    fn new(torrent: &Torrent, peer_id: [u8; 20], extensions: bool) -> Self {
+        let mut reserved = [0; 8];
+        if extensions {
+            reserved[EXTENSION_BIT.0] |= EXTENSION_BIT.1;
+        }
+        Handshake {
+            reserved,
+            info_hash: torrent.info_hash_bytes(),
+            peer_id,
+        }
+    }
+
+    fn supports_extensions(&self) -> bool {
+        self.reserved[EXTENSION_BIT.0] & EXTENSION_BIT.1 != 0
     }
 
     fn encode(&self) -> Vec<u8> {
         let mut output = Vec::new();
         output.push(19);
         output.extend(b"BitTorrent protocol");
-        output.extend([0u8; 8]);
+        output.extend(self.reserved);
         output.extend(&self.info_hash);
         output.extend(&self.peer_id);
 
@@ -53,6 +65,7 @@ impl Handshake {
         }
 
         Ok(Handshake {
+            reserved: input[20..28].try_into()?,
             info_hash: input[28..48].try_into()?,
             peer_id: input[48..68].try_into()?,
         })
@@ -81,6 +94,12 @@ enum PeerMessage {
         begin: u32,
         length: u32,
     },
+    /// A message of the extension protocol (BEP 10). `id` is 0 for its handshake, and
+    /// otherwise the ID the receiver gave the extension in its handshake.
+    Extended {
+        id: u8,
+        payload: Vec<u8>,
+    },
 }
 
 impl std::fmt::Debug for PeerMessage {
@@ -123,6 +142,11 @@ impl std::fmt::Debug for PeerMessage {
                 f,
                 "PeerMessage::Cancel {{ index: {index}, begin: {begin}, length: {length} }}"
             ),
+            PeerMessage::Extended { id, payload } => write!(
+                f,
+                "PeerMessage::Extended {{ id: {id}, payload.len(): {} }}",
+                payload.len()
+            ),
         }
     }
 }
@@ -171,6 +195,16 @@ impl PeerMessage {
                     length: u32::from_be_bytes([payload[8], payload[9], payload[10], payload[11]]),
                 })
             }
+            20 => {
+                // Extended
+                let (id, payload) = payload
+                    .split_first()
+                    .ok_or_else(|| anyhow::format_err!("empty extended message"))?;
+                Ok(PeerMessage::Extended {
+                    id: *id,
+                    payload: payload.to_vec(),
+                })
+            }
             _ => Err(anyhow::format_err!("invalid peer message tag {:?}", tag)),
         }
     }
@@ -186,6 +220,7 @@ impl PeerMessage {
             PeerMessage::Request { .. } => 6,
             PeerMessage::Piece { .. } => 7,
             PeerMessage::Cancel { .. } => 8,
+            PeerMessage::Extended { .. } => 20,
         }
     }
 
@@ -236,6 +271,12 @@ impl PeerMessage {
                 output.extend(begin.to_be_bytes());
                 output.extend(block);
             }
+            PeerMessage::Extended { id, payload } => {
+                output.extend(((2 + payload.len()) as u32).to_be_bytes());
+                output.push(self.tag());
+                output.push(*id);
+                output.extend(payload);
+            }
         }
         Ok(output)
     }
@@ -253,10 +294,16 @@ pub enum PeerConnectionState {
 }
 
 pub struct PeerConnection {
-    torrent: Torrent,
+    torrent: Arc<Torrent>,
     state: PeerConnectionState,
     stream: TcpStream,
+    pub peer_addr: SocketAddr,
     pub peer_id: Option<[u8; 20]>,
+    /// Set when we do peer exchange for this torrent.
+    pex: Option<Arc<Pex>>,
+    /// The ID the peer gave `ut_pex`, if it does peer exchange too.
+    peer_pex_id: Option<u8>,
+    pex_session: PexSession,
 }
 
 #[derive(Debug, PartialEq, Clone, Copy, Default)]
@@ -268,24 +315,34 @@ enum BlockState {
 }
 
 impl PeerConnection {
-    /// Connect and handshake with the given peer.
-    pub async fn connect(torrent: Torrent, peer_addr: SocketAddrV4) -> Result<Self> {
+    /// Connect and handshake with the given peer, introducing ourselves as `local_peer_id`.
+    /// With `pex`, we exchange peers with it if it supports that too.
+    pub async fn connect(
+        torrent: Arc<Torrent>,
+        local_peer_id: [u8; 20],
+        peer_addr: SocketAddr,
+        pex: Option<Arc<Pex>>,
+    ) -> Result<Self> {
         let stream = TcpStream::connect(peer_addr).await?;
         let mut connection = PeerConnection {
             torrent,
             state: PeerConnectionState::Connected,
             stream,
+            peer_addr,
             peer_id: None,
+            pex,
+            peer_pex_id: None,
+            pex_session: PexSession::default(),
         };
 
-        connection.send_handshake().await?;
+        connection.send_handshake(local_peer_id).await?;
         connection.receive_handshake().await?;
 
         Ok(connection)
     }
 
-    async fn send_handshake(&mut self) -> Result<()> {
-        let handshake_request = Handshake::new(&self.torrent)?;
+    async fn send_handshake(&mut self, local_peer_id: [u8; 20]) -> Result<()> {
+        let handshake_request = Handshake::new(&self.torrent, local_peer_id, self.pex.is_some());
         self.stream.write_all(&handshake_request.encode()).await?;
         self.state = PeerConnectionState::WaitingForHandshake;
         Ok(())
@@ -298,15 +355,91 @@ impl PeerConnection {
         let handshake_response = Handshake::decode(&buf)?;
         self.peer_id = Some(handshake_response.peer_id);
         self.state = PeerConnectionState::WaitingForBitfield;
+
+        if let Some(pex) = &self.pex {
+            pex.connected(self.peer_addr);
+            if handshake_response.supports_extensions() {
+                self.send_extended_handshake().await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Tell the peer which extensions we support, and the IDs to send their messages with.
+    async fn send_extended_handshake(&mut self) -> Result<()> {
+        let mut extensions = BTreeMap::new();
+        extensions.insert(
+            BencodeByteString(b"ut_pex"),
+            BencodeValue::Integer(pex::UT_PEX_ID.into()),
+        );
+        let mut handshake = BTreeMap::new();
+        handshake.insert(
+            BencodeByteString(b"m"),
+            BencodeValue::Dictionary(extensions),
+        );
+        self.send_message(PeerMessage::Extended {
+            id: EXTENDED_HANDSHAKE_ID,
+            payload: BencodeValue::Dictionary(handshake).to_bytes(),
+        })
+        .await
+    }
+
+    fn receive_extended(&mut self, id: u8, payload: &[u8]) -> Result<()> {
+        let Some(pex) = &self.pex else {
+            anyhow::bail!("unexpected extended message");
+        };
+        match id {
+            EXTENDED_HANDSHAKE_ID => {
+                let (_, handshake) = BencodeValue::from_bytes(payload)?;
+                self.peer_pex_id = handshake
+                    .as_dictionary()
+                    .and_then(|handshake| handshake.get(&BencodeByteString(b"m")))
+                    .and_then(BencodeValue::as_dictionary)
+                    .and_then(|extensions| extensions.get(&BencodeByteString(b"ut_pex")))
+                    .and_then(BencodeValue::as_integer)
+                    .and_then(|id| u8::try_from(*id).ok())
+                    // 0 means the peer turned the extension off
+                    .filter(|id| *id != 0);
+            }
+            pex::UT_PEX_ID => pex.receive(payload)?,
+            // Extensions we don't support and didn't ask for
+            _ => {}
+        }
         Ok(())
     }
 
+    /// Tell the peer about our other peers, at most once a minute.
+    async fn send_pex(&mut self) -> Result<()> {
+        let (Some(pex), Some(id)) = (&self.pex, self.peer_pex_id) else {
+            return Ok(());
+        };
+        let Some(message) = self.pex_session.next_message(pex, self.peer_addr) else {
+            return Ok(());
+        };
+        self.send_message(PeerMessage::Extended {
+            id,
+            payload: message.encode(),
+        })
+        .await
+    }
+
     async fn send_message(&mut self, msg: PeerMessage) -> Result<()> {
         self.stream.write_all(&msg.encode()?).await?;
         Ok(())
     }
 
+    /// Receive the next message, handling those of the extension protocol along the way.
     async fn receive_message(&mut self) -> Result<PeerMessage> {
+        loop {
+            self.send_pex().await?;
+            match self.receive_any_message().await? {
+                PeerMessage::Extended { id, payload } => self.receive_extended(id, &payload)?,
+                message => return Ok(message),
+            }
+        }
+    }
+
+    async fn receive_any_message(&mut self) -> Result<PeerMessage> {
         let mut length_buf = [0; 4];
         match self.stream.read_exact(&mut length_buf).await {
             Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => {
@@ -338,41 +471,22 @@ impl PeerConnection {
         Ok(())
     }
 
-    pub async fn download<P>(&mut self, output_path: P) -> Result<()>
+    /// Download a piece, check its hash and write it to `output_path`.
+    pub async fn download_piece<P>(&mut self, piece_index: usize, output_path: P) -> Result<()>
     where
         P: Into<PathBuf>,
     {
-        let temp_dir = TempDir::new()?;
-        for i in 0..self.torrent.info.piece_count() {
-            let piece_path = {
-                let mut p = PathBuf::from(temp_dir.path());
-                p.push(format!("piece-{}", i));
-                p
-            };
-            self.download_piece(i, &piece_path).await?;
-        }
-
-        let mut file = std::fs::File::create(output_path.into())?;
-        let mut piece_buf = Vec::with_capacity(self.torrent.info.piece_length);
-        for i in 0..self.torrent.info.piece_count() {
-            piece_buf.clear();
-            let piece_path = {
-                let mut p = PathBuf::from(temp_dir.path());
-                p.push(format!("piece-{}", i));
-                p
-            };
-            let mut piece_file = std::fs::File::open(piece_path)?;
-            piece_file.read_to_end(&mut piece_buf)?;
-            file.write_all(&piece_buf)?;
+        let piece = self.fetch_piece(piece_index).await?;
+        if !self.torrent.info.verify_piece(piece_index, &piece) {
+            anyhow::bail!("incorrect piece hash");
         }
 
+        std::fs::write(output_path.into(), &piece)?;
         Ok(())
     }
 
-    pub async fn download_piece<P>(&mut self, piece_index: usize, output_path: P) -> Result<()>
-    where
-        P: Into<PathBuf>,
-    {
+    /// Download a piece from the peer. The data is not checked against the piece hash.
+    pub async fn fetch_piece(&mut self, piece_index: usize) -> Result<Vec<u8>> {
         match self.state {
             PeerConnectionState::WaitingForBitfield => {
                 self.receive_bitfield().await?;
@@ -381,18 +495,10 @@ impl PeerConnection {
             _ => anyhow::bail!("invalid state {:?}", self.state),
         }
 
-        let piece_length = if piece_index == self.torrent.info.piece_count() - 1 {
-            if self.torrent.info.length % self.torrent.info.piece_length == 0 {
-                self.torrent.info.piece_length
-            } else {
-                self.torrent.info.length % self.torrent.info.piece_length
-            }
-        } else {
-            self.torrent.info.piece_length
-        };
+        let piece_length = self.torrent.info.piece_len(piece_index);
         let block_count = div_round_up(piece_length, BLOCK_LEN);
         let mut block_states = vec![BlockState::default(); block_count];
-        let last_block_len = if piece_length % BLOCK_LEN == 0 {
+        let last_block_len = if piece_length.is_multiple_of(BLOCK_LEN) {
             BLOCK_LEN
         } else {
             piece_length % BLOCK_LEN
@@ -477,21 +583,18 @@ impl PeerConnection {
             }
         }
 
-        let piece_hash = {
-            let mut hasher = Sha1::new();
-            hasher.update(&piece);
-            let result = hasher.finalize();
-            hex::encode(result)
-        };
-        if piece_hash != *self.torrent.info.piece_hashes().get(piece_index).unwrap() {
-            anyhow::bail!("incorrect piece hash");
-        }
+        Ok(piece)
+    }
+}
 
-        std::fs::write(output_path.into(), &piece)?;
-        Ok(())
+impl Drop for PeerConnection {
+    fn drop(&mut self) {
+        if let Some(pex) = &self.pex {
+            pex.disconnected(self.peer_addr);
+        }
     }
 }
 
 pub fn div_round_up(a: usize, b: usize) -> usize {
-    (a + (b - 1)) / b
+    a.div_ceil(b)
 }
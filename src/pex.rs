@@ -0,0 +1,293 @@
+use anyhow::{Context, Result};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    net::SocketAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tokio::sync::mpsc;
+
+use crate::{
+    bencode::{BencodeByteString, BencodeValue},
+    tracker::{self, Peer},
+};
+
+/// The extended message ID we ask peers to send `ut_pex` messages with.
+pub const UT_PEX_ID: u8 = 1;
+/// The least time between two `ut_pex` messages to the same peer.
+const INTERVAL: Duration = Duration::from_secs(60);
+/// The most peers to add, and to drop, in one message.
+const MAX_PEERS: usize = 50;
+/// In `added.f`: the peer accepts incoming connections, as we reached it.
+const FLAG_REACHABLE: u8 = 0x10;
+/// How long we keep telling peers about a peer after we disconnect from it. We only keep one
+/// connection at a time, so the peers we had a moment ago are the ones worth passing on.
+const RECENT: Duration = Duration::from_secs(10 * 60);
+
+/// Peer exchange (BEP 11) for one torrent: which peers we reached recently, to tell other
+/// peers about, and where to send the peers they tell us about.
+pub struct Pex {
+    /// The peers we reached, with when we disconnected from them unless we still haven't.
+    reached: Mutex<HashMap<SocketAddr, Option<Instant>>>,
+    /// Every address peers told us about, so that each is passed on once.
+    learned_addrs: Mutex<HashSet<SocketAddr>>,
+    learned: mpsc::UnboundedSender<Peer>,
+}
+
+impl Pex {
+    pub fn new(learned: mpsc::UnboundedSender<Peer>) -> Self {
+        Pex {
+            reached: Mutex::new(HashMap::new()),
+            learned_addrs: Mutex::new(HashSet::new()),
+            learned,
+        }
+    }
+
+    pub fn connected(&self, addr: SocketAddr) {
+        self.reached.lock().unwrap().insert(addr, None);
+    }
+
+    pub fn disconnected(&self, addr: SocketAddr) {
+        if let Some(disconnected) = self.reached.lock().unwrap().get_mut(&addr) {
+            *disconnected = Some(Instant::now());
+        }
+    }
+
+    /// The peers we are connected to or were until recently, forgetting the others.
+    fn recent(&self) -> HashSet<SocketAddr> {
+        let mut reached = self.reached.lock().unwrap();
+        reached.retain(|_, disconnected| disconnected.is_none_or(|at| at.elapsed() < RECENT));
+        reached.keys().copied().collect()
+    }
+
+    /// Handle a `ut_pex` message from a peer, passing on at most [`MAX_PEERS`] of the peers
+    /// it added that we haven't reached or heard of before.
+    pub fn receive(&self, payload: &[u8]) -> Result<()> {
+        let message = PexMessage::decode(payload)?;
+        let reached = self.reached.lock().unwrap();
+        let mut learned_addrs = self.learned_addrs.lock().unwrap();
+        for addr in message.added.into_iter().take(MAX_PEERS) {
+            if reached.contains_key(&addr) || !learned_addrs.insert(addr) {
+                continue;
+            }
+            // The download may already be over
+            let _ = self.learned.send(addr.into());
+        }
+        Ok(())
+    }
+}
+
+/// What we told one peer about, and when.
+#[derive(Default)]
+pub struct PexSession {
+    sent: HashSet<SocketAddr>,
+    last_sent: Option<Instant>,
+}
+
+impl PexSession {
+    /// The changes in the peers we reached recently since the last message to the peer at
+    /// `addr`, if it is time for another and there are any.
+    pub fn next_message(&mut self, pex: &Pex, addr: SocketAddr) -> Option<PexMessage> {
+        if self
+            .last_sent
+            .is_some_and(|last_sent| last_sent.elapsed() < INTERVAL)
+        {
+            return None;
+        }
+        let recent = pex.recent();
+        let added = recent
+            .iter()
+            .filter(|peer| **peer != addr && !self.sent.contains(peer))
+            .take(MAX_PEERS)
+            .copied()
+            .collect::<Vec<_>>();
+        let dropped = self
+            .sent
+            .iter()
+            .filter(|peer| !recent.contains(peer))
+            .take(MAX_PEERS)
+            .copied()
+            .collect::<Vec<_>>();
+        if added.is_empty() && dropped.is_empty() {
+            return None;
+        }
+        self.sent.extend(&added);
+        for peer in &dropped {
+            self.sent.remove(peer);
+        }
+        self.last_sent = Some(Instant::now());
+        Some(PexMessage { added, dropped })
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct PexMessage {
+    pub added: Vec<SocketAddr>,
+    pub dropped: Vec<SocketAddr>,
+}
+
+impl PexMessage {
+    pub fn encode(&self) -> Vec<u8> {
+        let (added, added6) = compact(&self.added);
+        let (dropped, dropped6) = compact(&self.dropped);
+        let flags = |compact: &[u8], len| vec![FLAG_REACHABLE; compact.len() / len];
+        let (added_f, added6_f) = (flags(&added, 6), flags(&added6, 18));
+
+        let mut dict = BTreeMap::new();
+        for (key, value) in [
+            (&b"added"[..], &added),
+            (b"added.f", &added_f),
+            (b"added6", &added6),
+            (b"added6.f", &added6_f),
+            (b"dropped", &dropped),
+            (b"dropped6", &dropped6),
+        ] {
+            dict.insert(
+                BencodeByteString(key),
+                BencodeValue::ByteString(BencodeByteString(value)),
+            );
+        }
+        BencodeValue::Dictionary(dict).to_bytes()
+    }
+
+    /// Read the peers from a `ut_pex` message. The flags aren't needed to connect to peers,
+    /// so they are left out.
+    pub fn decode(input: &[u8]) -> Result<Self> {
+        let (_, value) = BencodeValue::from_bytes(input)?;
+        let dict = value.as_dictionary().context("invalid ut_pex message")?;
+        let peers = |key: &[u8], parse: fn(&[u8]) -> Result<Vec<Peer>>| -> Result<Vec<_>> {
+            let Some(peers) = dict.get(&BencodeByteString(key)) else {
+                return Ok(Vec::new());
+            };
+            let peers = peers
+                .as_byte_string()
+                .with_context(|| format!("invalid {}", String::from_utf8_lossy(key)))?;
+            Ok(parse(peers.0)?.into_iter().map(|peer| peer.addr).collect())
+        };
+        let mut added = peers(b"added", tracker::parse_peers)?;
+        added.extend(peers(b"added6", tracker::parse_peers6)?);
+        let mut dropped = peers(b"dropped", tracker::parse_peers)?;
+        dropped.extend(peers(b"dropped6", tracker::parse_peers6)?);
+        Ok(PexMessage { added, dropped })
+    }
+}
+
+/// Compact IPv4 and IPv6 peer lists of `addrs`.
+fn compact(addrs: &[SocketAddr]) -> (Vec<u8>, Vec<u8>) {
+    let mut ipv4 = Vec::new();
+    let mut ipv6 = Vec::new();
+    for addr in addrs {
+        match addr {
+            SocketAddr::V4(addr) => {
+                ipv4.extend(addr.ip().octets());
+                ipv4.extend(addr.port().to_be_bytes());
+            }
+            SocketAddr::V6(addr) => {
+                ipv6.extend(addr.ip().octets());
+                ipv6.extend(addr.port().to_be_bytes());
+            }
+        }
+    }
+    (ipv4, ipv6)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Pex, PexMessage, PexSession, INTERVAL, MAX_PEERS, RECENT};
+    use std::{
+        net::{Ipv4Addr, SocketAddr},
+        time::Instant,
+    };
+    use tokio::sync::mpsc;
+
+    fn addr(addr: &str) -> SocketAddr {
+        addr.parse().unwrap()
+    }
+
+    #[test]
+    fn messages() {
+        let message = PexMessage {
+            added: vec![addr("10.0.0.1:6881"), addr("[2001:db8::1]:6881")],
+            dropped: vec![addr("10.0.0.2:6882")],
+        };
+        let encoded = message.encode();
+        assert!(encoded
+            .windows(b"7:added.f1:\x10".len())
+            .any(|window| window == b"7:added.f1:\x10"));
+        assert_eq!(PexMessage::decode(&encoded).unwrap(), message);
+
+        assert_eq!(
+            PexMessage::decode(b"de").unwrap(),
+            PexMessage {
+                added: Vec::new(),
+                dropped: Vec::new()
+            }
+        );
+        assert!(PexMessage::decode(b"d5:added5:12345e").is_err());
+    }
+
+    #[test]
+    fn sessions() {
+        let (learned, _) = mpsc::unbounded_channel();
+        let pex = Pex::new(learned);
+        let mut session = PexSession::default();
+        let us = addr("10.0.0.1:1");
+        assert_eq!(session.next_message(&pex, us), None);
+
+        // The peer isn't told about itself
+        pex.connected(us);
+        pex.connected(addr("10.0.0.2:1"));
+        let message = session.next_message(&pex, us).unwrap();
+        assert_eq!(message.added, [addr("10.0.0.2:1")]);
+
+        // At most once a minute
+        pex.connected(addr("10.0.0.3:1"));
+        assert_eq!(session.next_message(&pex, us), None);
+        session.last_sent = Some(Instant::now() - INTERVAL);
+        let message = session.next_message(&pex, us).unwrap();
+        assert_eq!(message.added, [addr("10.0.0.3:1")]);
+        assert!(message.dropped.is_empty());
+
+        // Peers we disconnected from are still passed on for a while
+        pex.disconnected(addr("10.0.0.2:1"));
+        let mut other = PexSession::default();
+        let mut added = other.next_message(&pex, us).unwrap().added;
+        added.sort();
+        assert_eq!(added, [addr("10.0.0.2:1"), addr("10.0.0.3:1")]);
+        pex.reached
+            .lock()
+            .unwrap()
+            .insert(addr("10.0.0.2:1"), Some(Instant::now() - RECENT));
+        session.last_sent = Some(Instant::now() - INTERVAL);
+        let message = session.next_message(&pex, us).unwrap();
+        assert!(message.added.is_empty());
+        assert_eq!(message.dropped, [addr("10.0.0.2:1")]);
+    }
+
+    #[test]
+    fn receiving() {
+        let (learned, mut learned_rx) = mpsc::unbounded_channel();
+        let pex = Pex::new(learned);
+        pex.connected(addr("10.0.0.1:1"));
+        let message = |added: Vec<SocketAddr>| {
+            PexMessage {
+                added,
+                dropped: Vec::new(),
+            }
+            .encode()
+        };
+
+        // Peers we reached or heard of already are left out, and so is all past the limit
+        let added = (0..MAX_PEERS as u16 * 2)
+            .map(|port| SocketAddr::from((Ipv4Addr::new(10, 0, 0, 2), port)))
+            .collect::<Vec<_>>();
+        pex.receive(&message(vec![addr("10.0.0.1:1"), added[0]]))
+            .unwrap();
+        pex.receive(&message(added.clone())).unwrap();
+        let mut learned = Vec::new();
+        while let Ok(peer) = learned_rx.try_recv() {
+            learned.push(peer.addr);
+        }
+        assert_eq!(learned, added[..MAX_PEERS]);
+    }
+}
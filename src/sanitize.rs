@@ -0,0 +1,429 @@
+use sha1::{Digest, Sha1};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+use thiserror::Error;
+
+/// Longest file name component most filesystems accept, in bytes.
+pub const MAX_COMPONENT_LEN: usize = 255;
+
+/// Device names Windows reserves regardless of extension.
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "CONIN$", "CONOUT$", "COM0", "COM1", "COM2", "COM3", "COM4",
+    "COM5", "COM6", "COM7", "COM8", "COM9", "LPT0", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6",
+    "LPT7", "LPT8", "LPT9",
+];
+
+/// Characters that are either path separators or not allowed in file names on Windows.
+const FORBIDDEN_CHARS: &[char] = &['/', '\\', '<', '>', ':', '"', '|', '?', '*'];
+
+/// Why a path taken from a torrent is unsafe to use on the local filesystem.
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum UnsafePath {
+    #[error("path has no components")]
+    Empty,
+    #[error("path component is empty")]
+    EmptyComponent,
+    #[error("path {0:?} is absolute")]
+    Absolute(String),
+    #[error("path component {0:?} refers to the current or parent directory")]
+    DotComponent(String),
+    #[error("path component {0:?} contains a NUL byte")]
+    Nul(String),
+    #[error("path component {0:?} contains a control character")]
+    ControlCharacter(String),
+    #[error("path component {0:?} contains the forbidden character {1:?}")]
+    ForbiddenCharacter(String, char),
+    #[error("path component {0:?} is a reserved device name")]
+    ReservedName(String),
+    #[error("path component {0:?} ends with a dot or a space")]
+    TrailingDotOrSpace(String),
+    #[error("path component is {0} bytes long, the limit is {MAX_COMPONENT_LEN}")]
+    TooLong(usize),
+    #[error("paths {0:?} and {1:?} name the same file on case-insensitive filesystems")]
+    Collision(String, String),
+}
+
+/// What to do with a path that fails validation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum PathPolicy {
+    /// Refuse to use the path.
+    Reject,
+    /// Rename the offending components with `sanitize_component`.
+    #[default]
+    Sanitize,
+}
+
+/// Check a single path component (a file or directory name) from a torrent.
+pub fn validate_component(component: &str) -> Result<(), UnsafePath> {
+    if component.is_empty() {
+        return Err(UnsafePath::EmptyComponent);
+    }
+    if component == "." || component == ".." {
+        return Err(UnsafePath::DotComponent(component.to_owned()));
+    }
+    if is_absolute(component) {
+        return Err(UnsafePath::Absolute(component.to_owned()));
+    }
+    if component.contains('\0') {
+        return Err(UnsafePath::Nul(component.to_owned()));
+    }
+    if component.chars().any(char::is_control) {
+        return Err(UnsafePath::ControlCharacter(component.to_owned()));
+    }
+    if let Some(c) = component.chars().find(|c| FORBIDDEN_CHARS.contains(c)) {
+        return Err(UnsafePath::ForbiddenCharacter(component.to_owned(), c));
+    }
+    if is_reserved_name(component) {
+        return Err(UnsafePath::ReservedName(component.to_owned()));
+    }
+    if component.ends_with('.') || component.ends_with(' ') {
+        return Err(UnsafePath::TrailingDotOrSpace(component.to_owned()));
+    }
+    if component.len() > MAX_COMPONENT_LEN {
+        return Err(UnsafePath::TooLong(component.len()));
+    }
+    Ok(())
+}
+
+/// Check every component of a relative path from a torrent.
+pub fn validate_path<S>(components: &[S]) -> Result<(), UnsafePath>
+where
+    S: AsRef<str>,
+{
+    if components.is_empty() {
+        return Err(UnsafePath::Empty);
+    }
+    for component in components {
+        validate_component(component.as_ref())?;
+    }
+    Ok(())
+}
+
+/// Deterministically rename a path component so that it passes `validate_component`.
+///
+/// Valid components are returned unchanged. Otherwise forbidden and control characters
+/// become `_`, `.` and `..` become `_` and `__`, trailing dots and spaces become `_`,
+/// reserved device names get a `_` prefix, and overlong names are truncated and tagged
+/// with a hash of the original so that distinct names stay distinct.
+pub fn sanitize_component(component: &str) -> String {
+    if validate_component(component).is_ok() {
+        return component.to_owned();
+    }
+
+    let mut output = match component {
+        "" | "." => "_".to_owned(),
+        ".." => "__".to_owned(),
+        _ => component
+            .chars()
+            .map(|c| {
+                if c.is_control() || FORBIDDEN_CHARS.contains(&c) {
+                    '_'
+                } else {
+                    c
+                }
+            })
+            .collect(),
+    };
+
+    let trimmed_len = output.trim_end_matches(['.', ' ']).len();
+    let trailing = output.len() - trimmed_len;
+    output.truncate(trimmed_len);
+    output.extend(std::iter::repeat_n('_', trailing));
+
+    if is_reserved_name(&output) {
+        output.insert(0, '_');
+    }
+
+    if output.len() > MAX_COMPONENT_LEN {
+        output = shorten(&output, component);
+    }
+
+    output
+}
+
+/// Turn the components of a path from a torrent into a relative `PathBuf`, applying `policy`.
+pub fn safe_path<S>(components: &[S], policy: PathPolicy) -> Result<PathBuf, UnsafePath>
+where
+    S: AsRef<str>,
+{
+    match policy {
+        PathPolicy::Reject => {
+            validate_path(components)?;
+            Ok(components.iter().map(AsRef::as_ref).collect())
+        }
+        PathPolicy::Sanitize => {
+            if components.is_empty() {
+                return Err(UnsafePath::Empty);
+            }
+            Ok(components
+                .iter()
+                .map(|c| sanitize_component(c.as_ref()))
+                .collect())
+        }
+    }
+}
+
+/// The paths of a torrent's files so far, to catch files that would overwrite each other.
+/// Paths are compared ignoring case, as on Windows and macOS, and after sanitizing, which
+/// can turn distinct names like `a?` and `a*` into the same one.
+#[derive(Debug, Default)]
+pub struct PathSet {
+    /// Each path by its case-folded form, and whether it is shared.
+    paths: HashMap<String, (PathBuf, bool)>,
+}
+
+impl PathSet {
+    /// Add `path`. If an earlier path names the same file, this fails under `Reject`, and
+    /// under `Sanitize` the file name gets a `~N` suffix until it is unique. `shared` paths
+    /// may name the same file as other shared ones, e.g. BEP 47 padding files, which only
+    /// ever hold zeros.
+    pub fn insert(
+        &mut self,
+        path: PathBuf,
+        shared: bool,
+        policy: PathPolicy,
+    ) -> Result<PathBuf, UnsafePath> {
+        let mut unique = path.clone();
+        for n in 1.. {
+            match self.paths.get(&case_fold(&unique)) {
+                None => break,
+                Some((_, other_shared)) if shared && *other_shared => return Ok(unique),
+                Some((other, _)) if policy == PathPolicy::Reject => {
+                    return Err(UnsafePath::Collision(
+                        other.display().to_string(),
+                        path.display().to_string(),
+                    ));
+                }
+                Some(_) => unique = numbered(&path, n),
+            }
+        }
+        self.paths
+            .insert(case_fold(&unique), (unique.clone(), shared));
+        Ok(unique)
+    }
+}
+
+fn case_fold(path: &Path) -> String {
+    path.to_string_lossy().to_lowercase()
+}
+
+/// `path` with `~n` added to its file name, before a short extension.
+fn numbered(path: &Path, n: usize) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let tag = format!("~{}", n);
+    let extension = extension(&name);
+    let stem = &name[..name.len() - extension.len()];
+    let mut end = stem
+        .len()
+        .min(MAX_COMPONENT_LEN - tag.len() - extension.len());
+    while !stem.is_char_boundary(end) {
+        end -= 1;
+    }
+    path.with_file_name(format!("{}{}{}", &stem[..end], tag, extension))
+}
+
+/// Whether `component` would be taken as an absolute path, e.g. `/etc` or `C:\Windows`.
+fn is_absolute(component: &str) -> bool {
+    let bytes = component.as_bytes();
+    matches!(bytes.first(), Some(b'/' | b'\\'))
+        || (bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':')
+}
+
+fn is_reserved_name(component: &str) -> bool {
+    let stem = component.split('.').next().unwrap_or_default().trim_end();
+    RESERVED_NAMES
+        .iter()
+        .any(|name| name.eq_ignore_ascii_case(stem))
+}
+
+/// Truncate `name` to fit `MAX_COMPONENT_LEN`, keeping a short extension and appending
+/// part of the SHA-1 of `original`.
+fn shorten(name: &str, original: &str) -> String {
+    let tag = {
+        let mut hasher = Sha1::new();
+        hasher.update(original.as_bytes());
+        let result = hasher.finalize();
+        format!("~{}", &hex::encode(result)[..8])
+    };
+    let extension = extension(name);
+
+    let mut end = MAX_COMPONENT_LEN - tag.len() - extension.len();
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}{}{}", &name[..end], tag, extension)
+}
+
+/// The extension of `name` with its dot, if it is short enough to be worth keeping when
+/// the rest of the name changes.
+fn extension(name: &str) -> &str {
+    match name.rfind('.') {
+        Some(i) if name.len() - i <= 16 => &name[i..],
+        _ => "",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        safe_path, sanitize_component, validate_component, PathPolicy, PathSet, UnsafePath,
+        MAX_COMPONENT_LEN,
+    };
+    use std::path::PathBuf;
+
+    #[test]
+    fn validate() {
+        assert_eq!(validate_component("sample.txt"), Ok(()));
+        assert_eq!(validate_component(""), Err(UnsafePath::EmptyComponent));
+        assert_eq!(
+            validate_component(".."),
+            Err(UnsafePath::DotComponent("..".to_owned()))
+        );
+        assert_eq!(
+            validate_component("a\0b"),
+            Err(UnsafePath::Nul("a\0b".to_owned()))
+        );
+        assert_eq!(
+            validate_component("/etc/passwd"),
+            Err(UnsafePath::Absolute("/etc/passwd".to_owned()))
+        );
+        assert_eq!(
+            validate_component("C:boot.ini"),
+            Err(UnsafePath::Absolute("C:boot.ini".to_owned()))
+        );
+        assert_eq!(
+            validate_component("a/b"),
+            Err(UnsafePath::ForbiddenCharacter("a/b".to_owned(), '/'))
+        );
+        assert_eq!(
+            validate_component("con.txt"),
+            Err(UnsafePath::ReservedName("con.txt".to_owned()))
+        );
+        assert_eq!(
+            validate_component("name. "),
+            Err(UnsafePath::TrailingDotOrSpace("name. ".to_owned()))
+        );
+        assert_eq!(
+            validate_component(&"a".repeat(300)),
+            Err(UnsafePath::TooLong(300))
+        );
+    }
+
+    #[test]
+    fn sanitize() {
+        {
+            // Valid names are untouched
+            assert_eq!(sanitize_component("sample.txt"), "sample.txt");
+            assert_eq!(sanitize_component("console.log"), "console.log");
+        }
+
+        {
+            // Unsafe names are renamed
+            assert_eq!(sanitize_component(""), "_");
+            assert_eq!(sanitize_component("."), "_");
+            assert_eq!(sanitize_component(".."), "__");
+            assert_eq!(sanitize_component("/etc/passwd"), "_etc_passwd");
+            assert_eq!(sanitize_component("..\\..\\boot.ini"), ".._.._boot.ini");
+            assert_eq!(sanitize_component("a\0b\nc"), "a_b_c");
+            assert_eq!(sanitize_component("AUX"), "_AUX");
+            assert_eq!(sanitize_component("lpt1.tar.gz"), "_lpt1.tar.gz");
+            assert_eq!(sanitize_component("COM0"), "_COM0");
+            assert_eq!(sanitize_component("conout$.txt"), "_conout$.txt");
+            assert_eq!(sanitize_component("name. "), "name__");
+        }
+
+        {
+            // Long names are shortened deterministically, keeping the extension
+            let long_a = format!("{}.iso", "a".repeat(300));
+            let long_b = format!("{}b.iso", "a".repeat(300));
+            let sanitized_a = sanitize_component(&long_a);
+            let sanitized_b = sanitize_component(&long_b);
+            assert_eq!(sanitized_a.len(), MAX_COMPONENT_LEN);
+            assert!(sanitized_a.ends_with(".iso"));
+            assert_ne!(sanitized_a, sanitized_b);
+            assert_eq!(sanitized_a, sanitize_component(&long_a));
+            assert_eq!(validate_component(&sanitized_a), Ok(()));
+        }
+
+        {
+            // Multi-byte characters are not split when truncating
+            let long = "é".repeat(200);
+            let sanitized = sanitize_component(&long);
+            assert!(sanitized.len() <= MAX_COMPONENT_LEN);
+            assert_eq!(validate_component(&sanitized), Ok(()));
+        }
+    }
+
+    #[test]
+    fn policies() {
+        let components = ["dir", "..", "file"];
+        assert_eq!(
+            safe_path(&components, PathPolicy::Reject),
+            Err(UnsafePath::DotComponent("..".to_owned()))
+        );
+        assert_eq!(
+            safe_path(&components, PathPolicy::Sanitize),
+            Ok(PathBuf::from("dir/__/file"))
+        );
+        assert_eq!(
+            safe_path::<&str>(&[], PathPolicy::Sanitize),
+            Err(UnsafePath::Empty)
+        );
+    }
+
+    #[test]
+    fn collisions() {
+        // `a?` and `a*` both sanitize to `a_`, and `A_` is the same file on Windows
+        let paths = [["dir", "a?"], ["dir", "a*"], ["DIR", "A_"]]
+            .map(|components| safe_path(&components, PathPolicy::Sanitize).unwrap());
+        let mut set = PathSet::default();
+        let unique = paths
+            .iter()
+            .map(|path| set.insert(path.clone(), false, PathPolicy::Sanitize))
+            .collect::<Result<Vec<_>, _>>();
+        assert_eq!(
+            unique,
+            Ok(vec![
+                PathBuf::from("dir/a_"),
+                PathBuf::from("dir/a_~1"),
+                PathBuf::from("DIR/A_~2"),
+            ])
+        );
+
+        let mut set = PathSet::default();
+        assert!(set
+            .insert("a.txt".into(), false, PathPolicy::Reject)
+            .is_ok());
+        assert_eq!(
+            set.insert("A.TXT".into(), false, PathPolicy::Reject),
+            Err(UnsafePath::Collision(
+                "a.txt".to_owned(),
+                "A.TXT".to_owned()
+            ))
+        );
+
+        {
+            // Padding files may share a path with each other, but not with other files
+            let mut set = PathSet::default();
+            assert!(set
+                .insert(".pad/4".into(), true, PathPolicy::Reject)
+                .is_ok());
+            assert!(set
+                .insert(".pad/4".into(), true, PathPolicy::Reject)
+                .is_ok());
+            assert!(set
+                .insert(".pad/4".into(), false, PathPolicy::Reject)
+                .is_err());
+            assert_eq!(
+                set.insert("b.iso".into(), false, PathPolicy::Sanitize),
+                Ok(PathBuf::from("b.iso"))
+            );
+            assert_eq!(
+                set.insert("B.iso".into(), true, PathPolicy::Sanitize),
+                Ok(PathBuf::from("B~1.iso"))
+            );
+        }
+    }
+}
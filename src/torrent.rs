@@ -1,21 +1,148 @@
 use anyhow::{Context, Result};
 use sha1::{Digest, Sha1};
-use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
 
-use crate::bencode::{BencodeByteString, BencodeValue};
+use crate::{
+    bencode::{BencodeByteString, BencodeValue},
+    sanitize::{self, PathPolicy, PathSet, UnsafePath},
+};
 
 #[derive(Debug)]
 pub struct Torrent {
-    pub announce: reqwest::Url,
+    /// `None` for trackerless torrents, whose peers come from the DHT.
+    pub announce: Option<reqwest::Url>,
+    /// Tiers of tracker URLs from `announce-list` (BEP 12), as written in the file.
+    pub announce_list: Vec<Vec<String>>,
+    /// Web seed URLs from `url-list` (BEP 19).
+    pub url_list: Vec<String>,
+    /// HTTP seed script URLs from `httpseeds` (BEP 17).
+    pub http_seeds: Vec<String>,
+    /// DHT nodes to bootstrap from (BEP 5), as host and port.
+    pub nodes: Vec<(String, u16)>,
     pub info: TorrentInfo,
+    /// The bencoded info dictionary exactly as it appears in the torrent file.
+    pub info_bytes: Vec<u8>,
 }
 
 #[derive(Debug)]
 pub struct TorrentInfo {
+    /// Total length of all files.
     pub length: usize,
-    pub name: String,
+    pub name: TorrentName,
     pub piece_length: usize,
     pub pieces: Vec<u8>,
+    /// The files of a multi-file torrent, or `None` for a single-file torrent.
+    pub files: Option<Vec<TorrentFile>>,
+    /// `meta version` from BEP 52, which is 2 for hybrid v1/v2 torrents.
+    pub meta_version: Option<i64>,
+    /// Set by `private` (BEP 27): peers must only come from the torrent's trackers.
+    pub private: bool,
+}
+
+/// The part of a file that a range of torrent data covers.
+#[derive(Debug, PartialEq, Eq)]
+pub struct FileSegment {
+    /// Index into `TorrentInfo::files`, or 0 for a single-file torrent.
+    pub file_index: usize,
+    pub offset: usize,
+    pub length: usize,
+}
+
+#[derive(Debug)]
+pub struct TorrentFile {
+    pub length: usize,
+    pub path: Vec<TorrentName>,
+    /// File attributes from BEP 47, e.g. `p` for padding files.
+    pub attr: Option<String>,
+}
+
+/// A name or path component from a torrent, kept as the raw bytes from the file along
+/// with a form that can be displayed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TorrentName {
+    pub bytes: Vec<u8>,
+    display: String,
+}
+
+/// Text encodings that torrents declare in their top-level `encoding` key and that we know
+/// how to decode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Encoding {
+    #[default]
+    Utf8,
+    Latin1,
+    Windows1252,
+}
+
+/// Characters for bytes 0x80 to 0x9F in Windows-1252, where it differs from Latin-1.
+/// Unassigned bytes fall back to the Latin-1 control characters.
+const WINDOWS_1252_HIGH: [char; 32] = [
+    '€', '\u{81}', '‚', 'ƒ', '„', '…', '†', '‡', 'ˆ', '‰', 'Š', '‹', 'Œ', '\u{8D}', 'Ž', '\u{8F}',
+    '\u{90}', '‘', '’', '“', '”', '•', '–', '—', '˜', '™', 'š', '›', 'œ', '\u{9D}', 'ž', 'Ÿ',
+];
+
+impl Encoding {
+    /// Look up an encoding by the label used in the `encoding` key, e.g. `UTF-8` or `CP1252`.
+    pub fn from_label(label: &str) -> Option<Self> {
+        let label = label.trim().to_ascii_lowercase().replace('_', "-");
+        match label.as_str() {
+            "utf-8" | "utf8" => Some(Encoding::Utf8),
+            "iso-8859-1" | "iso8859-1" | "latin1" | "latin-1" | "l1" => Some(Encoding::Latin1),
+            "windows-1252" | "cp1252" => Some(Encoding::Windows1252),
+            _ => None,
+        }
+    }
+
+    /// Decode `bytes`, or return `None` if they are not valid in this encoding.
+    pub fn decode(&self, bytes: &[u8]) -> Option<String> {
+        match self {
+            Encoding::Utf8 => std::str::from_utf8(bytes).ok().map(str::to_owned),
+            Encoding::Latin1 => Some(bytes.iter().map(|b| *b as char).collect()),
+            Encoding::Windows1252 => Some(
+                bytes
+                    .iter()
+                    .map(|b| match b {
+                        0x80..=0x9F => WINDOWS_1252_HIGH[(b - 0x80) as usize],
+                        _ => *b as char,
+                    })
+                    .collect(),
+            ),
+        }
+    }
+}
+
+impl TorrentName {
+    /// Keep the raw `bytes`, preferring the `utf8` variant (from a `.utf-8` key) for display,
+    /// then `bytes` decoded with `encoding`, then a lossy UTF-8 decoding. Lossy decoding can
+    /// give distinct names the same display form, so paths made from it must be checked for
+    /// collisions, as `TorrentInfo::output_files` does.
+    pub fn new(bytes: &[u8], utf8: Option<&[u8]>, encoding: Encoding) -> Self {
+        let display = utf8
+            .and_then(|utf8| std::str::from_utf8(utf8).ok())
+            .map(str::to_owned)
+            .or_else(|| encoding.decode(bytes))
+            .unwrap_or_else(|| String::from_utf8_lossy(bytes).into_owned());
+        TorrentName {
+            bytes: bytes.to_vec(),
+            display,
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.display
+    }
+}
+
+impl std::fmt::Display for TorrentName {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.display)
+    }
+}
+
+impl AsRef<str> for TorrentName {
+    fn as_ref(&self) -> &str {
+        self.as_str()
+    }
 }
 
 impl Torrent {
@@ -25,79 +152,229 @@ impl Torrent {
 
         let announce = dict
             .get(&BencodeByteString(b"announce"))
+            .map(|value| {
+                value
+                    .as_byte_string()
+                    .and_then(|bs| std::str::from_utf8(bs.0).ok())
+                    .and_then(|s| reqwest::Url::parse(s).ok())
+                    .context("invalid announce field")
+            })
+            .transpose()?;
+        let announce_list = dict
+            .get(&BencodeByteString(b"announce-list"))
+            .and_then(BencodeValue::as_list)
+            .map(|tiers| {
+                tiers
+                    .iter()
+                    .filter_map(BencodeValue::as_list)
+                    .map(|tier| {
+                        tier.iter()
+                            .filter_map(BencodeValue::as_byte_string)
+                            .map(|bs| String::from_utf8_lossy(bs.0).into_owned())
+                            .collect::<Vec<_>>()
+                    })
+                    .filter(|tier| !tier.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default();
+        // `url-list` may be a single URL rather than a list
+        let url_list = match dict.get(&BencodeByteString(b"url-list")) {
+            Some(BencodeValue::ByteString(bs)) => vec![String::from_utf8_lossy(bs.0).into_owned()],
+            Some(BencodeValue::List(urls)) => urls
+                .iter()
+                .filter_map(BencodeValue::as_byte_string)
+                .map(|bs| String::from_utf8_lossy(bs.0).into_owned())
+                .collect(),
+            _ => Vec::new(),
+        };
+        let http_seeds = dict
+            .get(&BencodeByteString(b"httpseeds"))
+            .and_then(BencodeValue::as_list)
+            .map(|urls| {
+                urls.iter()
+                    .filter_map(BencodeValue::as_byte_string)
+                    .map(|bs| String::from_utf8_lossy(bs.0).into_owned())
+                    .collect()
+            })
+            .unwrap_or_default();
+        let nodes = dict
+            .get(&BencodeByteString(b"nodes"))
+            .and_then(BencodeValue::as_list)
+            .map(|nodes| {
+                nodes
+                    .iter()
+                    .filter_map(|node| match node.as_list()? {
+                        [host, port] => Some((
+                            String::from_utf8_lossy(host.as_byte_string()?.0).into_owned(),
+                            u16::try_from(*port.as_integer()?).ok()?,
+                        )),
+                        _ => None,
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        let encoding = dict
+            .get(&BencodeByteString(b"encoding"))
             .and_then(BencodeValue::as_byte_string)
             .and_then(|bs| std::str::from_utf8(bs.0).ok())
-            .and_then(|s| reqwest::Url::parse(s).ok())
-            .context("missing or invalid announce field")?;
+            .and_then(Encoding::from_label)
+            .unwrap_or_default();
 
         let info = dict
             .get(&BencodeByteString(b"info"))
             .and_then(BencodeValue::as_dictionary)
             .context("missing or invalid info field")?;
-        let length = info
-            .get(&BencodeByteString(b"length"))
-            .and_then(BencodeValue::as_integer)
-            .and_then(|n| usize::try_from(*n).ok())
-            .context("missing or invalid length field")?;
+        let info_bytes = BencodeValue::dictionary_entry_bytes(input, b"info")?
+            .context("missing or invalid info field")?
+            .to_vec();
         let name = info
             .get(&BencodeByteString(b"name"))
             .and_then(BencodeValue::as_byte_string)
-            .and_then(|bs| std::str::from_utf8(bs.0).ok())
-            .context("missing or invalid name field")?
-            .to_owned();
+            .map(|bs| {
+                let utf8 = info
+                    .get(&BencodeByteString(b"name.utf-8"))
+                    .and_then(BencodeValue::as_byte_string)
+                    .map(|bs| bs.0);
+                TorrentName::new(bs.0, utf8, encoding)
+            })
+            .context("missing or invalid name field")?;
         let piece_length = info
             .get(&BencodeByteString(b"piece length"))
             .and_then(BencodeValue::as_integer)
             .and_then(|n| usize::try_from(*n).ok())
+            .filter(|n| *n > 0)
             .context("missing or invalid piece length field")?;
         let pieces = info
             .get(&BencodeByteString(b"pieces"))
             .and_then(BencodeValue::as_byte_string)
             .map(|bs| bs.0.to_vec())
             .context("missing or invalid pieces field")?;
-        if pieces.len() % 20 != 0 {
+        let meta_version = info
+            .get(&BencodeByteString(b"meta version"))
+            .and_then(BencodeValue::as_integer)
+            .copied();
+        let private = info
+            .get(&BencodeByteString(b"private"))
+            .and_then(BencodeValue::as_integer)
+            == Some(&1);
+        if !pieces.len().is_multiple_of(20) {
             anyhow::bail!("invalid pieces field");
         }
 
+        let (length, files) = match info.get(&BencodeByteString(b"files")) {
+            Some(files) => {
+                let files = files
+                    .as_list()
+                    .context("invalid files field")?
+                    .iter()
+                    .map(|file| parse_file(file, encoding))
+                    .collect::<Result<Vec<_>>>()?;
+                (files.iter().map(|f| f.length).sum(), Some(files))
+            }
+            None => {
+                let length = info
+                    .get(&BencodeByteString(b"length"))
+                    .and_then(BencodeValue::as_integer)
+                    .and_then(|n| usize::try_from(*n).ok())
+                    .context("missing or invalid length field")?;
+                (length, None)
+            }
+        };
+
         Ok(Torrent {
             announce,
+            announce_list,
+            url_list,
+            http_seeds,
+            nodes,
             info: TorrentInfo {
                 length,
                 name,
                 piece_length,
                 pieces,
+                files,
+                meta_version,
+                private,
             },
+            info_bytes,
         })
     }
 
     pub fn info_hash(&self) -> String {
-        let info_bencode = BencodeValue::Dictionary(
-            [
-                (
-                    BencodeByteString(b"length"),
-                    BencodeValue::Integer(self.info.length as i64),
-                ),
-                (
-                    BencodeByteString(b"name"),
-                    BencodeValue::ByteString(BencodeByteString(self.info.name.as_bytes())),
-                ),
-                (
-                    BencodeByteString(b"piece length"),
-                    BencodeValue::Integer(self.info.piece_length as i64),
-                ),
-                (
-                    BencodeByteString(b"pieces"),
-                    BencodeValue::ByteString(BencodeByteString(&self.info.pieces)),
-                ),
-            ]
-            .into_iter()
-            .collect::<BTreeMap<_, _>>(),
-        );
+        hex::encode(self.info_hash_bytes())
+    }
 
+    pub fn info_hash_bytes(&self) -> [u8; 20] {
         let mut hasher = Sha1::new();
-        hasher.update(info_bencode.to_bytes());
-        let result = hasher.finalize();
-        hex::encode(result)
+        hasher.update(&self.info_bytes);
+        hasher.finalize().into()
+    }
+
+    /// The torrent's trackers as BEP 12 tiers. `announce-list` takes precedence over
+    /// `announce` when present.
+    pub fn tracker_tiers(&self) -> Vec<Vec<String>> {
+        if self.announce_list.is_empty() {
+            self.announce
+                .iter()
+                .map(|url| vec![url.to_string()])
+                .collect()
+        } else {
+            self.announce_list.clone()
+        }
+    }
+}
+
+fn parse_file(value: &BencodeValue, encoding: Encoding) -> Result<TorrentFile> {
+    let file = value.as_dictionary().context("invalid file entry")?;
+    let length = file
+        .get(&BencodeByteString(b"length"))
+        .and_then(BencodeValue::as_integer)
+        .and_then(|n| usize::try_from(*n).ok())
+        .context("missing or invalid file length field")?;
+    let path = file
+        .get(&BencodeByteString(b"path"))
+        .and_then(BencodeValue::as_list)
+        .and_then(|components| {
+            components
+                .iter()
+                .map(|c| c.as_byte_string().map(|bs| bs.0))
+                .collect::<Option<Vec<_>>>()
+        })
+        .context("missing or invalid file path field")?;
+    // Only use `path.utf-8` if it lines up component for component with `path`
+    let utf8_path = file
+        .get(&BencodeByteString(b"path.utf-8"))
+        .and_then(BencodeValue::as_list)
+        .and_then(|components| {
+            components
+                .iter()
+                .map(|c| c.as_byte_string().map(|bs| bs.0))
+                .collect::<Option<Vec<_>>>()
+        })
+        .filter(|utf8_path| utf8_path.len() == path.len());
+    let attr = file
+        .get(&BencodeByteString(b"attr"))
+        .and_then(BencodeValue::as_byte_string)
+        .map(|bs| String::from_utf8_lossy(bs.0).into_owned());
+
+    Ok(TorrentFile {
+        length,
+        path: path
+            .iter()
+            .enumerate()
+            .map(|(i, component)| {
+                let utf8 = utf8_path.as_ref().map(|utf8_path| utf8_path[i]);
+                TorrentName::new(component, utf8, encoding)
+            })
+            .collect(),
+        attr,
+    })
+}
+
+impl TorrentFile {
+    /// Whether this is a BEP 47 padding file, which only exists to align the next file.
+    pub fn is_padding(&self) -> bool {
+        self.attr.as_deref().is_some_and(|attr| attr.contains('p'))
     }
 }
 
@@ -114,4 +391,261 @@ impl TorrentInfo {
     pub fn piece_count(&self) -> usize {
         self.pieces.len() / 20
     }
+
+    /// Length of the piece at `index`, which is shorter than `piece_length` for the last piece.
+    pub fn piece_len(&self, index: usize) -> usize {
+        if index == self.piece_count() - 1 && !self.length.is_multiple_of(self.piece_length) {
+            self.length % self.piece_length
+        } else {
+            self.piece_length
+        }
+    }
+
+    /// Whether `data` matches the hash of the piece at `index`.
+    pub fn verify_piece(&self, index: usize, data: &[u8]) -> bool {
+        let Some(expected) = self.pieces.chunks_exact(20).nth(index) else {
+            return false;
+        };
+        let mut hasher = Sha1::new();
+        hasher.update(data);
+        hasher.finalize().as_slice() == expected
+    }
+
+    /// The file segments that the `length` bytes of torrent data at `offset` are stored in,
+    /// in order.
+    pub fn file_segments(&self, offset: usize, length: usize) -> Vec<FileSegment> {
+        let file_lengths = match &self.files {
+            Some(files) => files.iter().map(|f| f.length).collect(),
+            None => vec![self.length],
+        };
+
+        let mut segments = Vec::new();
+        let end = offset + length;
+        let mut file_start = 0;
+        for (file_index, file_length) in file_lengths.into_iter().enumerate() {
+            let file_end = file_start + file_length;
+            let start = offset.max(file_start);
+            let stop = end.min(file_end);
+            if start < stop {
+                segments.push(FileSegment {
+                    file_index,
+                    offset: start - file_start,
+                    length: stop - start,
+                });
+            }
+            file_start = file_end;
+        }
+        segments
+    }
+
+    /// Whether this is a hybrid torrent that also carries BEP 52 (v2) metadata.
+    pub fn is_hybrid(&self) -> bool {
+        self.meta_version == Some(2)
+    }
+
+    /// Check that `name` and every file path are safe to use on the local filesystem, and
+    /// that no two files would overwrite each other.
+    pub fn validate_paths(&self) -> Result<(), UnsafePath> {
+        sanitize::validate_component(self.name.as_str())?;
+        let mut paths = PathSet::default();
+        for file in self.files.iter().flatten() {
+            sanitize::validate_path(&file.path)?;
+            let path = file.path.iter().map(TorrentName::as_str).collect();
+            paths.insert(path, file.is_padding(), PathPolicy::Reject)?;
+        }
+        Ok(())
+    }
+
+    /// Where to write the torrent's content inside `dir`, with `name` checked or renamed
+    /// according to `policy`. This is a file for single-file torrents and the root directory
+    /// of a multi-file torrent.
+    pub fn output_path(&self, dir: &Path, policy: PathPolicy) -> Result<PathBuf, UnsafePath> {
+        Ok(dir.join(sanitize::safe_path(&[&self.name], policy)?))
+    }
+
+    /// The files to write and their lengths, in the order their data appears in the pieces,
+    /// with paths checked or renamed according to `policy`, including files whose paths end
+    /// up the same. For a single-file torrent this is just `output_path`, otherwise the files
+    /// are placed under `output_path`.
+    pub fn output_files(
+        &self,
+        output_path: &Path,
+        policy: PathPolicy,
+    ) -> Result<Vec<(PathBuf, usize)>, UnsafePath> {
+        match &self.files {
+            None => Ok(vec![(output_path.to_owned(), self.length)]),
+            Some(files) => {
+                let mut paths = PathSet::default();
+                files
+                    .iter()
+                    .map(|file| {
+                        let path = sanitize::safe_path(&file.path, policy)?;
+                        let path = paths.insert(path, file.is_padding(), policy)?;
+                        Ok((output_path.join(path), file.length))
+                    })
+                    .collect()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Encoding, FileSegment, Torrent};
+    use crate::sanitize::PathPolicy;
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn parse_sample() {
+        let input = std::fs::read("sample.torrent").unwrap();
+        let torrent = Torrent::from_bytes(&input).unwrap();
+        assert_eq!(torrent.info.name.as_str(), "sample.txt");
+        assert_eq!(torrent.info.length, 92063);
+        assert!(torrent.info.files.is_none());
+        assert_eq!(
+            torrent.info_hash(),
+            "d69f91e6b2ae4c542468d1073a71d4ea13879a7f"
+        );
+    }
+
+    #[test]
+    fn trackerless() {
+        let input = b"d4:infod6:lengthi1e4:name1:a12:piece lengthi1e6:pieces20:aaaaaaaaaaaaaaaaaaaa7:privatei1ee5:nodesll9:127.0.0.1i6881eel3:::1i6882eel1:xeee";
+        let torrent = Torrent::from_bytes(input).unwrap();
+        assert!(torrent.announce.is_none());
+        assert!(torrent.tracker_tiers().is_empty());
+        assert!(torrent.info.private);
+        assert_eq!(
+            torrent.nodes,
+            [("127.0.0.1".to_owned(), 6881), ("::1".to_owned(), 6882)]
+        );
+    }
+
+    #[test]
+    fn parse_names() {
+        {
+            // Non UTF-8 name without any hints is kept as raw bytes
+            let input = b"d8:announce14:http://a.b/ann4:infod6:lengthi1e4:name3:\xE9t\xE912:piece lengthi1e6:pieces20:aaaaaaaaaaaaaaaaaaaaee";
+            let torrent = Torrent::from_bytes(input).unwrap();
+            assert_eq!(torrent.info.name.bytes, b"\xE9t\xE9");
+            assert_eq!(torrent.info.name.as_str(), "\u{FFFD}t\u{FFFD}");
+        }
+
+        {
+            // The top-level encoding is honoured
+            let input = b"d8:announce14:http://a.b/ann8:encoding10:ISO-8859-14:infod6:lengthi1e4:name3:\xE9t\xE912:piece lengthi1e6:pieces20:aaaaaaaaaaaaaaaaaaaaee";
+            let torrent = Torrent::from_bytes(input).unwrap();
+            assert_eq!(torrent.info.name.bytes, b"\xE9t\xE9");
+            assert_eq!(torrent.info.name.as_str(), "été");
+        }
+
+        {
+            // name.utf-8 is preferred
+            let input = b"d8:announce14:http://a.b/ann8:encoding3:GBK4:infod6:lengthi1e4:name3:\xE9t\xE910:name.utf-85:\xC3\xA9t\xC3\xA912:piece lengthi1e6:pieces20:aaaaaaaaaaaaaaaaaaaaee";
+            let torrent = Torrent::from_bytes(input).unwrap();
+            assert_eq!(torrent.info.name.bytes, b"\xE9t\xE9");
+            assert_eq!(torrent.info.name.as_str(), "été");
+        }
+    }
+
+    #[test]
+    fn parse_files() {
+        let input = b"d8:announce14:http://a.b/ann4:infod5:filesld6:lengthi3e4:pathl3:dir1:\x80e10:path.utf-8l3:dir3:\xE2\x82\xACeed6:lengthi4e4:pathl2:..eee4:name4:root12:piece lengthi4e6:pieces40:aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaee";
+        let torrent = Torrent::from_bytes(input).unwrap();
+        assert_eq!(torrent.info.length, 7);
+        let files = torrent.info.files.as_ref().unwrap();
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0].path[1].bytes, b"\x80");
+        assert_eq!(files[0].path[1].as_str(), "€");
+        assert!(torrent.info.validate_paths().is_err());
+        assert_eq!(
+            torrent
+                .info
+                .output_files(Path::new("out"), PathPolicy::Sanitize)
+                .unwrap(),
+            vec![
+                (PathBuf::from("out/dir/€"), 3),
+                (PathBuf::from("out/__"), 4)
+            ]
+        );
+
+        {
+            // Names that only differ in bytes lost to decoding still get files of their own
+            let input = b"d8:announce14:http://a.b/ann4:infod5:filesld6:lengthi1e4:pathl1:\xFFeed6:lengthi1e4:pathl1:\xFEeee4:name4:root12:piece lengthi2e6:pieces20:aaaaaaaaaaaaaaaaaaaaee";
+            let torrent = Torrent::from_bytes(input).unwrap();
+            let files = torrent.info.files.as_ref().unwrap();
+            assert_ne!(files[0].path[0].bytes, files[1].path[0].bytes);
+            assert_eq!(files[0].path[0].as_str(), files[1].path[0].as_str());
+            assert!(torrent.info.validate_paths().is_err());
+            assert_eq!(
+                torrent
+                    .info
+                    .output_files(Path::new("out"), PathPolicy::Sanitize)
+                    .unwrap(),
+                vec![
+                    (PathBuf::from("out/\u{FFFD}"), 1),
+                    (PathBuf::from("out/\u{FFFD}~1"), 1)
+                ]
+            );
+            assert!(torrent
+                .info
+                .output_files(Path::new("out"), PathPolicy::Reject)
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn pieces() {
+        let input = std::fs::read("sample.torrent").unwrap();
+        let torrent = Torrent::from_bytes(&input).unwrap();
+        assert_eq!(torrent.info.piece_len(0), 32768);
+        assert_eq!(torrent.info.piece_len(2), 92063 - 2 * 32768);
+        assert!(!torrent.info.verify_piece(0, b"not the piece"));
+        assert!(!torrent.info.verify_piece(3, b""));
+
+        let input =
+            b"d4:infod6:lengthi1e4:name1:a12:piece lengthi0e6:pieces20:aaaaaaaaaaaaaaaaaaaaee";
+        assert!(Torrent::from_bytes(input).is_err());
+    }
+
+    #[test]
+    fn file_segments() {
+        let input = b"d8:announce14:http://a.b/ann4:infod5:filesld6:lengthi3e4:pathl1:aeed6:lengthi0e4:pathl1:beed6:lengthi5e4:pathl1:ceee4:name4:root12:piece lengthi4e6:pieces40:aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaee";
+        let torrent = Torrent::from_bytes(input).unwrap();
+        assert_eq!(
+            torrent.info.file_segments(0, 4),
+            vec![
+                FileSegment {
+                    file_index: 0,
+                    offset: 0,
+                    length: 3
+                },
+                FileSegment {
+                    file_index: 2,
+                    offset: 0,
+                    length: 1
+                },
+            ]
+        );
+        assert_eq!(
+            torrent.info.file_segments(4, 4),
+            vec![FileSegment {
+                file_index: 2,
+                offset: 1,
+                length: 4
+            }]
+        );
+    }
+
+    #[test]
+    fn encodings() {
+        assert_eq!(Encoding::from_label("UTF-8"), Some(Encoding::Utf8));
+        assert_eq!(Encoding::from_label("cp1252"), Some(Encoding::Windows1252));
+        assert_eq!(Encoding::from_label("Shift_JIS"), None);
+        assert_eq!(
+            Encoding::Windows1252.decode(b"\x93hi\x94"),
+            Some("“hi”".to_owned())
+        );
+        assert_eq!(Encoding::Utf8.decode(b"\xFF"), None);
+    }
 }
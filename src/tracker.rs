@@ -1,80 +1,555 @@
 use anyhow::{Context, Result};
-use serde::Serialize;
-use std::net::{Ipv4Addr, SocketAddrV4};
+use rand::seq::SliceRandom;
+use std::{
+    collections::{HashMap, HashSet},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use thiserror::Error;
 
-use crate::{bencode::BencodeValue, torrent::Torrent, PEER_ID};
+use crate::{connections, identity::Identity, torrent::Torrent};
 
-const PORT: u16 = 6881;
+mod announcer;
+mod http;
+mod server;
+mod udp;
 
-#[derive(Debug, Serialize)]
-struct Request {
-    peer_id: String,
-    port: u16,
-    uploaded: usize,
-    downloaded: usize,
-    left: usize,
-    compact: u8,
+pub use announcer::Announcer;
+pub use server::{ServerConfig, TrackerServer};
+
+/// How long to wait for an HTTP tracker to accept the connection.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long to wait for a whole HTTP announce, from connecting to reading the response.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long one tracker in a tier gets to answer, resolving its host included, before the
+/// next one is tried.
+const ATTEMPT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// When a tracker that refused an announce wants us to try again (BEP 31).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Retry {
+    In(Duration),
+    Never,
+}
+
+impl std::fmt::Display for Retry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Retry::In(delay) => write!(f, "retry in {} minutes", delay.as_secs() / 60),
+            Retry::Never => write!(f, "do not retry"),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum TrackerError {
+    /// The tracker answered, but refused the announce or scrape.
+    #[error("tracker failure: {reason}{}", retry.map(|retry| format!(" ({})", retry)).unwrap_or_default())]
+    Failure {
+        reason: String,
+        retry: Option<Retry>,
+    },
+}
+
+/// Why we are announcing, when it is not just a regular update.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// The first announce of a download.
+    Started,
+    /// The download finished. Not sent if we already had the whole torrent when starting.
+    Completed,
+    /// We are shutting down.
+    Stopped,
 }
 
-impl Request {
-    pub fn new(size: usize) -> Self {
-        Request {
-            peer_id: PEER_ID.to_owned(),
-            port: PORT,
+impl Event {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Event::Started => "started",
+            Event::Completed => "completed",
+            Event::Stopped => "stopped",
+        }
+    }
+}
+
+/// What we tell a tracker when announcing.
+#[derive(Debug)]
+pub struct Announce {
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+    pub key: u32,
+    pub port: u16,
+    pub numwant: Option<u32>,
+    /// Our address, if configured. Trackers normally use the one we connect from.
+    pub ip: Option<IpAddr>,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+    pub event: Option<Event>,
+    /// The `tracker id` from an earlier response, which must be sent back.
+    pub tracker_id: Option<Vec<u8>>,
+    /// Our public addresses (BEP 7), so that a tracker reached over one family can hand out
+    /// our address in the other.
+    pub ipv4: Option<Ipv4Addr>,
+    pub ipv6: Option<Ipv6Addr>,
+}
+
+impl Announce {
+    pub fn new(torrent: &Torrent, identity: &Identity) -> Self {
+        Announce {
+            info_hash: torrent.info_hash_bytes(),
+            peer_id: identity.peer_id,
+            key: identity.key,
+            port: identity.port,
+            numwant: identity.numwant,
+            ip: identity.ip,
             uploaded: 0,
             downloaded: 0,
-            left: size,
-            compact: 1,
+            left: torrent.info.length as u64,
+            event: None,
+            tracker_id: None,
+            ipv4: connections::local_ipv4().filter(|ip| connections::is_public((*ip).into())),
+            ipv6: connections::local_ipv6().filter(|ip| connections::is_public((*ip).into())),
         }
     }
 }
 
-fn parse_peers(input: &[u8]) -> Result<Vec<SocketAddrV4>> {
-    if input.len() % 6 != 0 {
-        anyhow::bail!("invalid peers list");
+/// What a tracker tells us in reply to an announce.
+#[derive(Debug, Default)]
+pub struct AnnounceResponse {
+    /// How long to wait before announcing again.
+    pub interval: Option<Duration>,
+    /// We must not announce more often than this.
+    pub min_interval: Option<Duration>,
+    pub tracker_id: Option<Vec<u8>>,
+    /// Something the tracker wants the user to know, even though the announce went through.
+    pub warning: Option<String>,
+    /// The size of the swarm, if the tracker says.
+    pub seeders: Option<u64>,
+    pub leechers: Option<u64>,
+    pub peers: Vec<Peer>,
+}
+
+/// Swarm statistics for one torrent, from a scrape.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScrapeStats {
+    pub seeders: u64,
+    pub leechers: u64,
+    /// How many times the torrent has been downloaded completely.
+    pub downloaded: u64,
+}
+
+/// A peer handed out by a tracker.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Peer {
+    pub addr: SocketAddr,
+    /// Only known from non-compact peer lists. The peer should send the same ID in its
+    /// handshake.
+    pub peer_id: Option<[u8; 20]>,
+}
+
+impl From<SocketAddr> for Peer {
+    fn from(addr: SocketAddr) -> Self {
+        Peer {
+            addr,
+            peer_id: None,
+        }
+    }
+}
+
+impl AnnounceResponse {
+    /// Add the results of announcing to another tracker. Peers we already have are skipped.
+    fn merge(&mut self, other: AnnounceResponse) {
+        self.interval = match (self.interval, other.interval) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        self.min_interval = self.min_interval.max(other.min_interval);
+        self.tracker_id = self.tracker_id.take().or(other.tracker_id);
+        self.warning = self.warning.take().or(other.warning);
+        self.seeders = self.seeders.max(other.seeders);
+        self.leechers = self.leechers.max(other.leechers);
+        let mut seen = self
+            .peers
+            .iter()
+            .map(|peer| peer.addr)
+            .collect::<HashSet<_>>();
+        self.peers.extend(
+            other
+                .peers
+                .into_iter()
+                .filter(|peer| seen.insert(peer.addr)),
+        );
     }
+}
+
+/// A torrent's trackers, grouped in BEP 12 tiers. Each tier is shuffled once, and a tracker
+/// that answers is moved to the front of its tier so that it is tried first next time.
+pub struct Tiers {
+    tiers: Vec<Vec<reqwest::Url>>,
+}
+
+impl Tiers {
+    pub fn new(torrent: &Torrent) -> Self {
+        let mut rng = rand::thread_rng();
+        let tiers = torrent
+            .tracker_tiers()
+            .iter()
+            .map(|tier| {
+                let mut tier = tier
+                    .iter()
+                    .filter_map(|url| match reqwest::Url::parse(url) {
+                        Ok(url) => Some(url),
+                        Err(e) => {
+                            eprintln!("warning: skipping tracker {}: {}", url, e);
+                            None
+                        }
+                    })
+                    .collect::<Vec<_>>();
+                tier.shuffle(&mut rng);
+                tier
+            })
+            .filter(|tier| !tier.is_empty())
+            .collect();
+        Tiers { tiers }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tiers.is_empty()
+    }
+}
+
+/// Parse a compact peer list: 4 bytes of IPv4 address and 2 bytes of port per peer.
+pub fn parse_peers(input: &[u8]) -> Result<Vec<Peer>> {
+    parse_compact_peers(input, 4)
+}
+
+/// Parse a compact IPv6 peer list (BEP 7): 16 bytes of address and 2 bytes of port per peer.
+pub fn parse_peers6(input: &[u8]) -> Result<Vec<Peer>> {
+    parse_compact_peers(input, 16)
+}
 
-    let mut peers = Vec::new();
-    for i in (0..input.len()).step_by(6) {
-        let ip_addr = Ipv4Addr::new(input[i], input[i + 1], input[i + 2], input[i + 3]);
-        let port = u16::from_be_bytes([input[i + 4], input[i + 5]]);
-        peers.push(SocketAddrV4::new(ip_addr, port));
+fn parse_compact_peers(input: &[u8], ip_len: usize) -> Result<Vec<Peer>> {
+    if !input.len().is_multiple_of(ip_len + 2) {
+        anyhow::bail!("invalid peers list");
     }
-    Ok(peers)
+
+    Ok(input
+        .chunks_exact(ip_len + 2)
+        .map(|peer| {
+            let (ip, port) = peer.split_at(ip_len);
+            let ip = match <[u8; 4]>::try_from(ip) {
+                Ok(ip) => IpAddr::from(ip),
+                Err(_) => IpAddr::from(<[u8; 16]>::try_from(ip).unwrap()),
+            };
+            SocketAddr::new(ip, u16::from_be_bytes([port[0], port[1]])).into()
+        })
+        .collect())
 }
 
-pub fn get_peers(torrent: &Torrent) -> Result<Vec<SocketAddrV4>> {
-    let request_params = Request::new(torrent.info.length);
-    let info_hash = torrent.info_hash();
-    let mut url_encoded_info_hash = String::new();
-    for i in 0..20 {
-        url_encoded_info_hash.push('%');
-        url_encoded_info_hash.push(info_hash.chars().nth(2 * i).unwrap());
-        url_encoded_info_hash.push(info_hash.chars().nth(2 * i + 1).unwrap());
+/// Client for HTTP(S) and UDP trackers, picked by the scheme of the announce URL.
+pub struct TrackerClient {
+    identity: Identity,
+    http: reqwest::Client,
+    /// UDP trackers we have talked to, keyed by URL, so that their connection IDs are reused.
+    udp: Mutex<HashMap<String, Arc<tokio::sync::Mutex<udp::UdpTracker>>>>,
+    attempt_timeout: Duration,
+}
+
+impl TrackerClient {
+    pub fn new(identity: &Identity) -> Result<Self> {
+        let http = reqwest::Client::builder()
+            .connect_timeout(CONNECT_TIMEOUT)
+            .timeout(REQUEST_TIMEOUT)
+            .gzip(true)
+            .user_agent(&identity.user_agent)
+            .build()?;
+        Ok(TrackerClient {
+            identity: identity.clone(),
+            http,
+            udp: Mutex::new(HashMap::new()),
+            attempt_timeout: ATTEMPT_TIMEOUT,
+        })
+    }
+
+    /// Announce to the tracker at `url`.
+    pub async fn announce(
+        &self,
+        url: &reqwest::Url,
+        announce: &Announce,
+    ) -> Result<AnnounceResponse> {
+        let response = match url.scheme() {
+            "http" | "https" => http::announce(&self.http, url, announce).await?,
+            "udp" => {
+                let tracker = self.udp_tracker(url).await?;
+                let mut tracker = tracker.lock().await;
+                tracker.announce(announce).await?
+            }
+            scheme => anyhow::bail!("unsupported tracker scheme {:?}", scheme),
+        };
+        if let Some(warning) = &response.warning {
+            eprintln!("warning: tracker {} says: {}", url, warning);
+        }
+        Ok(response)
+    }
+
+    /// Get the statistics of the torrents with the given info hashes from the tracker at
+    /// `url`, in as few requests as possible. HTTP trackers leave out torrents they don't
+    /// know.
+    pub async fn scrape(
+        &self,
+        url: &reqwest::Url,
+        info_hashes: &[[u8; 20]],
+    ) -> Result<HashMap<[u8; 20], ScrapeStats>> {
+        match url.scheme() {
+            "http" | "https" => http::scrape(&self.http, url, info_hashes).await,
+            "udp" => {
+                let tracker = self.udp_tracker(url).await?;
+                let mut tracker = tracker.lock().await;
+                let stats = tracker.scrape(info_hashes).await?;
+                Ok(info_hashes.iter().copied().zip(stats).collect())
+            }
+            scheme => anyhow::bail!("unsupported tracker scheme {:?}", scheme),
+        }
+    }
+
+    async fn udp_tracker(
+        &self,
+        url: &reqwest::Url,
+    ) -> Result<Arc<tokio::sync::Mutex<udp::UdpTracker>>> {
+        if let Some(tracker) = self.udp.lock().unwrap().get(url.as_str()) {
+            return Ok(tracker.clone());
+        }
+        let tracker = Arc::new(tokio::sync::Mutex::new(udp::UdpTracker::new(url).await?));
+        Ok(self
+            .udp
+            .lock()
+            .unwrap()
+            .entry(url.as_str().to_owned())
+            .or_insert(tracker)
+            .clone())
+    }
+
+    /// Announce to the trackers of one tier in order, stopping at the first that answers.
+    async fn announce_tier(
+        &self,
+        tier: &mut Vec<reqwest::Url>,
+        announce: &Announce,
+    ) -> Result<AnnounceResponse> {
+        let mut last_error = None;
+        for i in 0..tier.len() {
+            let attempt =
+                tokio::time::timeout(self.attempt_timeout, self.announce(&tier[i], announce));
+            let result = match attempt.await {
+                Ok(result) => result,
+                Err(_) => Err(anyhow::anyhow!(
+                    "no answer within {} seconds",
+                    self.attempt_timeout.as_secs_f64()
+                )),
+            };
+            match result {
+                Ok(response) => {
+                    let url = tier.remove(i);
+                    tier.insert(0, url);
+                    return Ok(response);
+                }
+                Err(e) => last_error = Some(e.context(format!("announce to {} failed", tier[i]))),
+            }
+        }
+        Err(last_error.context("no trackers to announce to")?)
     }
 
-    let client = reqwest::blocking::Client::new();
-    let url = format!(
-        "{}?info_hash={}&{}",
-        torrent.announce,
-        url_encoded_info_hash,
-        serde_urlencoded::to_string(&request_params)?
-    );
-    let request = client.get(url);
+    /// Announce following BEP 12: tiers are tried in order until a tracker answers. With
+    /// `all_tiers`, every tier is announced to at once and the responses are merged.
+    pub async fn announce_tiers(
+        &self,
+        tiers: &mut Tiers,
+        announce: &Announce,
+        all_tiers: bool,
+    ) -> Result<AnnounceResponse> {
+        let mut last_error = None;
+        if all_tiers {
+            let results = futures::future::join_all(
+                tiers
+                    .tiers
+                    .iter_mut()
+                    .map(|tier| self.announce_tier(tier, announce)),
+            )
+            .await;
+            let mut merged: Option<AnnounceResponse> = None;
+            for result in results {
+                match (result, &mut merged) {
+                    (Ok(response), Some(merged)) => merged.merge(response),
+                    (Ok(response), None) => merged = Some(response),
+                    (Err(e), _) => last_error = Some(e),
+                }
+            }
+            if let Some(merged) = merged {
+                return Ok(merged);
+            }
+        } else {
+            for tier in tiers.tiers.iter_mut() {
+                match self.announce_tier(tier, announce).await {
+                    Ok(response) => return Ok(response),
+                    Err(e) => last_error = Some(e),
+                }
+            }
+        }
+        Err(last_error.context("torrent has no usable trackers")?)
+    }
 
-    let response = request.send()?;
-    if !response.status().is_success() {
-        anyhow::bail!("peer request failed: {:?}", response.text());
+    /// Announce ourselves to the torrent's trackers once.
+    pub async fn announce_once(
+        &self,
+        torrent: &Torrent,
+        all_tiers: bool,
+    ) -> Result<AnnounceResponse> {
+        self.announce_tiers(
+            &mut Tiers::new(torrent),
+            &Announce::new(torrent, &self.identity),
+            all_tiers,
+        )
+        .await
     }
-    let response_body = response.bytes()?;
-    let (_, response_data) = BencodeValue::from_bytes(&response_body)?;
-    for (key, value) in response_data.as_dictionary().context("invalid response")? {
-        if std::str::from_utf8(key.0) == Ok("peers") {
-            return parse_peers(value.as_byte_string().context("invalid response")?.0);
+}
+
+/// Announce ourselves to the torrent's trackers and return what they say, peers included.
+pub async fn announce(
+    torrent: &Torrent,
+    identity: &Identity,
+    all_tiers: bool,
+) -> Result<AnnounceResponse> {
+    TrackerClient::new(identity)?
+        .announce_once(torrent, all_tiers)
+        .await
+}
+
+/// Announce ourselves to the torrent's trackers and return the peers they know about.
+pub async fn get_peers(
+    torrent: &Torrent,
+    identity: &Identity,
+    all_tiers: bool,
+) -> Result<Vec<Peer>> {
+    Ok(announce(torrent, identity, all_tiers).await?.peers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Announce, Peer, Tiers, TrackerClient};
+    use crate::{
+        http::testing::{self, Response},
+        identity::Identity,
+        torrent::Torrent,
+    };
+    use std::{
+        net::{Ipv4Addr, SocketAddr},
+        time::{Duration, Instant},
+    };
+
+    /// A tracker that answers every announce with `peers`, or fails if there are none.
+    async fn tracker(peers: Option<&'static [u8]>) -> reqwest::Url {
+        let addr = testing::serve(move |_| match peers {
+            Some(peers) => {
+                let mut body = format!("d8:intervali60e5:peers{}:", peers.len()).into_bytes();
+                body.extend(peers);
+                body.extend(b"e");
+                Response::new(200, body)
+            }
+            None => Response::new(500, ""),
+        })
+        .await;
+        reqwest::Url::parse(&format!("http://{}/announce", addr)).unwrap()
+    }
+
+    fn peer(port: u16) -> Peer {
+        SocketAddr::from((Ipv4Addr::LOCALHOST, port)).into()
+    }
+
+    #[tokio::test]
+    async fn tiers() {
+        let mut input =
+            b"d8:announce14:http://a.b/ann4:infod6:lengthi10e4:name1:a12:piece lengthi10e6:pieces20:"
+                .to_vec();
+        input.extend([0; 20]);
+        input.extend(b"ee");
+        let identity = Identity::default();
+        let announce = Announce::new(&Torrent::from_bytes(&input).unwrap(), &identity);
+        let client = TrackerClient::new(&identity).unwrap();
+
+        let failing = tracker(None).await;
+        let first = tracker(Some(&[127, 0, 0, 1, 0, 1, 127, 0, 0, 1, 0, 2])).await;
+        let second = tracker(Some(&[127, 0, 0, 1, 0, 2, 127, 0, 0, 1, 0, 3])).await;
+
+        {
+            // A tracker that answers moves to the front of its tier
+            let mut tiers = Tiers {
+                tiers: vec![vec![failing.clone(), first.clone()], vec![second.clone()]],
+            };
+            let response = client
+                .announce_tiers(&mut tiers, &announce, false)
+                .await
+                .unwrap();
+            assert_eq!(response.peers, vec![peer(1), peer(2)]);
+            assert_eq!(tiers.tiers[0], vec![first.clone(), failing.clone()]);
+        }
+
+        {
+            // A tier where every tracker fails falls through to the next
+            let mut tiers = Tiers {
+                tiers: vec![vec![failing.clone()], vec![second.clone()]],
+            };
+            let response = client
+                .announce_tiers(&mut tiers, &announce, false)
+                .await
+                .unwrap();
+            assert_eq!(response.peers, vec![peer(2), peer(3)]);
+        }
+
+        {
+            // Announcing to all tiers merges the peers
+            let mut tiers = Tiers {
+                tiers: vec![
+                    vec![first.clone()],
+                    vec![failing.clone()],
+                    vec![second.clone()],
+                ],
+            };
+            let response = client
+                .announce_tiers(&mut tiers, &announce, true)
+                .await
+                .unwrap();
+            assert_eq!(response.peers, vec![peer(1), peer(2), peer(3)]);
+        }
+
+        {
+            // A tracker that never answers is given up on, so failover still happens
+            let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let silent =
+                reqwest::Url::parse(&format!("udp://{}", socket.local_addr().unwrap())).unwrap();
+            let mut client = TrackerClient::new(&identity).unwrap();
+            client.attempt_timeout = Duration::from_millis(100);
+            let mut tiers = Tiers {
+                tiers: vec![vec![silent.clone(), first.clone()], vec![second.clone()]],
+            };
+            let started = Instant::now();
+            let response = client
+                .announce_tiers(&mut tiers, &announce, false)
+                .await
+                .unwrap();
+            assert!(started.elapsed() < Duration::from_secs(5));
+            assert_eq!(response.peers, vec![peer(1), peer(2)]);
+        }
+
+        {
+            // Every tracker failing is an error
+            let mut tiers = Tiers {
+                tiers: vec![vec![failing.clone()]],
+            };
+            assert!(client
+                .announce_tiers(&mut tiers, &announce, true)
+                .await
+                .is_err());
         }
     }
-    Err(anyhow::format_err!(
-        "no peers field found in response: {}",
-        response_data
-    ))
 }
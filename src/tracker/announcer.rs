@@ -0,0 +1,285 @@
+use anyhow::Result;
+use std::{sync::Arc, time::Duration};
+use tokio::{
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+};
+
+use super::{Announce, AnnounceResponse, Event, Peer, Retry, Tiers, TrackerClient, TrackerError};
+use crate::{download::Stats, identity::Identity, torrent::Torrent};
+
+/// How long to wait between announces when the tracker doesn't say.
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(30 * 60);
+/// How long to wait after a failed announce. Each further failure doubles it.
+const RETRY_BASE: Duration = Duration::from_secs(15);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30 * 60);
+
+/// Announces one torrent to its trackers over the course of a download, reporting the
+/// download's progress each time.
+pub struct Announcer {
+    client: TrackerClient,
+    tiers: Tiers,
+    announce: Announce,
+    all_tiers: bool,
+    /// How long to wait before the next regular announce, from the last response.
+    interval: Duration,
+    retry_base: Duration,
+    /// Whether a tracker took our `started` event.
+    started: bool,
+}
+
+impl Announcer {
+    pub fn new(torrent: &Torrent, identity: &Identity, all_tiers: bool) -> Result<Self> {
+        Ok(Announcer {
+            client: TrackerClient::new(identity)?,
+            tiers: Tiers::new(torrent),
+            announce: Announce::new(torrent, identity),
+            all_tiers,
+            interval: DEFAULT_INTERVAL,
+            retry_base: RETRY_BASE,
+            started: false,
+        })
+    }
+
+    /// Whether the torrent has any trackers to announce to.
+    pub fn has_trackers(&self) -> bool {
+        !self.tiers.is_empty()
+    }
+
+    /// Whether a tracker knows about us, so that the final announces are worth making.
+    pub fn started(&self) -> bool {
+        self.started
+    }
+
+    pub async fn announce(
+        &mut self,
+        event: Option<Event>,
+        stats: &Stats,
+    ) -> Result<AnnounceResponse> {
+        self.announce.event = event;
+        self.announce.uploaded = stats.uploaded();
+        self.announce.downloaded = stats.downloaded();
+        self.announce.left = stats.left();
+        let response = self
+            .client
+            .announce_tiers(&mut self.tiers, &self.announce, self.all_tiers)
+            .await?;
+
+        if event == Some(Event::Started) {
+            self.started = true;
+        }
+        if let Some(tracker_id) = &response.tracker_id {
+            self.announce.tracker_id = Some(tracker_id.clone());
+        }
+        self.interval = response
+            .interval
+            .unwrap_or(DEFAULT_INTERVAL)
+            .max(response.min_interval.unwrap_or_default());
+        Ok(response)
+    }
+
+    /// Keep announcing in the background as often as the trackers ask, sending the peers
+    /// from every response to `peers`. Failed announces are retried with exponential backoff,
+    /// and if the `started` announce failed too, it is retried first.
+    pub fn spawn(mut self, stats: Arc<Stats>, peers: mpsc::UnboundedSender<Peer>) -> Reannouncer {
+        let (stop, mut stopped) = oneshot::channel();
+        let task = tokio::spawn(async move {
+            let mut failures = u32::from(!self.started);
+            let mut retry_in = None;
+            loop {
+                let delay = match failures {
+                    0 => self.interval,
+                    n => retry_in.take().unwrap_or_else(|| {
+                        (self.retry_base * 2u32.pow((n - 1).min(16))).min(MAX_RETRY_DELAY)
+                    }),
+                };
+                tokio::select! {
+                    _ = &mut stopped => break,
+                    _ = tokio::time::sleep(delay) => {}
+                }
+
+                let event = (!self.started).then_some(Event::Started);
+                let result = tokio::select! {
+                    _ = &mut stopped => break,
+                    result = self.announce(event, &stats) => result,
+                };
+                match result {
+                    Ok(response) => {
+                        failures = 0;
+                        for peer in response.peers {
+                            // The download may already be over
+                            let _ = peers.send(peer);
+                        }
+                    }
+                    Err(e) => {
+                        failures += 1;
+                        eprintln!("warning: re-announce failed: {:#}", e);
+                        // Honour the tracker's retry hint over our own backoff
+                        match e.downcast_ref::<TrackerError>() {
+                            Some(TrackerError::Failure {
+                                retry: Some(Retry::Never),
+                                ..
+                            }) => break,
+                            Some(TrackerError::Failure {
+                                retry: Some(Retry::In(delay)),
+                                ..
+                            }) => retry_in = Some(*delay),
+                            _ => {}
+                        }
+                    }
+                }
+            }
+            self
+        });
+        Reannouncer { stop, task }
+    }
+}
+
+/// An [`Announcer`] running in the background.
+pub struct Reannouncer {
+    stop: oneshot::Sender<()>,
+    task: JoinHandle<Announcer>,
+}
+
+impl Reannouncer {
+    /// Stop announcing and hand the announcer back, for the final announces.
+    pub async fn stop(self) -> Result<Announcer> {
+        let _ = self.stop.send(());
+        Ok(self.task.await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Announcer;
+    use crate::{
+        download::Stats,
+        http::testing::{self, Response},
+        identity::Identity,
+        torrent::Torrent,
+        tracker::Event,
+    };
+    use std::{
+        net::{Ipv4Addr, SocketAddr},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+    use tokio::sync::mpsc;
+
+    fn torrent(announce: &str) -> Torrent {
+        let mut input = format!(
+            "d8:announce{}:{}4:infod6:lengthi10e4:name1:a12:piece lengthi10e6:pieces20:",
+            announce.len(),
+            announce
+        )
+        .into_bytes();
+        input.extend([0; 20]);
+        input.extend(b"ee");
+        Torrent::from_bytes(&input).unwrap()
+    }
+
+    #[tokio::test]
+    async fn reannounce() {
+        // Succeed, fail once, then succeed with a new peer, checking that the tracker id is
+        // sent back
+        let requests = Arc::new(AtomicUsize::new(0));
+        let addr = {
+            let requests = requests.clone();
+            testing::serve(
+                move |request| match requests.fetch_add(1, Ordering::SeqCst) {
+                    0 => {
+                        assert!(request.target.contains("&event=started"));
+                        assert!(!request.target.contains("trackerid"));
+                        Response::new(
+                            200,
+                            b"d8:intervali1e12:min intervali0e10:tracker id3:abc5:peers0:e"
+                                .to_vec(),
+                        )
+                    }
+                    1 => Response::new(500, ""),
+                    _ => {
+                        assert!(!request.target.contains("&event="));
+                        assert!(request.target.contains("&trackerid=abc"));
+                        let mut body = b"d8:intervali60e5:peers6:".to_vec();
+                        body.extend([127, 0, 0, 1, 0x1A, 0xE1]);
+                        body.extend(b"e");
+                        Response::new(200, body)
+                    }
+                },
+            )
+            .await
+        };
+
+        let torrent = torrent(&format!("http://{}/announce", addr));
+        let stats = Arc::new(Stats::new(&torrent));
+        let mut announcer = Announcer::new(&torrent, &Identity::default(), false).unwrap();
+        announcer.retry_base = Duration::from_millis(10);
+        let response = announcer
+            .announce(Some(Event::Started), &stats)
+            .await
+            .unwrap();
+        assert!(response.peers.is_empty());
+        assert_eq!(announcer.interval, Duration::from_secs(1));
+
+        let (peers_tx, mut peers_rx) = mpsc::unbounded_channel();
+        let reannouncer = announcer.spawn(stats, peers_tx);
+        let peer = tokio::time::timeout(Duration::from_secs(5), peers_rx.recv())
+            .await
+            .unwrap();
+        assert_eq!(
+            peer,
+            Some(SocketAddr::from((Ipv4Addr::LOCALHOST, 6881)).into())
+        );
+
+        let announcer = reannouncer.stop().await.unwrap();
+        assert_eq!(announcer.interval, Duration::from_secs(60));
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn failed_start() {
+        // The tracker is down for the first announce, and the started event is sent again
+        // once it is back
+        let requests = Arc::new(AtomicUsize::new(0));
+        let addr = {
+            let requests = requests.clone();
+            testing::serve(move |request| {
+                assert!(request.target.contains("&event=started"));
+                match requests.fetch_add(1, Ordering::SeqCst) {
+                    0 => Response::new(500, ""),
+                    _ => {
+                        let mut body = b"d8:intervali60e5:peers6:".to_vec();
+                        body.extend([127, 0, 0, 1, 0x1A, 0xE1]);
+                        body.extend(b"e");
+                        Response::new(200, body)
+                    }
+                }
+            })
+            .await
+        };
+
+        let torrent = torrent(&format!("http://{}/announce", addr));
+        let stats = Arc::new(Stats::new(&torrent));
+        let mut announcer = Announcer::new(&torrent, &Identity::default(), false).unwrap();
+        announcer.retry_base = Duration::from_millis(10);
+        assert!(announcer.has_trackers());
+        assert!(announcer
+            .announce(Some(Event::Started), &stats)
+            .await
+            .is_err());
+        assert!(!announcer.started());
+
+        let (peers_tx, mut peers_rx) = mpsc::unbounded_channel();
+        let reannouncer = announcer.spawn(stats, peers_tx);
+        let peer = tokio::time::timeout(Duration::from_secs(5), peers_rx.recv())
+            .await
+            .unwrap();
+        assert!(peer.is_some());
+        let announcer = reannouncer.stop().await.unwrap();
+        assert!(announcer.started());
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+}
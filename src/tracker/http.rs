@@ -0,0 +1,618 @@
+use anyhow::{Context, Result};
+use futures::StreamExt;
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use super::{
+    parse_peers, parse_peers6, Announce, AnnounceResponse, Peer, Retry, ScrapeStats, TrackerError,
+};
+use crate::{
+    bencode::{BencodeByteString, BencodeValue},
+    http::percent_encode,
+};
+
+/// The most entries of a non-compact peer list we use; trackers send far fewer when honest.
+const MAX_LISTED_PEERS: usize = 200;
+/// How many hostnames of listed peers we look up at once.
+const CONCURRENT_LOOKUPS: usize = 8;
+/// How long to wait for one hostname to resolve before skipping the peer.
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Build the announce URL, adding our parameters to any query the tracker URL already has
+/// (private trackers often put a passkey there). The info hash and peer ID are
+/// percent-encoded byte by byte, since they are raw binary rather than text.
+fn announce_url(announce_url: &reqwest::Url, announce: &Announce) -> reqwest::Url {
+    let mut query = match announce_url.query() {
+        Some(query) if !query.is_empty() => format!("{}&", query),
+        _ => String::new(),
+    };
+    query.push_str(&format!(
+        "info_hash={}&peer_id={}&port={}&uploaded={}&downloaded={}&left={}&compact=1&key={:08X}",
+        percent_encode(&announce.info_hash),
+        percent_encode(&announce.peer_id),
+        announce.port,
+        announce.uploaded,
+        announce.downloaded,
+        announce.left,
+        announce.key,
+    ));
+    if let Some(numwant) = announce.numwant {
+        query.push_str(&format!("&numwant={}", numwant));
+    }
+    if let Some(ip) = announce.ip {
+        query.push_str(&format!(
+            "&ip={}",
+            percent_encode(ip.to_string().as_bytes())
+        ));
+    }
+    if let Some(event) = announce.event {
+        query.push_str(&format!("&event={}", event.as_str()));
+    }
+    if let Some(tracker_id) = &announce.tracker_id {
+        query.push_str(&format!("&trackerid={}", percent_encode(tracker_id)));
+    }
+    if let Some(ipv4) = announce.ipv4 {
+        query.push_str(&format!("&ipv4={}", ipv4));
+    }
+    if let Some(ipv6) = announce.ipv6 {
+        query.push_str(&format!(
+            "&ipv6={}",
+            percent_encode(ipv6.to_string().as_bytes())
+        ));
+    }
+
+    let mut url = announce_url.clone();
+    url.set_query(Some(&query));
+    url
+}
+
+pub async fn announce(
+    client: &reqwest::Client,
+    url: &reqwest::Url,
+    announce: &Announce,
+) -> Result<AnnounceResponse> {
+    let response = client.get(announce_url(url, announce)).send().await?;
+    let status = response.status();
+    let body = response.bytes().await?;
+    // Some trackers send their failure reason with an error status
+    match parse_response(&body).await {
+        Ok(response) if status.is_success() => Ok(response),
+        Err(e) if e.is::<TrackerError>() => Err(e),
+        _ if !status.is_success() => anyhow::bail!("tracker responded with {}", status),
+        result => result,
+    }
+}
+
+async fn parse_response(body: &[u8]) -> Result<AnnounceResponse> {
+    let (_, value) = BencodeValue::from_bytes(body).context("invalid tracker response")?;
+    let dict = value.as_dictionary().context("invalid tracker response")?;
+    let string = |key: &[u8]| {
+        dict.get(&BencodeByteString(key))
+            .and_then(BencodeValue::as_byte_string)
+            .map(|bs| String::from_utf8_lossy(bs.0).into_owned())
+    };
+    let integer = |key: &[u8]| {
+        dict.get(&BencodeByteString(key))
+            .and_then(BencodeValue::as_integer)
+            .and_then(|n| u64::try_from(*n).ok())
+    };
+
+    if let Some(failure) = failure(dict) {
+        return Err(failure.into());
+    }
+
+    // Trackers may ignore `compact=1` and send a list of dictionaries instead
+    let peers6 = dict.get(&BencodeByteString(b"peers6"));
+    let mut peers = match dict.get(&BencodeByteString(b"peers")) {
+        Some(BencodeValue::ByteString(peers)) => parse_peers(peers.0)?,
+        Some(BencodeValue::List(peers)) => {
+            futures::stream::iter(
+                peers
+                    .iter()
+                    .filter_map(PeerEntry::parse)
+                    .take(MAX_LISTED_PEERS)
+                    .map(PeerEntry::resolve),
+            )
+            .buffered(CONCURRENT_LOOKUPS)
+            .filter_map(|peer| async move { peer })
+            .collect()
+            .await
+        }
+        Some(_) => anyhow::bail!("invalid peers list"),
+        None if peers6.is_some() => Vec::new(),
+        None => anyhow::bail!("tracker response has no peers"),
+    };
+    if let Some(peers6) = peers6 {
+        let peers6 = peers6.as_byte_string().context("invalid peers6 list")?;
+        peers.extend(parse_peers6(peers6.0)?);
+    }
+    Ok(AnnounceResponse {
+        interval: integer(b"interval").map(Duration::from_secs),
+        min_interval: integer(b"min interval").map(Duration::from_secs),
+        tracker_id: dict
+            .get(&BencodeByteString(b"tracker id"))
+            .and_then(BencodeValue::as_byte_string)
+            .map(|bs| bs.0.to_vec()),
+        warning: string(b"warning message"),
+        seeders: integer(b"complete"),
+        leechers: integer(b"incomplete"),
+        peers,
+    })
+}
+
+/// The failure reason of a response, if the tracker refused the request.
+fn failure(dict: &BTreeMap<BencodeByteString, BencodeValue>) -> Option<TrackerError> {
+    let reason = dict
+        .get(&BencodeByteString(b"failure reason"))
+        .and_then(BencodeValue::as_byte_string)
+        .map(|bs| String::from_utf8_lossy(bs.0).into_owned())?;
+    let retry = match dict.get(&BencodeByteString(b"retry in")) {
+        Some(BencodeValue::Integer(minutes)) => u64::try_from(*minutes)
+            .ok()
+            .map(|minutes| Retry::In(Duration::from_secs(minutes * 60))),
+        Some(BencodeValue::ByteString(BencodeByteString(b"never"))) => Some(Retry::Never),
+        _ => None,
+    };
+    Some(TrackerError::Failure { reason, retry })
+}
+
+/// The scrape URL for a tracker (BEP 48): the last path component of the announce URL must
+/// start with `announce`, which is replaced with `scrape`. Each info hash is a separate
+/// `info_hash` parameter.
+fn scrape_url(announce_url: &reqwest::Url, info_hashes: &[[u8; 20]]) -> Result<reqwest::Url> {
+    let path = announce_url.path();
+    let (dir, last) = path.rsplit_once('/').unwrap_or(("", path));
+    let Some(rest) = last.strip_prefix("announce") else {
+        anyhow::bail!("tracker {} does not support scraping", announce_url);
+    };
+
+    let mut query = match announce_url.query() {
+        Some(query) if !query.is_empty() => format!("{}&", query),
+        _ => String::new(),
+    };
+    query.push_str(
+        &info_hashes
+            .iter()
+            .map(|info_hash| format!("info_hash={}", percent_encode(info_hash)))
+            .collect::<Vec<_>>()
+            .join("&"),
+    );
+
+    let mut url = announce_url.clone();
+    url.set_path(&format!("{}/scrape{}", dir, rest));
+    url.set_query(Some(&query));
+    Ok(url)
+}
+
+pub async fn scrape(
+    client: &reqwest::Client,
+    url: &reqwest::Url,
+    info_hashes: &[[u8; 20]],
+) -> Result<HashMap<[u8; 20], ScrapeStats>> {
+    let response = client.get(scrape_url(url, info_hashes)?).send().await?;
+    let status = response.status();
+    let body = response.bytes().await?;
+    match parse_scrape_response(&body) {
+        Ok(stats) if status.is_success() => Ok(stats),
+        Err(e) if e.is::<TrackerError>() => Err(e),
+        _ if !status.is_success() => anyhow::bail!("tracker responded with {}", status),
+        result => result,
+    }
+}
+
+fn parse_scrape_response(body: &[u8]) -> Result<HashMap<[u8; 20], ScrapeStats>> {
+    let (_, value) = BencodeValue::from_bytes(body).context("invalid scrape response")?;
+    let dict = value.as_dictionary().context("invalid scrape response")?;
+    if let Some(failure) = failure(dict) {
+        return Err(failure.into());
+    }
+
+    let files = dict
+        .get(&BencodeByteString(b"files"))
+        .and_then(BencodeValue::as_dictionary)
+        .context("scrape response has no files")?;
+    let mut stats = HashMap::new();
+    for (info_hash, file) in files {
+        let Ok(info_hash) = <[u8; 20]>::try_from(info_hash.0) else {
+            continue;
+        };
+        let file = file.as_dictionary().context("invalid scrape response")?;
+        let integer = |key: &[u8]| {
+            file.get(&BencodeByteString(key))
+                .and_then(BencodeValue::as_integer)
+                .and_then(|n| u64::try_from(*n).ok())
+                .unwrap_or(0)
+        };
+        stats.insert(
+            info_hash,
+            ScrapeStats {
+                seeders: integer(b"complete"),
+                leechers: integer(b"incomplete"),
+                downloaded: integer(b"downloaded"),
+            },
+        );
+    }
+    Ok(stats)
+}
+
+/// An entry of a non-compact peer list.
+struct PeerEntry {
+    /// An IP address or a hostname.
+    ip: String,
+    port: u16,
+    peer_id: Option<[u8; 20]>,
+}
+
+impl PeerEntry {
+    /// Returns `None` for malformed entries, which are skipped.
+    fn parse(value: &BencodeValue) -> Option<Self> {
+        let dict = value.as_dictionary()?;
+        let ip = dict
+            .get(&BencodeByteString(b"ip"))
+            .and_then(BencodeValue::as_byte_string)
+            .and_then(|bs| std::str::from_utf8(bs.0).ok())?;
+        let port = dict
+            .get(&BencodeByteString(b"port"))
+            .and_then(BencodeValue::as_integer)
+            .and_then(|n| u16::try_from(*n).ok())?;
+        let peer_id = dict
+            .get(&BencodeByteString(b"peer id"))
+            .and_then(BencodeValue::as_byte_string)
+            .and_then(|bs| bs.0.try_into().ok());
+        Some(PeerEntry {
+            ip: ip.to_owned(),
+            port,
+            peer_id,
+        })
+    }
+
+    /// Look up the address of the peer, if `ip` is a hostname. Peers that don't resolve in
+    /// time are skipped.
+    async fn resolve(self) -> Option<Peer> {
+        let addr = match self.ip.parse::<IpAddr>() {
+            Ok(ip) => SocketAddr::new(ip, self.port),
+            Err(_) => tokio::time::timeout(
+                LOOKUP_TIMEOUT,
+                tokio::net::lookup_host((self.ip.as_str(), self.port)),
+            )
+            .await
+            .ok()?
+            .ok()?
+            .next()?,
+        };
+        Some(Peer {
+            addr,
+            peer_id: self.peer_id,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{announce_url, parse_response, scrape_url, MAX_LISTED_PEERS};
+    use crate::{
+        http::testing::{self, Response},
+        identity::Identity,
+        torrent::Torrent,
+        tracker::{
+            get_peers, Announce, Event, Peer, Retry, ScrapeStats, TrackerClient, TrackerError,
+        },
+    };
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    /// `d8:intervali60e5:peers6:<127.0.0.1:6881>e`, gzipped.
+    const GZIPPED_RESPONSE: &[u8] = &[
+        0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0x4b, 0xb1, 0xb0, 0xca, 0xcc,
+        0x2b, 0x49, 0x2d, 0x2a, 0x4b, 0xcc, 0xc9, 0x34, 0x33, 0x48, 0x35, 0xb5, 0x2a, 0x48, 0x4d,
+        0x2d, 0x2a, 0x36, 0xb3, 0xaa, 0x67, 0x60, 0x60, 0x94, 0x7a, 0x98, 0x0a, 0x00, 0xe7, 0xf7,
+        0x3b, 0x2f, 0x1f, 0x00, 0x00, 0x00,
+    ];
+
+    fn torrent(announce: &str) -> Torrent {
+        let mut input = format!(
+            "d8:announce{}:{}4:infod6:lengthi10e4:name1:a12:piece lengthi10e6:pieces20:",
+            announce.len(),
+            announce
+        )
+        .into_bytes();
+        input.extend([0; 20]);
+        input.extend(b"ee");
+        Torrent::from_bytes(&input).unwrap()
+    }
+
+    #[test]
+    fn query_merging() {
+        let identity = Identity::default();
+        let mut announce = Announce::new(&torrent("http://tracker.example/announce"), &identity);
+        announce.info_hash = [0xAB; 20];
+        announce.peer_id = *b"-XX0100-0123456789ab";
+        announce.key = 0x1A2B;
+        announce.ipv4 = None;
+        announce.ipv6 = None;
+
+        {
+            // No existing query
+            let url = reqwest::Url::parse("http://tracker.example/announce").unwrap();
+            let url = announce_url(&url, &announce);
+            assert_eq!(
+                url.query().unwrap(),
+                format!(
+                    "info_hash={}&peer_id=-XX0100-0123456789ab&port=6881&uploaded=0&downloaded=0&left=10&compact=1&key=00001A2B",
+                    "%AB".repeat(20)
+                )
+            );
+        }
+
+        {
+            // Configured identity
+            let identity = Identity {
+                port: 51413,
+                numwant: Some(100),
+                ip: Some("2001:db8::9".parse().unwrap()),
+                ..Identity::default()
+            };
+            let announce = Announce::new(&torrent("http://tracker.example/announce"), &identity);
+            let url = reqwest::Url::parse("http://tracker.example/announce").unwrap();
+            let query = announce_url(&url, &announce).query().unwrap().to_owned();
+            assert!(query.contains("&port=51413&"));
+            assert!(query.contains(&format!(
+                "&key={:08X}&numwant=100&ip=2001%3Adb8%3A%3A9",
+                identity.key
+            )));
+        }
+
+        {
+            // Events are sent by name
+            let mut announce =
+                Announce::new(&torrent("http://tracker.example/announce"), &identity);
+            announce.event = Some(Event::Completed);
+            let url = reqwest::Url::parse("http://tracker.example/announce").unwrap();
+            let url = announce_url(&url, &announce);
+            assert!(url.query().unwrap().contains("&event=completed"));
+        }
+
+        {
+            // Our public addresses
+            let mut announce =
+                Announce::new(&torrent("http://tracker.example/announce"), &identity);
+            announce.ipv4 = Some("203.0.113.7".parse().unwrap());
+            announce.ipv6 = Some("2001:db8::7".parse().unwrap());
+            let url = reqwest::Url::parse("http://tracker.example/announce").unwrap();
+            let url = announce_url(&url, &announce);
+            assert!(url
+                .query()
+                .unwrap()
+                .ends_with("&ipv4=203.0.113.7&ipv6=2001%3Adb8%3A%3A7"));
+        }
+
+        {
+            // Passkey in the query
+            let url =
+                reqwest::Url::parse("https://tracker.example/announce?passkey=s3cret").unwrap();
+            let url = announce_url(&url, &announce);
+            assert!(url
+                .as_str()
+                .starts_with("https://tracker.example/announce?passkey=s3cret&info_hash=%AB%AB"));
+        }
+    }
+
+    #[test]
+    fn scrape_urls() {
+        for (announce, scrape) in [
+            (
+                "http://example.com/announce",
+                Some("http://example.com/scrape?info_hash=%01%01"),
+            ),
+            (
+                "http://example.com/x/announce.php?key=1",
+                Some("http://example.com/x/scrape.php?key=1&info_hash=%01%01"),
+            ),
+            (
+                "http://example.com/announce?x2%0644",
+                Some("http://example.com/scrape?x2%0644&info_hash=%01%01"),
+            ),
+            ("http://example.com/a", None),
+            ("http://example.com/announce/x", None),
+            ("http://example.com/x%064announce", None),
+        ] {
+            let announce = reqwest::Url::parse(announce).unwrap();
+            let url = scrape_url(&announce, &[[1; 20]]).ok();
+            let url = url.map(|url| url.as_str().replacen(&"%01".repeat(20), "%01%01", 1));
+            assert_eq!(url.as_deref(), scrape, "{}", announce);
+        }
+    }
+
+    #[tokio::test]
+    async fn scrape() {
+        let addr = testing::serve(|request| {
+            assert_eq!(
+                request.target,
+                format!(
+                    "/scrape?info_hash={}&info_hash={}",
+                    "%01".repeat(20),
+                    "%02".repeat(20)
+                )
+            );
+            let mut body = b"d5:filesd20:".to_vec();
+            body.extend([1; 20]);
+            body.extend(b"d8:completei5e10:downloadedi50e10:incompletei10eeee");
+            Response::new(200, body)
+        })
+        .await;
+
+        let url = reqwest::Url::parse(&format!("http://{}/announce", addr)).unwrap();
+        let stats = TrackerClient::new(&Identity::default())
+            .unwrap()
+            .scrape(&url, &[[1; 20], [2; 20]])
+            .await
+            .unwrap();
+        assert_eq!(stats.len(), 1);
+        assert_eq!(
+            stats[&[1; 20]],
+            ScrapeStats {
+                seeders: 5,
+                leechers: 10,
+                downloaded: 50
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn responses() {
+        {
+            // A warning doesn't stop the announce
+            let response =
+                parse_response(b"d8:intervali60e5:peers0:15:warning message10:slow down!e")
+                    .await
+                    .unwrap();
+            assert_eq!(response.warning.as_deref(), Some("slow down!"));
+            assert_eq!(response.interval, Some(std::time::Duration::from_secs(60)));
+        }
+
+        {
+            // A failure reason is a typed error
+            let error = parse_response(b"d14:failure reason20:unregistered torrente")
+                .await
+                .unwrap_err();
+            assert!(matches!(
+                error.downcast_ref::<TrackerError>(),
+                Some(TrackerError::Failure { reason, retry: None }) if reason == "unregistered torrent"
+            ));
+            assert_eq!(error.to_string(), "tracker failure: unregistered torrent");
+        }
+
+        {
+            // Retry hints
+            let error = parse_response(b"d14:failure reason15:passkey invalid8:retry ini5ee")
+                .await
+                .unwrap_err();
+            assert_eq!(
+                error.to_string(),
+                "tracker failure: passkey invalid (retry in 5 minutes)"
+            );
+            let error = parse_response(b"d14:failure reason6:banned8:retry in5:nevere")
+                .await
+                .unwrap_err();
+            assert!(matches!(
+                error.downcast_ref::<TrackerError>(),
+                Some(TrackerError::Failure {
+                    retry: Some(Retry::Never),
+                    ..
+                })
+            ));
+            assert_eq!(error.to_string(), "tracker failure: banned (do not retry)");
+        }
+    }
+
+    #[tokio::test]
+    async fn peer_lists() {
+        {
+            // Dictionaries, with and without peer IDs, with a hostname and a malformed entry
+            let response = parse_response(
+                b"d5:peersld2:ip9:127.0.0.17:peer id20:-XX0100-0123456789ab4:porti6881eed2:ip9:localhost4:porti6882eed2:ip9:127.0.0.14:porti99999eeee",
+            )
+            .await
+            .unwrap();
+            assert_eq!(response.peers.len(), 2);
+            assert_eq!(
+                response.peers[0],
+                Peer {
+                    addr: SocketAddr::from((Ipv4Addr::LOCALHOST, 6881)),
+                    peer_id: Some(*b"-XX0100-0123456789ab"),
+                }
+            );
+            // `localhost` may resolve to either family
+            assert!(response.peers[1].addr.ip().is_loopback());
+            assert_eq!(response.peers[1].addr.port(), 6882);
+            assert_eq!(response.peers[1].peer_id, None);
+        }
+
+        {
+            // IPv6 peers, in the dictionary form and in `peers6`
+            let mut body = b"d5:peersld2:ip3:::14:porti1eee6:peers618:".to_vec();
+            body.extend([0x20, 0x01, 0x0d, 0xb8]);
+            body.extend([0; 11]);
+            body.extend([1, 0x1A, 0xE1]);
+            body.extend(b"e");
+            let response = parse_response(&body).await.unwrap();
+            assert_eq!(
+                response.peers,
+                vec![
+                    Peer::from("[::1]:1".parse::<SocketAddr>().unwrap()),
+                    Peer::from("[2001:db8::1]:6881".parse::<SocketAddr>().unwrap()),
+                ]
+            );
+
+            // `peers6` on its own
+            let response = parse_response(b"d6:peers60:e").await.unwrap();
+            assert!(response.peers.is_empty());
+            assert!(parse_response(b"d6:peers67:1234567e").await.is_err());
+        }
+
+        {
+            // Compact peers have no peer IDs
+            let response = parse_response(b"d5:peers6:\x7f\x00\x00\x01\x1a\xe1e")
+                .await
+                .unwrap();
+            assert_eq!(response.peers[0].peer_id, None);
+        }
+
+        {
+            // Only so many listed peers are used
+            let mut body = b"d5:peersl".to_vec();
+            for port in 1..=MAX_LISTED_PEERS + 1 {
+                body.extend(format!("d2:ip9:127.0.0.14:porti{}ee", port).as_bytes());
+            }
+            body.extend(b"ee");
+            let response = parse_response(&body).await.unwrap();
+            assert_eq!(response.peers.len(), MAX_LISTED_PEERS);
+            assert_eq!(response.peers[0].addr.port(), 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn gzipped_response() {
+        let addr = testing::serve(|request| {
+            assert!(request
+                .header("accept-encoding")
+                .is_some_and(|v| v.contains("gzip")));
+            let mut response = Response::new(200, GZIPPED_RESPONSE);
+            response
+                .headers
+                .push(("Content-Encoding".to_owned(), "gzip".to_owned()));
+            response
+        })
+        .await;
+
+        let identity = Identity::default();
+        let peers = get_peers(
+            &torrent(&format!("http://{}/announce", addr)),
+            &identity,
+            false,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            peers,
+            vec![SocketAddr::from((Ipv4Addr::LOCALHOST, 6881)).into()]
+        );
+
+        {
+            // The failure reason is reported even with an error status
+            let addr =
+                testing::serve(|_| Response::new(403, "d14:failure reason15:passkey invalide"))
+                    .await;
+            let error = get_peers(
+                &torrent(&format!("http://{}/announce", addr)),
+                &identity,
+                false,
+            )
+            .await
+            .unwrap_err();
+            assert!(format!("{:#}", error).ends_with("tracker failure: passkey invalid"));
+        }
+    }
+}
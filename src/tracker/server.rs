@@ -0,0 +1,434 @@
+use anyhow::{Context, Result};
+use futures::FutureExt;
+use rand::seq::IteratorRandom;
+use std::{
+    collections::{HashMap, HashSet},
+    net::{IpAddr, SocketAddr, SocketAddrV4, SocketAddrV6},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::net::{TcpListener, UdpSocket};
+
+use super::{Event, Peer, ScrapeStats, TrackerError};
+
+mod http;
+mod udp;
+
+/// How many peers to hand out when the client doesn't say.
+const DEFAULT_NUMWANT: usize = 50;
+/// The most peers to hand out in one response, whatever the client asks for.
+const MAX_NUMWANT: usize = 200;
+
+pub struct ServerConfig {
+    /// How long clients should wait between announces.
+    pub interval: Duration,
+    /// Peers that haven't announced for this long are forgotten.
+    pub peer_timeout: Duration,
+    /// The only torrents to track, or `None` to track any torrent that is announced.
+    pub allowlist: Option<HashSet<[u8; 20]>>,
+    /// Whether peers may announce addresses other than the one they reach us from. Only
+    /// safe with trusted clients, as anyone could otherwise point the swarm at a third party.
+    pub trust_client_ips: bool,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            interval: Duration::from_secs(30 * 60),
+            peer_timeout: Duration::from_secs(60 * 60),
+            allowlist: None,
+            trust_client_ips: false,
+        }
+    }
+}
+
+/// An announce as the tracker sees it, whichever protocol it came in over.
+struct AnnounceRequest {
+    info_hash: [u8; 20],
+    peer_id: [u8; 20],
+    /// The address the announce came from. Peer IDs are handed out to other peers, so a
+    /// peer is only the same one if it also announces from the same address.
+    source: IpAddr,
+    /// The peer's address in each family it told us about or connected from.
+    ipv4: Option<SocketAddrV4>,
+    ipv6: Option<SocketAddrV6>,
+    /// Whether the peer wants addresses of each family.
+    ipv4_peers: bool,
+    ipv6_peers: bool,
+    left: u64,
+    event: Option<Event>,
+    numwant: Option<usize>,
+}
+
+struct AnnounceReply {
+    interval: Duration,
+    seeders: u64,
+    leechers: u64,
+    peers: Vec<Peer>,
+}
+
+struct SwarmPeer {
+    ipv4: Option<SocketAddrV4>,
+    ipv6: Option<SocketAddrV6>,
+    seeding: bool,
+    last_seen: Instant,
+}
+
+impl SwarmPeer {
+    fn peers(&self, peer_id: [u8; 20]) -> impl Iterator<Item = Peer> {
+        let ipv4 = self.ipv4.map(SocketAddr::V4);
+        let ipv6 = self.ipv6.map(SocketAddr::V6);
+        ipv4.into_iter().chain(ipv6).map(move |addr| Peer {
+            addr,
+            peer_id: Some(peer_id),
+        })
+    }
+}
+
+#[derive(Default)]
+struct Swarm {
+    /// Keyed by peer ID and the address the peer announces from.
+    peers: HashMap<([u8; 20], IpAddr), SwarmPeer>,
+    /// How many peers have announced that they completed the download.
+    completed: u64,
+}
+
+impl Swarm {
+    fn expire(&mut self, peer_timeout: Duration) {
+        self.peers
+            .retain(|_, peer| peer.last_seen.elapsed() < peer_timeout);
+    }
+
+    fn stats(&self) -> ScrapeStats {
+        let seeders = self.peers.values().filter(|peer| peer.seeding).count() as u64;
+        ScrapeStats {
+            seeders,
+            leechers: self.peers.len() as u64 - seeders,
+            downloaded: self.completed,
+        }
+    }
+}
+
+/// The peers of every torrent the tracker knows about, shared by all the ways of reaching it.
+struct Swarms {
+    config: ServerConfig,
+    swarms: Mutex<HashMap<[u8; 20], Swarm>>,
+    /// When every swarm was last checked for expired peers.
+    swept: Mutex<Instant>,
+}
+
+impl Swarms {
+    fn new(config: ServerConfig) -> Self {
+        Swarms {
+            config,
+            swarms: Mutex::new(HashMap::new()),
+            swept: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Whether a swarm with no peers left should be kept, for its statistics.
+    fn keep_empty(&self, info_hash: &[u8; 20]) -> bool {
+        self.config
+            .allowlist
+            .as_ref()
+            .is_some_and(|allowlist| allowlist.contains(info_hash))
+    }
+
+    /// Expire peers in every swarm once in a while, and forget the swarms left empty, so
+    /// torrents nobody announces any more don't take up memory.
+    fn sweep(&self, swarms: &mut HashMap<[u8; 20], Swarm>) {
+        let mut swept = self.swept.lock().unwrap();
+        if swept.elapsed() < self.config.peer_timeout {
+            return;
+        }
+        *swept = Instant::now();
+        swarms.retain(|info_hash, swarm| {
+            swarm.expire(self.config.peer_timeout);
+            !swarm.peers.is_empty() || self.keep_empty(info_hash)
+        });
+    }
+
+    fn announce(&self, request: AnnounceRequest) -> Result<AnnounceReply, TrackerError> {
+        if let Some(allowlist) = &self.config.allowlist {
+            if !allowlist.contains(&request.info_hash) {
+                return Err(TrackerError::Failure {
+                    reason: "torrent not tracked here".to_owned(),
+                    retry: None,
+                });
+            }
+        }
+
+        let mut swarms = self.swarms.lock().unwrap();
+        self.sweep(&mut swarms);
+        let swarm = swarms.entry(request.info_hash).or_default();
+        swarm.expire(self.config.peer_timeout);
+        let seeding = request.left == 0;
+        let key = (request.peer_id, request.source);
+        match request.event {
+            Some(Event::Stopped) => {
+                swarm.peers.remove(&key);
+            }
+            event => {
+                // Only a peer we saw leeching can complete, and only once
+                let leeching = swarm.peers.get(&key).is_some_and(|peer| !peer.seeding);
+                if event == Some(Event::Completed) && seeding && leeching {
+                    swarm.completed += 1;
+                }
+                swarm.peers.insert(
+                    key,
+                    SwarmPeer {
+                        ipv4: request.ipv4,
+                        ipv6: request.ipv6,
+                        seeding,
+                        last_seen: Instant::now(),
+                    },
+                );
+            }
+        }
+
+        let numwant = request.numwant.unwrap_or(DEFAULT_NUMWANT).min(MAX_NUMWANT);
+        // Seeders have no use for other seeders
+        let peers = swarm
+            .peers
+            .iter()
+            .filter(|(peer_key, peer)| **peer_key != key && !(seeding && peer.seeding))
+            .flat_map(|((peer_id, _), peer)| peer.peers(*peer_id))
+            .filter(|peer| match peer.addr {
+                SocketAddr::V4(_) => request.ipv4_peers,
+                SocketAddr::V6(_) => request.ipv6_peers,
+            })
+            .choose_multiple(&mut rand::thread_rng(), numwant);
+        let stats = swarm.stats();
+        if swarm.peers.is_empty() && !self.keep_empty(&request.info_hash) {
+            swarms.remove(&request.info_hash);
+        }
+        Ok(AnnounceReply {
+            interval: self.config.interval,
+            seeders: stats.seeders,
+            leechers: stats.leechers,
+            peers,
+        })
+    }
+
+    /// Statistics for the given torrents, or for every torrent if none are given. Torrents
+    /// we don't track are left out.
+    fn scrape(&self, info_hashes: &[[u8; 20]]) -> Vec<([u8; 20], ScrapeStats)> {
+        let mut swarms = self.swarms.lock().unwrap();
+        if let Some(allowlist) = &self.config.allowlist {
+            // Allowed torrents nobody announced yet are tracked all the same
+            for info_hash in allowlist {
+                swarms.entry(*info_hash).or_default();
+            }
+        }
+        let info_hashes = match info_hashes {
+            [] => swarms.keys().copied().collect(),
+            info_hashes => info_hashes.to_vec(),
+        };
+        info_hashes
+            .into_iter()
+            .filter_map(|info_hash| {
+                let swarm = swarms.get_mut(&info_hash)?;
+                swarm.expire(self.config.peer_timeout);
+                Some((info_hash, swarm.stats()))
+            })
+            .collect()
+    }
+}
+
+/// A BitTorrent tracker, for running private swarms without third-party software.
+pub struct TrackerServer {
+    swarms: Arc<Swarms>,
+    http: Vec<TcpListener>,
+    udp: Vec<UdpSocket>,
+}
+
+impl TrackerServer {
+    /// Listen for HTTP announces and scrapes on each of `http_addrs`, and for UDP ones on
+    /// each of `udp_addrs`.
+    pub async fn bind(
+        config: ServerConfig,
+        http_addrs: &[SocketAddr],
+        udp_addrs: &[SocketAddr],
+    ) -> Result<Self> {
+        anyhow::ensure!(
+            !http_addrs.is_empty() || !udp_addrs.is_empty(),
+            "no addresses to listen on"
+        );
+        let mut http = Vec::new();
+        for addr in http_addrs {
+            let listener = TcpListener::bind(addr)
+                .await
+                .with_context(|| format!("failed to listen on {}", addr))?;
+            http.push(listener);
+        }
+        let mut udp = Vec::new();
+        for addr in udp_addrs {
+            let socket = UdpSocket::bind(addr)
+                .await
+                .with_context(|| format!("failed to listen on {}", addr))?;
+            udp.push(socket);
+        }
+        Ok(TrackerServer {
+            swarms: Arc::new(Swarms::new(config)),
+            http,
+            udp,
+        })
+    }
+
+    /// The addresses we actually listen on for HTTP, with ephemeral ports filled in.
+    pub fn http_addrs(&self) -> Result<Vec<SocketAddr>> {
+        Ok(self
+            .http
+            .iter()
+            .map(TcpListener::local_addr)
+            .collect::<std::io::Result<_>>()?)
+    }
+
+    /// The addresses we actually listen on for UDP, with ephemeral ports filled in.
+    pub fn udp_addrs(&self) -> Result<Vec<SocketAddr>> {
+        Ok(self
+            .udp
+            .iter()
+            .map(UdpSocket::local_addr)
+            .collect::<std::io::Result<_>>()?)
+    }
+
+    /// Serve until an error stops one of the listeners.
+    pub async fn run(self) -> Result<()> {
+        let http = self
+            .http
+            .into_iter()
+            .map(|listener| http::serve(listener, self.swarms.clone()).boxed());
+        let udp = self
+            .udp
+            .into_iter()
+            .map(|socket| udp::serve(socket, self.swarms.clone()).boxed());
+        let servers = http.chain(udp);
+        let (result, _, _) = futures::future::select_all(servers).await;
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AnnounceRequest, ServerConfig, Swarms};
+    use crate::tracker::{Event, ScrapeStats, TrackerError};
+    use std::{net::SocketAddrV4, time::Duration};
+
+    fn request(peer: u8, left: u64, event: Option<Event>) -> AnnounceRequest {
+        AnnounceRequest {
+            info_hash: [1; 20],
+            peer_id: [peer; 20],
+            source: [10, 0, 0, peer].into(),
+            ipv4: Some(SocketAddrV4::new([10, 0, 0, peer].into(), 6881)),
+            ipv6: None,
+            ipv4_peers: true,
+            ipv6_peers: true,
+            left,
+            event,
+            numwant: None,
+        }
+    }
+
+    #[test]
+    fn swarms() {
+        let swarms = Swarms::new(ServerConfig::default());
+        let reply = swarms.announce(request(1, 0, None)).unwrap();
+        assert!(reply.peers.is_empty());
+        swarms.announce(request(2, 0, None)).unwrap();
+        let reply = swarms
+            .announce(request(3, 10, Some(Event::Started)))
+            .unwrap();
+        assert_eq!((reply.seeders, reply.leechers), (2, 1));
+        assert_eq!(reply.peers.len(), 2);
+
+        {
+            // Seeders only get leechers
+            let reply = swarms.announce(request(1, 0, None)).unwrap();
+            assert_eq!(reply.peers.len(), 1);
+            assert_eq!(reply.peers[0].addr.to_string(), "10.0.0.3:6881");
+            assert_eq!(reply.peers[0].peer_id, Some([3; 20]));
+        }
+
+        {
+            // numwant limits the peers handed out
+            let mut limited = request(4, 10, None);
+            limited.numwant = Some(1);
+            assert_eq!(swarms.announce(limited).unwrap().peers.len(), 1);
+            swarms
+                .announce(request(4, 0, Some(Event::Stopped)))
+                .unwrap();
+        }
+
+        {
+            // Another address announcing with a peer's ID can neither stop nor move it
+            let mut impostor = request(3, 10, Some(Event::Stopped));
+            impostor.source = [10, 0, 0, 9].into();
+            swarms.announce(impostor).unwrap();
+            let mut impostor = request(3, 10, None);
+            impostor.source = [10, 0, 0, 9].into();
+            impostor.ipv4 = Some(SocketAddrV4::new([10, 0, 0, 9].into(), 6881));
+            let reply = swarms.announce(impostor).unwrap();
+            assert!(reply
+                .peers
+                .iter()
+                .any(|peer| peer.addr.to_string() == "10.0.0.3:6881"));
+            let mut impostor = request(3, 10, Some(Event::Stopped));
+            impostor.source = [10, 0, 0, 9].into();
+            swarms.announce(impostor).unwrap();
+        }
+
+        // Completing again doesn't count twice
+        swarms
+            .announce(request(3, 0, Some(Event::Completed)))
+            .unwrap();
+        swarms
+            .announce(request(3, 0, Some(Event::Completed)))
+            .unwrap();
+        swarms
+            .announce(request(2, 0, Some(Event::Stopped)))
+            .unwrap();
+        assert_eq!(
+            swarms.scrape(&[]),
+            [(
+                [1; 20],
+                ScrapeStats {
+                    seeders: 2,
+                    leechers: 0,
+                    downloaded: 1
+                }
+            )]
+        );
+        assert!(swarms.scrape(&[[2; 20]]).is_empty());
+
+        // Swarms are forgotten once their last peer leaves
+        for peer in [1, 3] {
+            swarms
+                .announce(request(peer, 0, Some(Event::Stopped)))
+                .unwrap();
+        }
+        assert!(swarms.scrape(&[]).is_empty());
+    }
+
+    #[test]
+    fn expiry_and_allowlist() {
+        let swarms = Swarms::new(ServerConfig {
+            peer_timeout: Duration::from_millis(10),
+            allowlist: Some([[1; 20]].into()),
+            ..ServerConfig::default()
+        });
+        swarms.announce(request(1, 10, None)).unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+        let reply = swarms.announce(request(2, 10, None)).unwrap();
+        assert!(reply.peers.is_empty());
+        assert_eq!(reply.leechers, 1);
+
+        let mut other = request(1, 10, None);
+        other.info_hash = [2; 20];
+        assert!(matches!(
+            swarms.announce(other),
+            Err(TrackerError::Failure { .. })
+        ));
+        assert_eq!(swarms.scrape(&[[2; 20]]), []);
+    }
+}
@@ -0,0 +1,450 @@
+use anyhow::{Context, Result};
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+use super::{AnnounceRequest, Swarms};
+use crate::{
+    bencode::{BencodeByteString, BencodeValue},
+    http::percent_decode,
+    tracker::{Event, TrackerError},
+};
+
+/// How long a client gets to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Tracker requests are a GET line and a few headers, so anything longer is not a client of
+/// ours.
+const MAX_REQUEST_LEN: usize = 8 * 1024;
+
+/// Answer announces and scrapes on `listener`, one request per connection.
+pub async fn serve(listener: TcpListener, swarms: Arc<Swarms>) -> Result<()> {
+    loop {
+        let (stream, remote) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                eprintln!("warning: failed to accept connection: {}", e);
+                continue;
+            }
+        };
+        let swarms = swarms.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle(stream, remote, &swarms).await {
+                eprintln!("warning: request from {} failed: {:#}", remote, e);
+            }
+        });
+    }
+}
+
+async fn handle(mut stream: TcpStream, remote: SocketAddr, swarms: &Swarms) -> Result<()> {
+    let request_line = tokio::time::timeout(REQUEST_TIMEOUT, read_request_line(&mut stream))
+        .await
+        .context("timed out reading request")??;
+    let (status, body) = respond(&request_line, remote.ip().to_canonical(), swarms);
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(&body).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Read the request up to the end of its headers, returning the request line. Tracker
+/// requests have no body.
+async fn read_request_line(stream: &mut TcpStream) -> Result<String> {
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.ends_with(b"\r\n\r\n") {
+        let n = stream.read(&mut buf).await?;
+        anyhow::ensure!(n > 0, "connection closed mid-request");
+        request.extend(&buf[..n]);
+        anyhow::ensure!(request.len() <= MAX_REQUEST_LEN, "request too long");
+    }
+    let request = String::from_utf8_lossy(&request);
+    Ok(request.lines().next().unwrap_or_default().to_owned())
+}
+
+fn respond(request_line: &str, remote: IpAddr, swarms: &Swarms) -> (&'static str, Vec<u8>) {
+    let mut parts = request_line.split(' ');
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        return ("400 Bad Request", b"bad request".to_vec());
+    };
+    if method != "GET" {
+        return ("405 Method Not Allowed", b"method not allowed".to_vec());
+    }
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let Some(query) = Query::parse(query) else {
+        return ("400 Bad Request", b"bad query string".to_vec());
+    };
+    let response = match path {
+        "/announce" => announce(&query, remote, swarms),
+        "/scrape" => scrape(&query, swarms),
+        _ => return ("404 Not Found", b"not found".to_vec()),
+    };
+    // Failures are reported in the body, as clients expect
+    ("200 OK", response.unwrap_or_else(|reason| failure(&reason)))
+}
+
+/// The decoded parameters of a query string, in order. Names may repeat.
+struct Query(Vec<(String, Vec<u8>)>);
+
+impl Query {
+    fn parse(query: &str) -> Option<Self> {
+        let mut params = Vec::new();
+        for param in query.split('&').filter(|param| !param.is_empty()) {
+            let (name, value) = param.split_once('=').unwrap_or((param, ""));
+            let name = String::from_utf8(percent_decode(name)?).ok()?;
+            params.push((name, percent_decode(value)?));
+        }
+        Some(Query(params))
+    }
+
+    fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a [u8]> {
+        self.0
+            .iter()
+            .filter(move |(n, _)| n == name)
+            .map(|(_, value)| value.as_slice())
+    }
+
+    fn get(&self, name: &str) -> Option<&[u8]> {
+        self.0
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value.as_slice())
+    }
+
+    /// The parameter parsed as `T`, or `None` if it is missing.
+    fn parse_param<T: FromStr>(&self, name: &str) -> Result<Option<T>, String> {
+        self.get(name)
+            .map(|value| {
+                std::str::from_utf8(value)
+                    .ok()
+                    .and_then(|value| value.parse().ok())
+                    .ok_or_else(|| format!("invalid {}", name))
+            })
+            .transpose()
+    }
+
+    fn info_hash(&self, name: &str) -> Result<[u8; 20], String> {
+        self.get(name)
+            .and_then(|value| value.try_into().ok())
+            .ok_or_else(|| format!("invalid {}", name))
+    }
+}
+
+fn announce(query: &Query, remote: IpAddr, swarms: &Swarms) -> Result<Vec<u8>, String> {
+    let info_hash = query.info_hash("info_hash")?;
+    let peer_id = query.info_hash("peer_id")?;
+    let port = query
+        .parse_param::<u16>("port")?
+        .filter(|port| *port != 0)
+        .ok_or("invalid port")?;
+    let left = query.parse_param("left")?.ok_or("missing left")?;
+    let numwant = query.parse_param("numwant")?;
+    let event = query.get("event").and_then(|event| {
+        [Event::Started, Event::Completed, Event::Stopped]
+            .into_iter()
+            .find(|e| e.as_str().as_bytes() == event)
+    });
+    let compact = query.get("compact") != Some(b"0");
+    let no_peer_id = query.get("no_peer_id") == Some(b"1");
+
+    // The address the peer connects from, unless it tells us better and we trust it to.
+    // `ipv4` and `ipv6` (BEP 7) may include a port, and hostnames in `ip` are ignored.
+    let mut addrs = vec![SocketAddr::new(remote, port)];
+    let claimed: &[&str] = if swarms.config.trust_client_ips {
+        &["ip", "ipv4", "ipv6"]
+    } else {
+        &[]
+    };
+    for &name in claimed {
+        let Some(value) = query.get(name).and_then(|v| std::str::from_utf8(v).ok()) else {
+            continue;
+        };
+        if let Ok(addr) = value.parse::<SocketAddr>() {
+            addrs.push(addr);
+        } else if let Ok(ip) = value.parse::<IpAddr>() {
+            addrs.push(SocketAddr::new(ip.to_canonical(), port));
+        }
+    }
+    let mut request = AnnounceRequest {
+        info_hash,
+        peer_id,
+        source: remote,
+        ipv4: None,
+        ipv6: None,
+        ipv4_peers: true,
+        ipv6_peers: true,
+        left,
+        event,
+        numwant,
+    };
+    for addr in addrs {
+        match addr {
+            SocketAddr::V4(addr) => request.ipv4 = Some(addr),
+            SocketAddr::V6(addr) => request.ipv6 = Some(addr),
+        }
+    }
+
+    let reply = swarms.announce(request).map_err(|e| match e {
+        TrackerError::Failure { reason, .. } => reason,
+    })?;
+
+    let mut peers = Vec::new();
+    let mut peers6 = Vec::new();
+    for peer in &reply.peers {
+        match peer.addr {
+            SocketAddr::V4(addr) => {
+                peers.extend(addr.ip().octets());
+                peers.extend(addr.port().to_be_bytes());
+            }
+            SocketAddr::V6(addr) => {
+                peers6.extend(addr.ip().octets());
+                peers6.extend(addr.port().to_be_bytes());
+            }
+        }
+    }
+    let ips = reply
+        .peers
+        .iter()
+        .map(|peer| peer.addr.ip().to_string())
+        .collect::<Vec<_>>();
+
+    let mut response = BTreeMap::new();
+    response.insert(
+        BencodeByteString(b"interval"),
+        BencodeValue::Integer(reply.interval.as_secs() as i64),
+    );
+    response.insert(
+        BencodeByteString(b"complete"),
+        BencodeValue::Integer(reply.seeders as i64),
+    );
+    response.insert(
+        BencodeByteString(b"incomplete"),
+        BencodeValue::Integer(reply.leechers as i64),
+    );
+    if compact {
+        response.insert(
+            BencodeByteString(b"peers"),
+            BencodeValue::ByteString(BencodeByteString(&peers)),
+        );
+        if !peers6.is_empty() {
+            response.insert(
+                BencodeByteString(b"peers6"),
+                BencodeValue::ByteString(BencodeByteString(&peers6)),
+            );
+        }
+    } else {
+        let list = reply
+            .peers
+            .iter()
+            .zip(&ips)
+            .map(|(peer, ip)| {
+                let mut entry = BTreeMap::new();
+                entry.insert(
+                    BencodeByteString(b"ip"),
+                    BencodeValue::ByteString(BencodeByteString(ip.as_bytes())),
+                );
+                entry.insert(
+                    BencodeByteString(b"port"),
+                    BencodeValue::Integer(peer.addr.port().into()),
+                );
+                if let (Some(peer_id), false) = (&peer.peer_id, no_peer_id) {
+                    entry.insert(
+                        BencodeByteString(b"peer id"),
+                        BencodeValue::ByteString(BencodeByteString(peer_id)),
+                    );
+                }
+                BencodeValue::Dictionary(entry)
+            })
+            .collect();
+        response.insert(BencodeByteString(b"peers"), BencodeValue::List(list));
+    }
+    Ok(BencodeValue::Dictionary(response).to_bytes())
+}
+
+fn scrape(query: &Query, swarms: &Swarms) -> Result<Vec<u8>, String> {
+    let info_hashes = query
+        .get_all("info_hash")
+        .map(|info_hash| info_hash.try_into().map_err(|_| "invalid info_hash"))
+        .collect::<Result<Vec<[u8; 20]>, _>>()?;
+    let stats = swarms.scrape(&info_hashes);
+
+    let files = stats
+        .iter()
+        .map(|(info_hash, stats)| {
+            let mut file = BTreeMap::new();
+            for (key, value) in [
+                (&b"complete"[..], stats.seeders),
+                (b"incomplete", stats.leechers),
+                (b"downloaded", stats.downloaded),
+            ] {
+                file.insert(BencodeByteString(key), BencodeValue::Integer(value as i64));
+            }
+            (
+                BencodeByteString(&info_hash[..]),
+                BencodeValue::Dictionary(file),
+            )
+        })
+        .collect();
+    let mut response = BTreeMap::new();
+    response.insert(BencodeByteString(b"files"), BencodeValue::Dictionary(files));
+    Ok(BencodeValue::Dictionary(response).to_bytes())
+}
+
+fn failure(reason: &str) -> Vec<u8> {
+    let mut response = BTreeMap::new();
+    response.insert(
+        BencodeByteString(b"failure reason"),
+        BencodeValue::ByteString(BencodeByteString(reason.as_bytes())),
+    );
+    BencodeValue::Dictionary(response).to_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        identity::Identity,
+        tracker::{
+            server::{ServerConfig, TrackerServer},
+            Announce, Event, ScrapeStats, TrackerClient,
+        },
+    };
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+    fn announce(peer: u8, port: u16, left: u64) -> Announce {
+        Announce {
+            info_hash: [9; 20],
+            peer_id: [peer; 20],
+            key: 0,
+            port,
+            numwant: None,
+            ip: None,
+            uploaded: 0,
+            downloaded: 0,
+            left,
+            event: Some(Event::Started),
+            tracker_id: None,
+            ipv4: None,
+            ipv6: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn http_tracker() {
+        let addrs = ["127.0.0.1:0".parse().unwrap(), "[::1]:0".parse().unwrap()];
+        let server = TrackerServer::bind(ServerConfig::default(), &addrs, &[])
+            .await
+            .unwrap();
+        let [v4, v6] = server.http_addrs().unwrap()[..] else {
+            panic!("expected two addresses");
+        };
+        tokio::spawn(server.run());
+        let url = |addr: SocketAddr, path: &str| {
+            reqwest::Url::parse(&format!("http://{}/{}", addr, path)).unwrap()
+        };
+
+        let client = TrackerClient::new(&Identity::default()).unwrap();
+        let response = client
+            .announce(&url(v4, "announce"), &announce(1, 1111, 10))
+            .await
+            .unwrap();
+        assert!(response.peers.is_empty());
+        assert_eq!(response.interval.unwrap().as_secs(), 30 * 60);
+
+        // Peers of both families are handed out
+        let response = client
+            .announce(&url(v6, "announce"), &announce(2, 2222, 0))
+            .await
+            .unwrap();
+        assert_eq!((response.seeders, response.leechers), (Some(1), Some(1)));
+        assert_eq!(response.peers.len(), 1);
+        assert_eq!(response.peers[0].addr.to_string(), "127.0.0.1:1111");
+        let response = client
+            .announce(&url(v4, "announce"), &announce(1, 1111, 10))
+            .await
+            .unwrap();
+        assert_eq!(response.peers.len(), 1);
+        assert_eq!(response.peers[0].addr.to_string(), "[::1]:2222");
+
+        {
+            // Non-compact peer lists carry peer IDs
+            let target = format!(
+                "{}?info_hash={}&peer_id={}&port=3333&left=5&compact=0",
+                url(v4, "announce"),
+                "%09".repeat(20),
+                "%03".repeat(20)
+            );
+            let body = reqwest::get(target).await.unwrap().bytes().await.unwrap();
+            let mut entry = b"d2:ip9:127.0.0.17:peer id20:".to_vec();
+            entry.extend([1; 20]);
+            entry.extend(b"4:porti1111ee");
+            assert!(body.windows(entry.len()).any(|window| window == entry));
+            let entry = b"d2:ip3:::17:peer id20:";
+            assert!(body.windows(entry.len()).any(|window| window == entry));
+        }
+
+        let stats = client
+            .scrape(&url(v4, "announce"), &[[9; 20]])
+            .await
+            .unwrap();
+        assert_eq!(
+            stats[&[9; 20]],
+            ScrapeStats {
+                seeders: 1,
+                leechers: 2,
+                downloaded: 0
+            }
+        );
+
+        {
+            // Peers are known by the address they connect from, unless they are trusted
+            let mut claiming = announce(4, 4444, 10);
+            claiming.info_hash = [7; 20];
+            claiming.ip = Some(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 9)));
+            let mut asking = announce(5, 5555, 10);
+            asking.info_hash = [7; 20];
+            for (trust_client_ips, expected) in
+                [(false, "127.0.0.1:4444"), (true, "192.0.2.9:4444")]
+            {
+                let config = ServerConfig {
+                    trust_client_ips,
+                    ..ServerConfig::default()
+                };
+                let server = TrackerServer::bind(config, &addrs[..1], &[]).await.unwrap();
+                let addr = server.http_addrs().unwrap()[0];
+                tokio::spawn(server.run());
+                client
+                    .announce(&url(addr, "announce"), &claiming)
+                    .await
+                    .unwrap();
+                let response = client
+                    .announce(&url(addr, "announce"), &asking)
+                    .await
+                    .unwrap();
+                assert_eq!(response.peers[0].addr.to_string(), expected);
+            }
+        }
+
+        {
+            // Bad requests
+            let error = client
+                .announce(&url(v4, "announce"), &announce(1, 0, 10))
+                .await
+                .unwrap_err();
+            assert_eq!(error.to_string(), "tracker failure: invalid port");
+            let response = reqwest::get(url(v4, "elsewhere")).await.unwrap();
+            assert_eq!(response.status(), 404);
+        }
+    }
+}
@@ -0,0 +1,366 @@
+use anyhow::Result;
+use sha1::{Digest, Sha1};
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::net::UdpSocket;
+
+use super::{AnnounceRequest, Swarms};
+use crate::tracker::{
+    udp::{
+        event_id, read_u32, ACTION_ANNOUNCE, ACTION_CONNECT, ACTION_ERROR, ACTION_SCRAPE,
+        MAX_PACKET_LEN, MAX_SCRAPE_HASHES, PROTOCOL_ID,
+    },
+    Event, TrackerError,
+};
+
+/// Connection IDs are accepted during the period they were made in and the one after, so
+/// they stay valid for one to two periods.
+const CONNECTION_ID_PERIOD: Duration = Duration::from_secs(60);
+
+/// Hands out and checks connection IDs without remembering them. An ID is a MAC of the
+/// client's address and the current period, so only that client can use it, and only for a
+/// while.
+struct ConnectionIds {
+    secret: [u8; 20],
+    started: Instant,
+}
+
+impl ConnectionIds {
+    fn new() -> Self {
+        ConnectionIds {
+            secret: rand::random(),
+            started: Instant::now(),
+        }
+    }
+
+    fn period(&self) -> u64 {
+        (self.started.elapsed().as_secs_f64() / CONNECTION_ID_PERIOD.as_secs_f64()) as u64
+    }
+
+    fn make(&self, addr: SocketAddr, period: u64) -> u64 {
+        let mut message = period.to_be_bytes().to_vec();
+        match addr.ip().to_canonical() {
+            IpAddr::V4(ip) => message.extend(ip.octets()),
+            IpAddr::V6(ip) => message.extend(ip.octets()),
+        }
+        message.extend(addr.port().to_be_bytes());
+        let mac = hmac_sha1(&self.secret, &message);
+        u64::from_be_bytes(mac[..8].try_into().unwrap())
+    }
+
+    fn issue(&self, addr: SocketAddr) -> u64 {
+        self.make(addr, self.period())
+    }
+
+    fn check(&self, addr: SocketAddr, connection_id: u64) -> bool {
+        let period = self.period();
+        connection_id == self.make(addr, period)
+            || (period > 0 && connection_id == self.make(addr, period - 1))
+    }
+}
+
+/// HMAC (RFC 2104) with SHA-1, for keys of at most one block.
+fn hmac_sha1(key: &[u8], message: &[u8]) -> [u8; 20] {
+    let mut block = [0; 64];
+    block[..key.len()].copy_from_slice(key);
+    let pad = |byte: u8| block.map(|b| b ^ byte);
+    let inner = Sha1::new()
+        .chain_update(pad(0x36))
+        .chain_update(message)
+        .finalize();
+    Sha1::new()
+        .chain_update(pad(0x5C))
+        .chain_update(inner)
+        .finalize()
+        .into()
+}
+
+/// Answer BEP 15 connects, announces and scrapes on `socket`.
+pub async fn serve(socket: UdpSocket, swarms: Arc<Swarms>) -> Result<()> {
+    let connection_ids = ConnectionIds::new();
+    let mut buf = vec![0; MAX_PACKET_LEN];
+    loop {
+        let (n, from) = socket.recv_from(&mut buf).await?;
+        let Some(response) = respond(&buf[..n], from, &connection_ids, &swarms) else {
+            continue;
+        };
+        if let Err(e) = socket.send_to(&response, from).await {
+            eprintln!("warning: failed to answer {}: {}", from, e);
+        }
+    }
+}
+
+/// The response to one request, or `None` for packets that can't be answered.
+fn respond(
+    request: &[u8],
+    from: SocketAddr,
+    connection_ids: &ConnectionIds,
+    swarms: &Swarms,
+) -> Option<Vec<u8>> {
+    if request.len() < 16 {
+        return None;
+    }
+    let connection_id = u64::from_be_bytes(request[..8].try_into().unwrap());
+    let action = read_u32(request, 8);
+    let transaction_id = read_u32(request, 12);
+    let mut response = Vec::new();
+
+    let result = match action {
+        ACTION_CONNECT if connection_id == PROTOCOL_ID => {
+            response.extend(connection_ids.issue(from).to_be_bytes());
+            Ok(())
+        }
+        ACTION_CONNECT => Err("bad protocol id".to_owned()),
+        _ if !connection_ids.check(from, connection_id) => Err("invalid connection id".to_owned()),
+        ACTION_ANNOUNCE => announce(request, from, swarms, &mut response),
+        ACTION_SCRAPE => {
+            scrape(request, swarms, &mut response);
+            Ok(())
+        }
+        _ => Err("unknown action".to_owned()),
+    };
+
+    let mut header = Vec::with_capacity(8 + response.len());
+    match result {
+        Ok(()) => {
+            header.extend(action.to_be_bytes());
+            header.extend(transaction_id.to_be_bytes());
+            header.extend(response);
+        }
+        Err(reason) => {
+            header.extend(ACTION_ERROR.to_be_bytes());
+            header.extend(transaction_id.to_be_bytes());
+            header.extend(reason.as_bytes());
+        }
+    }
+    Some(header)
+}
+
+/// Handle an announce, writing the response after the action and transaction ID.
+fn announce(
+    request: &[u8],
+    from: SocketAddr,
+    swarms: &Swarms,
+    response: &mut Vec<u8>,
+) -> Result<(), String> {
+    if request.len() < 98 {
+        return Err("announce request too short".to_owned());
+    }
+    let event = read_u32(request, 80);
+    let event = [Event::Started, Event::Completed, Event::Stopped]
+        .into_iter()
+        .find(|e| event_id(Some(*e)) == event);
+    let ip = Ipv4Addr::from(read_u32(request, 84));
+    let num_want = read_u32(request, 92) as i32;
+    let port = u16::from_be_bytes([request[96], request[97]]);
+
+    // Peers get addresses of the family they reached us over. The IP field only fits IPv4,
+    // so IPv6 peers are always known by the address they sent from, and it is only used
+    // for clients we trust not to name a third party.
+    let from_ip = from.ip().to_canonical();
+    let mut announce = AnnounceRequest {
+        info_hash: request[16..36].try_into().unwrap(),
+        peer_id: request[36..56].try_into().unwrap(),
+        source: from_ip,
+        ipv4: None,
+        ipv6: None,
+        ipv4_peers: from_ip.is_ipv4(),
+        ipv6_peers: from_ip.is_ipv6(),
+        left: u64::from_be_bytes(request[64..72].try_into().unwrap()),
+        event,
+        numwant: usize::try_from(num_want).ok(),
+    };
+    match from_ip {
+        IpAddr::V4(from_ip) => {
+            let ip = if ip.is_unspecified() || !swarms.config.trust_client_ips {
+                from_ip
+            } else {
+                ip
+            };
+            announce.ipv4 = Some(SocketAddrV4::new(ip, port));
+        }
+        IpAddr::V6(from_ip) => {
+            announce.ipv6 = Some(SocketAddrV6::new(from_ip, port, 0, 0));
+        }
+    }
+
+    let reply = swarms.announce(announce).map_err(|e| match e {
+        TrackerError::Failure { reason, .. } => reason,
+    })?;
+    response.extend((reply.interval.as_secs() as u32).to_be_bytes());
+    response.extend((reply.leechers as u32).to_be_bytes());
+    response.extend((reply.seeders as u32).to_be_bytes());
+    for peer in reply.peers {
+        match peer.addr {
+            SocketAddr::V4(addr) => response.extend(addr.ip().octets()),
+            SocketAddr::V6(addr) => response.extend(addr.ip().octets()),
+        }
+        response.extend(peer.addr.port().to_be_bytes());
+    }
+    Ok(())
+}
+
+/// Handle a scrape, writing the response after the action and transaction ID. Torrents we
+/// don't track get zeros, since the response has no other way of leaving them out.
+fn scrape(request: &[u8], swarms: &Swarms, response: &mut Vec<u8>) {
+    let info_hashes = request[16..]
+        .chunks_exact(20)
+        .take(MAX_SCRAPE_HASHES)
+        .map(|info_hash| info_hash.try_into().unwrap())
+        .collect::<Vec<[u8; 20]>>();
+    let stats = swarms.scrape(&info_hashes);
+    for info_hash in &info_hashes {
+        let (seeders, downloaded, leechers) = stats
+            .iter()
+            .find(|(h, _)| h == info_hash)
+            .map_or((0, 0, 0), |(_, stats)| {
+                (stats.seeders, stats.downloaded, stats.leechers)
+            });
+        for n in [seeders, downloaded, leechers] {
+            response.extend((n as u32).to_be_bytes());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{hmac_sha1, ConnectionIds, CONNECTION_ID_PERIOD};
+    use crate::{
+        identity::Identity,
+        tracker::{
+            server::{ServerConfig, TrackerServer},
+            udp::read_u32,
+            Announce, Event, ScrapeStats, TrackerClient,
+        },
+    };
+    use std::{
+        net::{IpAddr, Ipv4Addr},
+        time::Duration,
+    };
+    use tokio::net::UdpSocket;
+
+    #[test]
+    fn connection_ids() {
+        // RFC 2202 test case 2
+        assert_eq!(
+            hex::encode(hmac_sha1(b"Jefe", b"what do ya want for nothing?")),
+            "effcdf6ae5eb2fa2d27416d5f184df9c259a7c79"
+        );
+
+        let addr = "127.0.0.1:6881".parse().unwrap();
+        let mut ids = ConnectionIds::new();
+        let id = ids.issue(addr);
+        assert!(ids.check(addr, id));
+        assert!(!ids.check("127.0.0.1:6882".parse().unwrap(), id));
+        assert!(!ids.check("[::ffff:127.0.0.2]:6881".parse().unwrap(), id));
+        ids.started -= CONNECTION_ID_PERIOD;
+        assert!(ids.check(addr, id));
+        ids.started -= CONNECTION_ID_PERIOD;
+        assert!(!ids.check(addr, id));
+    }
+
+    fn announce(peer: u8, port: u16, left: u64) -> Announce {
+        Announce {
+            info_hash: [9; 20],
+            peer_id: [peer; 20],
+            key: 0,
+            port,
+            numwant: None,
+            ip: None,
+            uploaded: 0,
+            downloaded: 0,
+            left,
+            event: Some(Event::Started),
+            tracker_id: None,
+            ipv4: None,
+            ipv6: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn udp_tracker() {
+        let http = ["127.0.0.1:0".parse().unwrap()];
+        let udp = ["127.0.0.1:0".parse().unwrap(), "[::1]:0".parse().unwrap()];
+        let server = TrackerServer::bind(ServerConfig::default(), &http, &udp)
+            .await
+            .unwrap();
+        let [http] = server.http_addrs().unwrap()[..] else {
+            panic!("expected one HTTP address");
+        };
+        let [v4, v6] = server.udp_addrs().unwrap()[..] else {
+            panic!("expected two UDP addresses");
+        };
+        tokio::spawn(server.run());
+        let url = |scheme: &str, addr| {
+            reqwest::Url::parse(&format!("{}://{}/announce", scheme, addr)).unwrap()
+        };
+
+        // The swarm is shared with HTTP, and peers only get addresses of their own family
+        let client = TrackerClient::new(&Identity::default()).unwrap();
+        client
+            .announce(&url("http", http), &announce(1, 1111, 10))
+            .await
+            .unwrap();
+        let response = client
+            .announce(&url("udp", v6), &announce(2, 2222, 10))
+            .await
+            .unwrap();
+        assert!(response.peers.is_empty());
+        assert_eq!(response.leechers, Some(2));
+        let response = client
+            .announce(&url("udp", v4), &announce(3, 3333, 0))
+            .await
+            .unwrap();
+        assert_eq!(response.interval, Some(Duration::from_secs(30 * 60)));
+        assert_eq!((response.seeders, response.leechers), (Some(1), Some(2)));
+        assert_eq!(response.peers.len(), 1);
+        assert_eq!(response.peers[0].addr.to_string(), "127.0.0.1:1111");
+
+        let stats = client
+            .scrape(&url("udp", v4), &[[9; 20], [8; 20]])
+            .await
+            .unwrap();
+        assert_eq!(
+            stats[&[9; 20]],
+            ScrapeStats {
+                seeders: 1,
+                leechers: 2,
+                downloaded: 0
+            }
+        );
+        assert_eq!(stats[&[8; 20]].seeders, 0);
+
+        {
+            // The IP field is ignored from untrusted clients
+            let mut claiming = announce(4, 4444, 10);
+            claiming.info_hash = [7; 20];
+            claiming.ip = Some(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 9)));
+            client.announce(&url("udp", v4), &claiming).await.unwrap();
+            let mut asking = announce(5, 5555, 10);
+            asking.info_hash = [7; 20];
+            let response = client.announce(&url("udp", v4), &asking).await.unwrap();
+            assert_eq!(response.peers[0].addr.to_string(), "127.0.0.1:4444");
+        }
+
+        {
+            // Made-up connection IDs are refused
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let mut request = 0x1234u64.to_be_bytes().to_vec();
+            request.extend(2u32.to_be_bytes());
+            request.extend(7u32.to_be_bytes());
+            request.extend([9; 20]);
+            socket.send_to(&request, v4).await.unwrap();
+            let mut buf = [0; 1024];
+            let n = tokio::time::timeout(Duration::from_secs(5), socket.recv(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(read_u32(&buf, 0), 3);
+            assert_eq!(read_u32(&buf, 4), 7);
+            assert_eq!(&buf[8..n], b"invalid connection id");
+        }
+    }
+}
@@ -0,0 +1,453 @@
+use anyhow::{Context, Result};
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant},
+};
+use tokio::net::UdpSocket;
+
+use super::{
+    parse_peers, parse_peers6, Announce, AnnounceResponse, Event, ScrapeStats, TrackerError,
+};
+
+/// Magic constant that starts every connect request.
+pub(super) const PROTOCOL_ID: u64 = 0x41727101980;
+/// How long a connection ID stays valid after the tracker hands it out.
+const CONNECTION_ID_LIFETIME: Duration = Duration::from_secs(60);
+/// Retransmit after 15 * 2^n seconds for n from 0 up to this.
+const MAX_RETRANSMISSIONS: u32 = 8;
+const RETRANSMISSION_BASE: Duration = Duration::from_secs(15);
+/// The most time one announce or scrape may take, retransmissions included. The whole BEP 15
+/// schedule would keep a dead tracker busy for about two hours.
+const REQUEST_BUDGET: Duration = Duration::from_secs(60);
+/// Most info hashes that fit in one scrape request.
+pub(super) const MAX_SCRAPE_HASHES: usize = 74;
+/// Largest datagram we expect from a tracker.
+pub(super) const MAX_PACKET_LEN: usize = 65536;
+
+pub(super) const ACTION_CONNECT: u32 = 0;
+pub(super) const ACTION_ANNOUNCE: u32 = 1;
+pub(super) const ACTION_SCRAPE: u32 = 2;
+pub(super) const ACTION_ERROR: u32 = 3;
+
+/// A BEP 15 UDP tracker.
+pub struct UdpTracker {
+    socket: UdpSocket,
+    /// The current connection ID and when we got it.
+    connection: Option<(u64, Instant)>,
+    retransmission_base: Duration,
+    request_budget: Duration,
+}
+
+impl UdpTracker {
+    pub async fn new(url: &reqwest::Url) -> Result<Self> {
+        let host = url.host_str().context("UDP tracker URL has no host")?;
+        // IPv6 literals come bracketed
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let port = url.port().context("UDP tracker URL has no port")?;
+        let addr = tokio::net::lookup_host((host, port))
+            .await?
+            .next()
+            .with_context(|| format!("could not resolve {}", host))?;
+        Self::with_addr(addr).await
+    }
+
+    async fn with_addr(addr: SocketAddr) -> Result<Self> {
+        let bind_addr: SocketAddr = if addr.is_ipv4() {
+            "0.0.0.0:0".parse()?
+        } else {
+            "[::]:0".parse()?
+        };
+        let socket = UdpSocket::bind(bind_addr).await?;
+        socket.connect(addr).await?;
+        Ok(UdpTracker {
+            socket,
+            connection: None,
+            retransmission_base: RETRANSMISSION_BASE,
+            request_budget: REQUEST_BUDGET,
+        })
+    }
+
+    pub async fn announce(&mut self, announce: &Announce) -> Result<AnnounceResponse> {
+        let response = self
+            .request(ACTION_ANNOUNCE, |connection_id, transaction_id| {
+                let mut request = Vec::with_capacity(98);
+                request.extend(connection_id.to_be_bytes());
+                request.extend(ACTION_ANNOUNCE.to_be_bytes());
+                request.extend(transaction_id.to_be_bytes());
+                request.extend(announce.info_hash);
+                request.extend(announce.peer_id);
+                request.extend(announce.downloaded.to_be_bytes());
+                request.extend(announce.left.to_be_bytes());
+                request.extend(announce.uploaded.to_be_bytes());
+                request.extend(event_id(announce.event).to_be_bytes());
+                // Zero means the address we send from. Only IPv4 addresses fit.
+                let ip = match announce.ip {
+                    Some(IpAddr::V4(ip)) => u32::from(ip),
+                    _ => 0,
+                };
+                request.extend(ip.to_be_bytes());
+                request.extend(announce.key.to_be_bytes());
+                // -1 means the tracker's default
+                let num_want = announce
+                    .numwant
+                    .and_then(|n| i32::try_from(n).ok())
+                    .unwrap_or(-1);
+                request.extend(num_want.to_be_bytes());
+                request.extend(announce.port.to_be_bytes());
+                request
+            })
+            .await?;
+        if response.len() < 12 {
+            anyhow::bail!("announce response too short");
+        }
+
+        Ok(AnnounceResponse {
+            interval: Some(Duration::from_secs(read_u32(&response, 0) as u64)),
+            min_interval: None,
+            tracker_id: None,
+            warning: None,
+            leechers: Some(read_u32(&response, 4) as u64),
+            seeders: Some(read_u32(&response, 8) as u64),
+            // The peers are of the family we reached the tracker with
+            peers: match self.socket.peer_addr()? {
+                SocketAddr::V4(_) => parse_peers(&response[12..])?,
+                SocketAddr::V6(_) => parse_peers6(&response[12..])?,
+            },
+        })
+    }
+
+    /// Scrape the given torrents, returning their statistics in the same order.
+    pub async fn scrape(&mut self, info_hashes: &[[u8; 20]]) -> Result<Vec<ScrapeStats>> {
+        let mut stats = Vec::with_capacity(info_hashes.len());
+        for info_hashes in info_hashes.chunks(MAX_SCRAPE_HASHES) {
+            stats.extend(self.scrape_chunk(info_hashes).await?);
+        }
+        Ok(stats)
+    }
+
+    async fn scrape_chunk(&mut self, info_hashes: &[[u8; 20]]) -> Result<Vec<ScrapeStats>> {
+        let response = self
+            .request(ACTION_SCRAPE, |connection_id, transaction_id| {
+                let mut request = Vec::with_capacity(16 + 20 * info_hashes.len());
+                request.extend(connection_id.to_be_bytes());
+                request.extend(ACTION_SCRAPE.to_be_bytes());
+                request.extend(transaction_id.to_be_bytes());
+                for info_hash in info_hashes {
+                    request.extend(info_hash);
+                }
+                request
+            })
+            .await?;
+        if response.len() < 12 * info_hashes.len() {
+            anyhow::bail!("scrape response too short");
+        }
+
+        Ok(response
+            .chunks_exact(12)
+            .take(info_hashes.len())
+            .map(|stats| ScrapeStats {
+                seeders: read_u32(stats, 0) as u64,
+                downloaded: read_u32(stats, 4) as u64,
+                leechers: read_u32(stats, 8) as u64,
+            })
+            .collect())
+    }
+
+    /// Send a request built by `build_request` from a connection ID and a transaction ID, and
+    /// return the body of the response after the action and transaction ID. Lost packets are
+    /// retransmitted on the BEP 15 schedule, reconnecting first if the connection ID expired,
+    /// until the request budget runs out.
+    async fn request<F>(&mut self, action: u32, build_request: F) -> Result<Vec<u8>>
+    where
+        F: Fn(u64, u32) -> Vec<u8>,
+    {
+        let deadline = Instant::now() + self.request_budget;
+        for n in 0..=MAX_RETRANSMISSIONS {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                break;
+            }
+            let timeout = (self.retransmission_base * 2u32.pow(n)).min(remaining);
+            let connection_id = match self.connection {
+                Some((connection_id, obtained)) if obtained.elapsed() < CONNECTION_ID_LIFETIME => {
+                    connection_id
+                }
+                _ => match self.connect(timeout).await? {
+                    Some(connection_id) => connection_id,
+                    None => continue,
+                },
+            };
+
+            let transaction_id = rand::random::<u32>();
+            self.socket
+                .send(&build_request(connection_id, transaction_id))
+                .await?;
+            if let Some(response) = self.receive(action, transaction_id, timeout).await? {
+                return Ok(response);
+            }
+        }
+        anyhow::bail!("UDP tracker did not respond")
+    }
+
+    /// Get a new connection ID, or `None` if the tracker didn't answer within `timeout`.
+    async fn connect(&mut self, timeout: Duration) -> Result<Option<u64>> {
+        let transaction_id = rand::random::<u32>();
+        let mut request = Vec::with_capacity(16);
+        request.extend(PROTOCOL_ID.to_be_bytes());
+        request.extend(ACTION_CONNECT.to_be_bytes());
+        request.extend(transaction_id.to_be_bytes());
+        self.socket.send(&request).await?;
+
+        let Some(response) = self
+            .receive(ACTION_CONNECT, transaction_id, timeout)
+            .await?
+        else {
+            return Ok(None);
+        };
+        if response.len() < 8 {
+            anyhow::bail!("connect response too short");
+        }
+        let connection_id = u64::from_be_bytes(response[..8].try_into()?);
+        self.connection = Some((connection_id, Instant::now()));
+        Ok(Some(connection_id))
+    }
+
+    /// Wait up to `timeout` for the response to `transaction_id`, ignoring stray packets.
+    async fn receive(
+        &mut self,
+        action: u32,
+        transaction_id: u32,
+        timeout: Duration,
+    ) -> Result<Option<Vec<u8>>> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        let mut buf = vec![0; MAX_PACKET_LEN];
+        loop {
+            let n = match tokio::time::timeout_at(deadline, self.socket.recv(&mut buf)).await {
+                Ok(n) => n?,
+                Err(_) => return Ok(None),
+            };
+            let packet = &buf[..n];
+            if packet.len() < 8 || read_u32(packet, 4) != transaction_id {
+                continue;
+            }
+            match read_u32(packet, 0) {
+                ACTION_ERROR => {
+                    // Whatever the tracker said, the connection ID is not worth keeping
+                    self.connection = None;
+                    return Err(TrackerError::Failure {
+                        reason: String::from_utf8_lossy(&packet[8..]).into_owned(),
+                        retry: None,
+                    }
+                    .into());
+                }
+                a if a == action => return Ok(Some(packet[8..].to_vec())),
+                a => anyhow::bail!("unexpected action {} in tracker response", a),
+            }
+        }
+    }
+}
+
+pub(super) fn event_id(event: Option<Event>) -> u32 {
+    match event {
+        None => 0,
+        Some(Event::Completed) => 1,
+        Some(Event::Started) => 2,
+        Some(Event::Stopped) => 3,
+    }
+}
+
+pub(super) fn read_u32(input: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(input[offset..offset + 4].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_u32, UdpTracker, PROTOCOL_ID};
+    use crate::{
+        identity::Identity,
+        torrent::Torrent,
+        tracker::{Announce, Event, ScrapeStats},
+    };
+    use std::{
+        net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+        time::Duration,
+    };
+    use tokio::net::UdpSocket;
+
+    const CONNECTION_ID: u64 = 0x1122334455667788;
+
+    async fn serve(drop: usize) -> SocketAddr {
+        serve_on("127.0.0.1:0", drop).await
+    }
+
+    /// A scripted UDP tracker that answers connects, announces and scrapes, and drops the
+    /// first `drop` packets it receives.
+    async fn serve_on(bind_addr: &str, mut drop: usize) -> SocketAddr {
+        let socket = UdpSocket::bind(bind_addr).await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 2048];
+            loop {
+                let (n, from) = socket.recv_from(&mut buf).await.unwrap();
+                if drop > 0 {
+                    drop -= 1;
+                    continue;
+                }
+                let packet = &buf[..n];
+                let connection_id = u64::from_be_bytes(packet[..8].try_into().unwrap());
+                let action = read_u32(packet, 8);
+                let transaction_id = read_u32(packet, 12);
+
+                let mut response = Vec::new();
+                match action {
+                    0 => {
+                        assert_eq!(connection_id, PROTOCOL_ID);
+                        response.extend(0u32.to_be_bytes());
+                        response.extend(transaction_id.to_be_bytes());
+                        response.extend(CONNECTION_ID.to_be_bytes());
+                    }
+                    _ if connection_id != CONNECTION_ID => {
+                        response.extend(3u32.to_be_bytes());
+                        response.extend(transaction_id.to_be_bytes());
+                        response.extend(b"bad connection id");
+                    }
+                    1 => {
+                        assert_eq!(n, 98);
+                        assert_eq!(read_u32(packet, 80), 2, "event should be started");
+                        assert_eq!(read_u32(packet, 92), 50, "numwant should be 50");
+                        response.extend(1u32.to_be_bytes());
+                        response.extend(transaction_id.to_be_bytes());
+                        response.extend(1800u32.to_be_bytes());
+                        response.extend(2u32.to_be_bytes());
+                        response.extend(3u32.to_be_bytes());
+                        match from {
+                            SocketAddr::V4(_) => response.extend([127, 0, 0, 1]),
+                            SocketAddr::V6(_) => response.extend(Ipv6Addr::LOCALHOST.octets()),
+                        }
+                        response.extend([0x1A, 0xE1]);
+                    }
+                    2 => {
+                        response.extend(2u32.to_be_bytes());
+                        response.extend(transaction_id.to_be_bytes());
+                        for i in 0..(n as u32 - 16) / 20 {
+                            response.extend((10 * i + 1).to_be_bytes());
+                            response.extend((10 * i + 2).to_be_bytes());
+                            response.extend((10 * i + 3).to_be_bytes());
+                        }
+                    }
+                    _ => unreachable!(),
+                }
+                socket.send_to(&response, from).await.unwrap();
+            }
+        });
+        addr
+    }
+
+    fn announce() -> Announce {
+        let mut input =
+            b"d8:announce15:udp://a.b:6969/4:infod6:lengthi10e4:name1:a12:piece lengthi10e6:pieces20:"
+                .to_vec();
+        input.extend([0; 20]);
+        input.extend(b"ee");
+        let identity = Identity {
+            numwant: Some(50),
+            ..Identity::default()
+        };
+        let mut announce = Announce::new(&Torrent::from_bytes(&input).unwrap(), &identity);
+        announce.event = Some(Event::Started);
+        announce
+    }
+
+    #[tokio::test]
+    async fn announce_and_scrape() {
+        let addr = serve(0).await;
+        let mut tracker = UdpTracker::with_addr(addr).await.unwrap();
+
+        let response = tracker.announce(&announce()).await.unwrap();
+        assert_eq!(response.interval, Some(Duration::from_secs(1800)));
+        assert_eq!(response.leechers, Some(2));
+        assert_eq!(response.seeders, Some(3));
+        assert_eq!(
+            response.peers,
+            vec![SocketAddr::from((Ipv4Addr::LOCALHOST, 6881)).into()]
+        );
+
+        let stats = tracker.scrape(&[[1; 20], [2; 20]]).await.unwrap();
+        assert_eq!(
+            stats,
+            vec![
+                ScrapeStats {
+                    seeders: 1,
+                    downloaded: 2,
+                    leechers: 3
+                },
+                ScrapeStats {
+                    seeders: 11,
+                    downloaded: 12,
+                    leechers: 13
+                },
+            ]
+        );
+
+        {
+            // Large scrapes are split over several requests
+            let stats = tracker.scrape(&[[1; 20]; 100]).await.unwrap();
+            assert_eq!(stats.len(), 100);
+            assert_eq!(stats[74].seeders, 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn ipv6() {
+        // Trackers reached over IPv6 send 18-byte peers
+        let addr = serve_on("[::1]:0", 0).await;
+        let mut tracker = UdpTracker::with_addr(addr).await.unwrap();
+        let response = tracker.announce(&announce()).await.unwrap();
+        assert_eq!(
+            response.peers,
+            vec![SocketAddr::from((Ipv6Addr::LOCALHOST, 6881)).into()]
+        );
+    }
+
+    #[tokio::test]
+    async fn retransmission_and_errors() {
+        {
+            // The connect and first announce are lost
+            let addr = serve(2).await;
+            let mut tracker = UdpTracker::with_addr(addr).await.unwrap();
+            tracker.retransmission_base = Duration::from_millis(10);
+            let response = tracker.announce(&announce()).await.unwrap();
+            assert_eq!(response.peers.len(), 1);
+        }
+
+        {
+            // An expired connection ID is replaced before announcing
+            let addr = serve(0).await;
+            let mut tracker = UdpTracker::with_addr(addr).await.unwrap();
+            tracker.connection =
+                Some((0xdead, std::time::Instant::now() - Duration::from_secs(61)));
+            assert!(tracker.announce(&announce()).await.is_ok());
+        }
+
+        {
+            // An error action is reported
+            let addr = serve(0).await;
+            let mut tracker = UdpTracker::with_addr(addr).await.unwrap();
+            tracker.connection = Some((0xdead, std::time::Instant::now()));
+            let error = tracker.announce(&announce()).await.unwrap_err();
+            assert_eq!(error.to_string(), "tracker failure: bad connection id");
+            assert!(tracker.connection.is_none());
+        }
+
+        {
+            // A tracker that never answers is given up on once the budget runs out
+            let addr = serve(usize::MAX).await;
+            let mut tracker = UdpTracker::with_addr(addr).await.unwrap();
+            tracker.retransmission_base = Duration::from_millis(10);
+            tracker.request_budget = Duration::from_millis(50);
+            let started = std::time::Instant::now();
+            assert!(tracker.announce(&announce()).await.is_err());
+            assert!(started.elapsed() < Duration::from_secs(1));
+        }
+    }
+}
@@ -0,0 +1,110 @@
+use anyhow::{Context, Result};
+use reqwest::{header, StatusCode};
+
+use crate::{
+    http::percent_encode,
+    torrent::{FileSegment, TorrentInfo},
+};
+
+/// A BEP 19 web seed: an HTTP server with a copy of the torrent's files, from which pieces
+/// are fetched with range requests.
+pub struct WebSeed {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl WebSeed {
+    pub fn new(url: &str) -> Result<Self> {
+        let parsed = reqwest::Url::parse(url).context("invalid web seed URL")?;
+        if !matches!(parsed.scheme(), "http" | "https") {
+            anyhow::bail!("unsupported web seed scheme {:?}", parsed.scheme());
+        }
+        Ok(WebSeed {
+            client: reqwest::Client::new(),
+            url: url.to_owned(),
+        })
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// The URL of a file. For single-file torrents the seed URL is the file itself, unless it
+    /// ends with `/`, in which case the name is appended. Multi-file torrents append the name
+    /// and the file's path.
+    fn file_url(&self, info: &TorrentInfo, file_index: usize) -> String {
+        let mut url = self.url.clone();
+        match &info.files {
+            None => {
+                if url.ends_with('/') {
+                    url.push_str(&percent_encode(&info.name.bytes));
+                }
+            }
+            Some(files) => {
+                if !url.ends_with('/') {
+                    url.push('/');
+                }
+                url.push_str(&percent_encode(&info.name.bytes));
+                for component in files[file_index].path.iter() {
+                    url.push('/');
+                    url.push_str(&percent_encode(&component.bytes));
+                }
+            }
+        }
+        url
+    }
+
+    /// Download a piece from the web seed. The data is not checked against the piece hash.
+    /// BEP 47 padding files are all zeros and not on the seed, so they are filled in here.
+    pub async fn fetch_piece(&self, info: &TorrentInfo, piece_index: usize) -> Result<Vec<u8>> {
+        let offset = piece_index * info.piece_length;
+        let mut piece = Vec::with_capacity(info.piece_len(piece_index));
+        for segment in info.file_segments(offset, info.piece_len(piece_index)) {
+            let is_padding = info
+                .files
+                .as_ref()
+                .is_some_and(|files| files[segment.file_index].is_padding());
+            if is_padding {
+                piece.resize(piece.len() + segment.length, 0);
+            } else {
+                piece.extend(self.fetch_segment(info, &segment).await?);
+            }
+        }
+        Ok(piece)
+    }
+
+    async fn fetch_segment(&self, info: &TorrentInfo, segment: &FileSegment) -> Result<Vec<u8>> {
+        let url = self.file_url(info, segment.file_index);
+        let range = format!(
+            "bytes={}-{}",
+            segment.offset,
+            segment.offset + segment.length - 1
+        );
+        let response = self
+            .client
+            .get(&url)
+            .header(header::RANGE, range)
+            .send()
+            .await?;
+
+        let status = response.status();
+        let body = response.bytes().await?;
+        let data = match status {
+            StatusCode::PARTIAL_CONTENT => &body[..],
+            // The server ignored the range and sent the whole file
+            StatusCode::OK if body.len() >= segment.offset + segment.length => {
+                &body[segment.offset..segment.offset + segment.length]
+            }
+            _ => anyhow::bail!("web seed request for {} failed with {}", url, status),
+        };
+        if data.len() != segment.length {
+            anyhow::bail!(
+                "web seed sent {} bytes of {} instead of {}",
+                data.len(),
+                url,
+                segment.length
+            );
+        }
+        Ok(data.to_vec())
+    }
+}